@@ -0,0 +1,124 @@
+use actix_http::RequestHead;
+use actix_web::http::{header, HeaderMap, Method, StatusCode};
+use actix_web::HttpResponse;
+
+/// How requests that cannot follow a redirect to CAS are told apart from
+/// navigations. They get a 401 with the login url instead of a redirect.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ApiDetection {
+    /// From the request headers and method: `fetch`/XHR calls, JSON clients, CORS
+    /// preflights and WebSocket upgrades are API requests
+    #[default]
+    Auto,
+    /// Every request is a navigation, redirected to CAS
+    Never,
+    /// Every request is an API request
+    Always,
+}
+
+impl ApiDetection {
+    /// Whether `head` is an API request
+    pub fn is_api_request(&self, head: &RequestHead) -> bool {
+        match self {
+            ApiDetection::Auto => is_api_request(&head.method, &head.headers),
+            ApiDetection::Never => false,
+            ApiDetection::Always => true,
+        }
+    }
+}
+
+fn is_api_request(method: &Method, headers: &HeaderMap) -> bool {
+    let header = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.to_ascii_lowercase())
+    };
+    let sec_fetch_mode = header("sec-fetch-mode");
+    if let Some(mode) = &sec_fetch_mode {
+        return mode != "navigate";
+    }
+    if headers.contains_key(header::UPGRADE) {
+        return true;
+    }
+    if header("x-requested-with").as_deref() == Some("xmlhttprequest") {
+        return true;
+    }
+    // Browsers without Fetch Metadata (older Safari, webviews) still ask for
+    // pages, and post forms with form encodings
+    let accept = header("accept");
+    if accept.as_deref().is_some_and(|accept| accept.contains("text/html")) {
+        return false;
+    }
+    let content_type = header("content-type").unwrap_or_default();
+    if content_type.starts_with("application/x-www-form-urlencoded")
+        || content_type.starts_with("multipart/form-data")
+    {
+        return false;
+    }
+    // Forms are the only navigations that are not GET
+    if *method != Method::GET && *method != Method::HEAD {
+        return true;
+    }
+    accept.is_some_and(|accept| accept.contains("json"))
+}
+
+/// 401 answered to API requests of users who are not logged in
+pub(crate) fn unauthenticated_response(login_url: &str) -> HttpResponse {
+    HttpResponse::build(StatusCode::UNAUTHORIZED)
+        .header(
+            header::WWW_AUTHENTICATE,
+            format!("CAS realm=\"CAS\", login_url=\"{}\"", login_url),
+        )
+        .json(serde_json::json!({
+            "error": "unauthenticated",
+            "login_url": login_url,
+        }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn is_api(req: TestRequest) -> bool {
+        ApiDetection::Auto.is_api_request(req.to_http_request().head())
+    }
+
+    #[test]
+    fn should_detect_api_requests() {
+        assert!(is_api(TestRequest::get().header("Accept", "application/json")));
+        assert!(is_api(TestRequest::get().header("Accept", "application/problem+json")));
+        assert!(is_api(TestRequest::get().header("X-Requested-With", "XMLHttpRequest")));
+        assert!(is_api(TestRequest::get().header("Sec-Fetch-Mode", "cors")));
+        assert!(is_api(TestRequest::get().header("Upgrade", "websocket")));
+        assert!(is_api(TestRequest::default().method(Method::OPTIONS)));
+        assert!(is_api(TestRequest::post()));
+    }
+
+    #[test]
+    fn should_detect_navigations() {
+        assert!(!is_api(TestRequest::get()));
+        assert!(!is_api(TestRequest::get().header("Accept", "text/html,application/json;q=0.9")));
+        assert!(!is_api(TestRequest::get().header("Accept", "*/*")));
+        assert!(!is_api(TestRequest::post().header("Sec-Fetch-Mode", "navigate")));
+    }
+
+    #[test]
+    fn should_detect_forms_without_fetch_metadata() {
+        let urlencoded = "application/x-www-form-urlencoded";
+        assert!(!is_api(TestRequest::post().header("Content-Type", urlencoded)));
+        assert!(!is_api(TestRequest::post().header("Content-Type", "multipart/form-data; boundary=x")));
+        assert!(!is_api(TestRequest::post().header("Accept", "text/html,*/*;q=0.8")));
+        assert!(is_api(TestRequest::post().header("Content-Type", "application/json")));
+        assert!(is_api(TestRequest::post().header("Sec-Fetch-Mode", "cors").header("Content-Type", urlencoded)));
+    }
+
+    #[test]
+    fn detection_can_be_forced() {
+        let json = TestRequest::get().header("Accept", "application/json").to_http_request();
+        assert!(!ApiDetection::Never.is_api_request(json.head()));
+        let html = TestRequest::get().to_http_request();
+        assert!(ApiDetection::Always.is_api_request(html.head()));
+    }
+}
@@ -0,0 +1,209 @@
+use actix_web::http::StatusCode;
+use actix_web::HttpResponse;
+use std::fmt;
+use std::sync::Arc;
+
+/// Page answered instead of the requested one
+#[derive(Clone, Debug, PartialEq)]
+pub enum ErrorPage {
+    /// The user may not access the page
+    Forbidden,
+    /// The page is hidden from the user
+    NotFound,
+    /// CAS rejected the tickets of several logins in a row, with the failure
+    /// `code` of the last one. Visiting `retry_url` starts a new login.
+    ValidationFailed { retry_url: String, code: String },
+    /// CAS could not be reached to validate the ticket
+    CasUnavailable { retry_url: String },
+    /// The CAS client is misconfigured, e.g. its login url cannot be built
+    ConfigurationError,
+    /// The host of the request is not allowed
+    InvalidHost,
+    /// The `CasEventHandler` denied the login of the user
+    LoginDenied,
+    /// The `CasEventHandler` failed to handle the login of the user. Visiting
+    /// `retry_url` starts a new login.
+    LoginFailed { retry_url: String },
+}
+
+impl ErrorPage {
+    /// Status of the response answering the page
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ErrorPage::Forbidden => StatusCode::FORBIDDEN,
+            ErrorPage::NotFound => StatusCode::NOT_FOUND,
+            ErrorPage::ValidationFailed { .. } => StatusCode::FORBIDDEN,
+            ErrorPage::CasUnavailable { .. } => StatusCode::SERVICE_UNAVAILABLE,
+            ErrorPage::ConfigurationError => StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorPage::InvalidHost => StatusCode::BAD_REQUEST,
+            ErrorPage::LoginDenied => StatusCode::FORBIDDEN,
+            ErrorPage::LoginFailed { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Url starting a new login, for the pages that offer one
+    pub fn retry_url(&self) -> Option<&str> {
+        match self {
+            ErrorPage::ValidationFailed { retry_url, .. }
+            | ErrorPage::CasUnavailable { retry_url }
+            | ErrorPage::LoginFailed { retry_url } => Some(retry_url),
+            _ => None,
+        }
+    }
+}
+
+/// Renders the error pages of the middleware
+///
+/// Closures are renderers, which lets templates be plugged in:
+///
+/// ```
+/// use actix_web::HttpResponse;
+/// use cas_client_actix::{ErrorPage, ErrorPageRenderer};
+///
+/// // A template engine such as askama or tera would render the body
+/// fn render(page: &ErrorPage) -> HttpResponse {
+///     HttpResponse::build(page.status())
+///         .content_type("text/html; charset=utf-8")
+///         .body(format!("<h1>{}</h1>", page.status()))
+/// }
+///
+/// let response = render.render(&ErrorPage::Forbidden);
+/// assert_eq!(response.status(), 403);
+/// ```
+pub trait ErrorPageRenderer: Send + Sync {
+    fn render(&self, page: &ErrorPage) -> HttpResponse;
+}
+
+impl<F> ErrorPageRenderer for F
+where
+    F: Fn(&ErrorPage) -> HttpResponse + Send + Sync,
+{
+    fn render(&self, page: &ErrorPage) -> HttpResponse {
+        self(page)
+    }
+}
+
+/// Plain HTML error pages
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DefaultErrorPages;
+
+impl ErrorPageRenderer for DefaultErrorPages {
+    fn render(&self, page: &ErrorPage) -> HttpResponse {
+        let (title, message, code) = match page {
+            ErrorPage::Forbidden => ("Forbidden", "You are not allowed to access this page.", None),
+            ErrorPage::NotFound => ("Not found", "This page does not exist.", None),
+            ErrorPage::ValidationFailed { code, .. } => (
+                "Login failed",
+                "Your login could not be validated.",
+                Some(code),
+            ),
+            ErrorPage::CasUnavailable { .. } => (
+                "Login unavailable",
+                "The authentication service cannot be reached. Please try again later.",
+                None,
+            ),
+            ErrorPage::ConfigurationError => (
+                "Configuration error",
+                "Authentication is not configured correctly.",
+                None,
+            ),
+            ErrorPage::InvalidHost => ("Bad request", "This host is not served here.", None),
+            ErrorPage::LoginDenied => (
+                "Login denied",
+                "Your account may not log in to this application.",
+                None,
+            ),
+            ErrorPage::LoginFailed { .. } => (
+                "Login failed",
+                "Your login could not be completed. Please try again later.",
+                None,
+            ),
+        };
+        let code = match code {
+            Some(code) => format!("<p>Error code: <code>{}</code></p>", escape_html(code)),
+            None => String::new(),
+        };
+        let retry_link = match page.retry_url() {
+            Some(retry_url) => format!("<p><a href=\"{}\">Log in again</a></p>", escape_html(retry_url)),
+            None => String::new(),
+        };
+        HttpResponse::build(page.status())
+            .content_type("text/html; charset=utf-8")
+            .body(format!(
+                "<!DOCTYPE html>\n<html><head><title>{title}</title></head>\
+                 <body><h1>{title}</h1><p>{message}</p>{code}{retry_link}</body></html>",
+                title = title,
+                message = message,
+                code = code,
+                retry_link = retry_link,
+            ))
+    }
+}
+
+/// Renderer shared by the middlewares of an `ActixCasClient`
+#[derive(Clone)]
+pub(crate) struct SharedRenderer(pub(crate) Arc<dyn ErrorPageRenderer>);
+
+impl Default for SharedRenderer {
+    fn default() -> Self {
+        SharedRenderer(Arc::new(DefaultErrorPages))
+    }
+}
+
+impl fmt::Debug for SharedRenderer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ErrorPageRenderer(..)")
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::body::{Body, ResponseBody};
+
+    fn body(response: &HttpResponse) -> String {
+        match response.body() {
+            ResponseBody::Body(Body::Bytes(bytes)) => String::from_utf8(bytes.to_vec()).unwrap(),
+            _ => panic!("Unexpected body"),
+        }
+    }
+
+    #[test]
+    fn default_pages_should_escape_retry_url() {
+        let page = ErrorPage::ValidationFailed {
+            retry_url: "https://app.example.org/user?a=1&b=\"2\"".to_string(),
+            code: "INVALID_SERVICE".to_string(),
+        };
+        let response = DefaultErrorPages.render(&page);
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let body = body(&response);
+        assert!(body.contains("<a href=\"https://app.example.org/user?a=1&amp;b=&quot;2&quot;\">"));
+        assert!(body.contains("<code>INVALID_SERVICE</code>"));
+    }
+
+    #[test]
+    fn pages_should_have_status() {
+        let response = DefaultErrorPages.render(&ErrorPage::NotFound);
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert!(body(&response).contains("<h1>Not found</h1>"));
+        let retry_url = String::from("/");
+        assert_eq!(
+            ErrorPage::CasUnavailable { retry_url }.status(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert_eq!(ErrorPage::ConfigurationError.retry_url(), None);
+        let retry_url = String::from("/");
+        assert_eq!(
+            ErrorPage::LoginFailed { retry_url }.status(),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+}
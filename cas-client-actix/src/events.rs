@@ -0,0 +1,70 @@
+use actix_web::HttpRequest;
+use async_trait::async_trait;
+use cas_client_core::{CasUser, CasValidationError};
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+
+/// Error of a `CasEventHandler`, e.g. from the database provisioning users
+pub type CasEventError = Box<dyn Error + Send + Sync>;
+
+/// What to do with a user CAS authenticated
+#[derive(Clone, Debug, PartialEq)]
+pub enum LoginDecision {
+    /// Log the user in as validated by CAS
+    Allow,
+    /// Log in this user instead, e.g. with attributes synced from the app
+    AllowAs(CasUser),
+    /// Do not log the user in, e.g. a disabled account. They get a 403.
+    Deny,
+}
+
+/// Lifecycle events of the CAS logins, for provisioning users, syncing their
+/// attributes or auditing
+///
+/// Every method has a default implementation doing nothing, so that handlers only
+/// implement the events they need:
+///
+/// ```
+/// use async_trait::async_trait;
+/// use cas_client_actix::{CasEventError, CasEventHandler, LoginDecision};
+/// use cas_client_core::CasUser;
+///
+/// struct DisabledAccounts(Vec<String>);
+///
+/// #[async_trait(?Send)]
+/// impl CasEventHandler for DisabledAccounts {
+///     async fn on_login(&self, cas_user: &CasUser) -> Result<LoginDecision, CasEventError> {
+///         match self.0.iter().any(|username| username == cas_user.username()) {
+///             true => Ok(LoginDecision::Deny),
+///             false => Ok(LoginDecision::Allow),
+///         }
+///     }
+/// }
+/// ```
+#[async_trait(?Send)]
+pub trait CasEventHandler: Send + Sync {
+    /// Once CAS validated the ticket of `cas_user`, before they are stored in the
+    /// session. An error gets the user the `ErrorPage::LoginFailed` page, asking
+    /// to try again.
+    async fn on_login(&self, _cas_user: &CasUser) -> Result<LoginDecision, CasEventError> {
+        Ok(LoginDecision::Allow)
+    }
+
+    /// Before `cas_user`, `None` if nobody was logged in, is logged out of the
+    /// session of `req` by the logout route, e.g. to revoke the tokens issued to
+    /// the session
+    async fn on_logout(&self, _req: &HttpRequest, _cas_user: Option<&CasUser>) {}
+
+    /// When the validation of a ticket fails
+    async fn on_failure(&self, _error: &CasValidationError) {}
+}
+
+#[derive(Clone)]
+pub(crate) struct SharedEventHandler(pub(crate) Arc<dyn CasEventHandler>);
+
+impl fmt::Debug for SharedEventHandler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("CasEventHandler(..)")
+    }
+}
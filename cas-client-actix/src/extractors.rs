@@ -0,0 +1,290 @@
+//! Extractors of the logged in user for actix handlers
+//!
+//! `CasUser` is defined in `cas_client_core`, so the extractors wrap it:
+//!
+//! ```
+//! use actix_web::HttpResponse;
+//! use cas_client_actix::{AuthenticatedCasUser, MaybeCasUser};
+//!
+//! // Rejected with 401 when nobody is logged in
+//! async fn profile(user: AuthenticatedCasUser) -> HttpResponse {
+//!     HttpResponse::Ok().body(format!("Welcome {}!", user.username()))
+//! }
+//!
+//! async fn home(user: MaybeCasUser) -> HttpResponse {
+//!     match user.as_ref() {
+//!         Some(user) => HttpResponse::Ok().body(format!("Welcome {}!", user.username())),
+//!         None => HttpResponse::Ok().body("Welcome guest!"),
+//!     }
+//! }
+//! ```
+use crate::session::{
+    self, CasAuthentication, CAS_AUTHENTICATION_SESSION_KEY, CAS_PRINCIPAL_SESSION_KEY,
+    DEFAULT_SESSION_IDLE_TIMEOUT, DEFAULT_SESSION_MAX_LIFETIME,
+};
+use crate::principal::SharedResolver;
+use crate::ActixCasClient;
+use cas_client_core::CasUser;
+use serde::de::DeserializeOwned;
+use std::ops::Deref;
+
+use actix_http::error::{ErrorInternalServerError, ErrorUnauthorized};
+use actix_session::UserSession;
+use actix_web::dev::Payload;
+use actix_web::{web, Error, FromRequest, HttpRequest};
+use futures::future::{err, ok, Ready};
+
+/// Logged in `CasUser`. Requests without one are rejected with 401.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AuthenticatedCasUser(pub CasUser);
+
+impl AuthenticatedCasUser {
+    pub fn into_inner(self) -> CasUser {
+        self.0
+    }
+}
+
+impl Deref for AuthenticatedCasUser {
+    type Target = CasUser;
+
+    fn deref(&self) -> &CasUser {
+        &self.0
+    }
+}
+
+impl FromRequest for AuthenticatedCasUser {
+    type Config = ();
+    type Error = Error;
+    type Future = Ready<Result<Self, Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        match cas_user_for_request(req) {
+            Some(cas_user) => ok(AuthenticatedCasUser(cas_user)),
+            None => err(ErrorUnauthorized("Not logged in")),
+        }
+    }
+}
+
+/// Logged in `CasUser`, if any
+#[derive(Clone, Debug, PartialEq)]
+pub struct MaybeCasUser(pub Option<CasUser>);
+
+impl MaybeCasUser {
+    pub fn into_inner(self) -> Option<CasUser> {
+        self.0
+    }
+}
+
+impl Deref for MaybeCasUser {
+    type Target = Option<CasUser>;
+
+    fn deref(&self) -> &Option<CasUser> {
+        &self.0
+    }
+}
+
+impl FromRequest for MaybeCasUser {
+    type Config = ();
+    type Error = Error;
+    type Future = Ready<Result<Self, Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ok(MaybeCasUser(cas_user_for_request(req)))
+    }
+}
+
+/// Authentication metadata of the logged in user. Requests without one are
+/// rejected with 401.
+impl FromRequest for CasAuthentication {
+    type Config = ();
+    type Error = Error;
+    type Future = Ready<Result<Self, Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        if cas_user_for_request(req).is_none() {
+            return err(ErrorUnauthorized("Not logged in"));
+        }
+        match req.get_session().get::<CasAuthentication>(CAS_AUTHENTICATION_SESSION_KEY) {
+            Ok(Some(authentication)) => ok(authentication),
+            _ => err(ErrorUnauthorized("Not logged in")),
+        }
+    }
+}
+
+/// Principal the `PrincipalResolver` resolved from the logged in user. Requests
+/// without one are rejected with 401.
+///
+/// `P` must be the `Principal` type of the resolver of the middleware, or of the
+/// `ActixCasClient` in the app data for handlers it does not wrap. Any other type
+/// is rejected with 500.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Principal<P>(pub P);
+
+impl<P> Principal<P> {
+    pub fn into_inner(self) -> P {
+        self.0
+    }
+}
+
+impl<P> Deref for Principal<P> {
+    type Target = P;
+
+    fn deref(&self) -> &P {
+        &self.0
+    }
+}
+
+impl<P: DeserializeOwned + 'static> FromRequest for Principal<P> {
+    type Config = ();
+    type Error = Error;
+    type Future = Ready<Result<Self, Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let resolver = req
+            .extensions()
+            .get::<SharedResolver>()
+            .cloned()
+            .or_else(|| app_cas_client(req).and_then(|cas_client| cas_client.principal_resolver.clone()));
+        match resolver {
+            Some(resolver) if resolver.resolves::<P>() => {}
+            resolver => {
+                error!(
+                    "No PrincipalResolver of {} for the Principal extractor ({:?})",
+                    std::any::type_name::<P>(),
+                    resolver
+                );
+                return err(ErrorInternalServerError("Invalid principal"));
+            }
+        }
+        if cas_user_for_request(req).is_none() {
+            return err(ErrorUnauthorized("Not logged in"));
+        }
+        match req.get_session().get::<P>(CAS_PRINCIPAL_SESSION_KEY) {
+            Ok(Some(principal)) => ok(Principal(principal)),
+            Ok(None) => err(ErrorUnauthorized("Not logged in")),
+            Err(error) => {
+                error!("Error while reading the principal from session! Error: {}", error);
+                err(ErrorInternalServerError("Invalid principal"))
+            }
+        }
+    }
+}
+
+/// User placed in the request extensions by the middleware, or read from the
+/// session for handlers it does not wrap. The session timeouts are those of the
+/// `ActixCasClient` in the app data, if any, the default ones otherwise.
+fn cas_user_for_request(req: &HttpRequest) -> Option<CasUser> {
+    let (max_lifetime, idle_timeout) = match app_cas_client(req) {
+        Some(cas_client) => (cas_client.session_max_lifetime(), cas_client.session_idle_timeout()),
+        None => (Some(DEFAULT_SESSION_MAX_LIFETIME), Some(DEFAULT_SESSION_IDLE_TIMEOUT)),
+    };
+    let session = req.get_session();
+    session::cas_user_for_request(&req.extensions(), &session, max_lifetime, idle_timeout)
+}
+
+/// `ActixCasClient` in the app data, if any
+fn app_cas_client(req: &HttpRequest) -> Option<&ActixCasClient> {
+    req.app_data::<ActixCasClient>()
+        .or_else(|| req.app_data::<web::Data<ActixCasClient>>().map(|data| data.get_ref()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{cas_user, request_with_user};
+    use crate::{CasEventError, PrincipalResolver};
+    use actix_web::http::StatusCode;
+    use cas_client_core::CasClient;
+    use actix_web::test::TestRequest;
+
+    #[actix_rt::test]
+    async fn should_extract_logged_in_user() {
+        let req = request_with_user(TestRequest::get(), cas_user("jdoe", &[]));
+        let user = AuthenticatedCasUser::extract(&req).await.unwrap();
+        assert_eq!(user.username(), "jdoe");
+        let user = MaybeCasUser::extract(&req).await.unwrap();
+        assert_eq!(user.into_inner(), Some(cas_user("jdoe", &[])));
+        assert!(CasAuthentication::extract(&req).await.is_ok());
+    }
+
+    #[actix_rt::test]
+    async fn should_reject_guests() {
+        let req = TestRequest::get().to_http_request();
+        let error = AuthenticatedCasUser::extract(&req).await.unwrap_err();
+        assert_eq!(error.as_response_error().status_code(), StatusCode::UNAUTHORIZED);
+        let error = CasAuthentication::extract(&req).await.unwrap_err();
+        assert_eq!(error.as_response_error().status_code(), StatusCode::UNAUTHORIZED);
+        assert_eq!(MaybeCasUser::extract(&req).await.unwrap(), MaybeCasUser(None));
+        assert_eq!(Option::<AuthenticatedCasUser>::extract(&req).await.unwrap(), None);
+    }
+
+    struct UserIds;
+
+    #[async_trait::async_trait(?Send)]
+    impl PrincipalResolver for UserIds {
+        type Principal = String;
+
+        async fn resolve(&self, _cas_user: &CasUser) -> Result<String, CasEventError> {
+            Ok("user-42".to_string())
+        }
+    }
+
+    fn cas_client_with_resolver() -> ActixCasClient {
+        let mut cas_client = ActixCasClient::new(CasClient::new("http://fake.cas").unwrap(), None, None);
+        cas_client.set_principal_resolver(UserIds);
+        cas_client
+    }
+
+    #[actix_rt::test]
+    async fn should_extract_principal() {
+        let req = request_with_user(TestRequest::get().data(cas_client_with_resolver()), cas_user("jdoe", &[]));
+        let error = Principal::<String>::extract(&req).await.unwrap_err();
+        assert_eq!(error.as_response_error().status_code(), StatusCode::UNAUTHORIZED);
+        req.get_session().set(CAS_PRINCIPAL_SESSION_KEY, "user-42").unwrap();
+        let principal = Principal::<String>::extract(&req).await.unwrap();
+        assert_eq!(principal.into_inner(), "user-42");
+        // Not the principal of the resolver
+        let error = Principal::<u64>::extract(&req).await.unwrap_err();
+        assert_eq!(error.as_response_error().status_code(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        // Nor of any resolver
+        let req = request_with_user(TestRequest::get(), cas_user("jdoe", &[]));
+        req.get_session().set(CAS_PRINCIPAL_SESSION_KEY, "user-42").unwrap();
+        let error = Principal::<String>::extract(&req).await.unwrap_err();
+        assert_eq!(error.as_response_error().status_code(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    fn expired_request(req: TestRequest) -> HttpRequest {
+        let req = request_with_user(req, cas_user("jdoe", &[]));
+        let authenticated_at = session::now() - 9 * 3_600;
+        req.get_session()
+            .set(CAS_AUTHENTICATION_SESSION_KEY, CasAuthentication::new(authenticated_at))
+            .unwrap();
+        req.get_session().set(CAS_PRINCIPAL_SESSION_KEY, "user-42").unwrap();
+        req
+    }
+
+    #[actix_rt::test]
+    async fn should_reject_expired_sessions() {
+        let req = expired_request(TestRequest::get().data(cas_client_with_resolver()));
+        assert!(AuthenticatedCasUser::extract(&req).await.is_err());
+        assert_eq!(MaybeCasUser::extract(&req).await.unwrap(), MaybeCasUser(None));
+        assert!(CasAuthentication::extract(&req).await.is_err());
+        assert!(Principal::<String>::extract(&req).await.is_err());
+
+        // Timeouts of the client in the app data
+        let mut cas_client = cas_client_with_resolver();
+        cas_client.set_session_max_lifetime(None).set_session_idle_timeout(None);
+        let req = expired_request(TestRequest::get().data(cas_client));
+        assert!(AuthenticatedCasUser::extract(&req).await.is_ok());
+        assert!(Principal::<String>::extract(&req).await.is_ok());
+    }
+
+    #[actix_rt::test]
+    async fn should_prefer_request_extensions() {
+        let req = request_with_user(TestRequest::get(), cas_user("jdoe", &[]));
+        req.extensions_mut().insert(cas_user("from-middleware", &[]));
+        let user = AuthenticatedCasUser::extract(&req).await.unwrap();
+        assert_eq!(user.username(), "from-middleware");
+    }
+}
@@ -0,0 +1,288 @@
+use actix_web::dev::{AppConfig, RequestHead};
+use actix_web::http::header;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// Network in CIDR notation (`10.0.0.0/8`, `fd00::/8`), or single address
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct IpNetwork {
+    address: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpNetwork {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.address, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                prefix_matches(&network.octets(), &ip.octets(), self.prefix_len)
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                prefix_matches(&network.octets(), &ip.octets(), self.prefix_len)
+            }
+            // IPv4 peers of a dual-stack listener
+            (IpAddr::V4(network), IpAddr::V6(ip)) => match ip.to_ipv4_mapped() {
+                Some(ip) => {
+                    prefix_matches(&network.octets(), &ip.octets(), self.prefix_len)
+                }
+                None => false,
+            },
+            (IpAddr::V6(_), IpAddr::V4(_)) => false,
+        }
+    }
+}
+
+impl FromStr for IpNetwork {
+    type Err = String;
+
+    fn from_str(network: &str) -> Result<Self, Self::Err> {
+        let (address, prefix_len) = match network.split_once('/') {
+            Some((address, prefix_len)) => (address, Some(prefix_len)),
+            None => (network, None),
+        };
+        let address = IpAddr::from_str(address.trim())
+            .map_err(|err| format!("Invalid address in {:?}: {}", network, err))?;
+        let max_prefix_len = match address {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len
+                .trim()
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix_len| *prefix_len <= max_prefix_len)
+                .ok_or_else(|| format!("Invalid prefix length in {:?}", network))?,
+            None => max_prefix_len,
+        };
+        Ok(IpNetwork {
+            address,
+            prefix_len,
+        })
+    }
+}
+
+fn prefix_matches(network: &[u8], ip: &[u8], prefix_len: u8) -> bool {
+    let full_bytes = (prefix_len / 8) as usize;
+    if network[..full_bytes] != ip[..full_bytes] {
+        return false;
+    }
+    let remaining_bits = prefix_len % 8;
+    if remaining_bits == 0 {
+        return true;
+    }
+    let mask = 0xffu8 << (8 - remaining_bits);
+    network[full_bytes] & mask == ip[full_bytes] & mask
+}
+
+/// Scheme and host the client requested
+///
+/// `Forwarded` (RFC 7239) and `X-Forwarded-Proto` / `X-Forwarded-Host` are only
+/// honored when the peer is one of `trusted_proxies`: anyone else could forge
+/// them.
+pub(crate) fn request_origin(
+    req: &RequestHead,
+    app_config: &AppConfig,
+    trusted_proxies: &[IpNetwork],
+) -> (String, String) {
+    let is_trusted =
+        |ip: IpAddr| trusted_proxies.iter().any(|network| network.contains(ip));
+    let (mut scheme, mut host) = (None, None);
+    if req
+        .peer_addr
+        .map(|peer| is_trusted(peer.ip()))
+        .unwrap_or(false)
+    {
+        let header = |name: &str| {
+            req.headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+        };
+        match header("forwarded") {
+            Some(forwarded) => {
+                let element = forwarded_element(forwarded, &is_trusted);
+                scheme = element
+                    .iter()
+                    .find(|(key, _)| key == "proto")
+                    .map(|(_, value)| value.clone());
+                host = element
+                    .iter()
+                    .find(|(key, _)| key == "host")
+                    .map(|(_, value)| value.clone());
+            }
+            None => {
+                // Values appended by the proxies: the last one comes from the peer
+                let last = |name| {
+                    header(name)
+                        .and_then(|value| value.rsplit(',').next())
+                        .map(|value| value.trim().to_string())
+                };
+                scheme = last("x-forwarded-proto");
+                host = last("x-forwarded-host");
+            }
+        }
+    }
+    let scheme = scheme
+        .filter(|scheme| !scheme.is_empty())
+        .unwrap_or_else(|| match req.uri.scheme_str() {
+            Some(scheme) => scheme.to_string(),
+            None if app_config.secure() => "https".to_string(),
+            None => "http".to_string(),
+        });
+    let host = host.filter(|host| !host.is_empty()).unwrap_or_else(|| {
+        let host_header = req
+            .headers
+            .get(header::HOST)
+            .and_then(|value| value.to_str().ok());
+        match (host_header, req.uri.authority()) {
+            (Some(host), _) => host.to_string(),
+            (None, Some(authority)) => authority.to_string(),
+            (None, None) => app_config.host().to_string(),
+        }
+    });
+    (scheme.to_ascii_lowercase(), host.to_ascii_lowercase())
+}
+
+/// Parameters of the `Forwarded` element written by the outermost trusted proxy:
+/// the elements are read from the last one, appended by the peer, while they
+/// describe a trusted proxy
+fn forwarded_element(
+    forwarded: &str,
+    is_trusted: &dyn Fn(IpAddr) -> bool,
+) -> Vec<(String, String)> {
+    let elements: Vec<Vec<(String, String)>> = forwarded
+        .split(',')
+        .map(|element| {
+            element
+                .split(';')
+                .filter_map(|pair| pair.split_once('='))
+                .map(|(key, value)| {
+                    (
+                        key.trim().to_ascii_lowercase(),
+                        value.trim().trim_matches('"').to_string(),
+                    )
+                })
+                .collect()
+        })
+        .collect();
+    let mut chosen = Vec::new();
+    for element in elements.into_iter().rev() {
+        let forwarded_for = element
+            .iter()
+            .find(|(key, _)| key == "for")
+            .and_then(|(_, node)| node_ip(node));
+        chosen = element;
+        match forwarded_for {
+            Some(ip) if is_trusted(ip) => continue,
+            _ => break,
+        }
+    }
+    chosen
+}
+
+/// Address of a `Forwarded` node: `192.0.2.60`, `192.0.2.60:8080` or
+/// `[2001:db8::1]:4711`. Obfuscated and `unknown` nodes have none.
+fn node_ip(node: &str) -> Option<IpAddr> {
+    let address = match node.strip_prefix('[') {
+        Some(bracketed) => bracketed.split(']').next()?,
+        None => node.split(':').next()?,
+    };
+    address.parse().ok()
+}
+
+/// `host` without its port
+pub(crate) fn host_name(host: &str) -> &str {
+    if host.starts_with('[') {
+        return match host.find(']') {
+            Some(end) => &host[..=end],
+            None => host,
+        };
+    }
+    match host.rsplit_once(':') {
+        Some((name, port)) if port.chars().all(|c| c.is_ascii_digit()) => name,
+        _ => host,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn networks(networks: &[&str]) -> Vec<IpNetwork> {
+        networks
+            .iter()
+            .map(|network| network.parse().unwrap())
+            .collect()
+    }
+
+    fn origin(req: TestRequest, trusted_proxies: &[&str]) -> (String, String) {
+        let req = req.to_http_request();
+        request_origin(req.head(), req.app_config(), &networks(trusted_proxies))
+    }
+
+    #[test]
+    fn networks_should_match_prefix() {
+        let network: IpNetwork = "10.1.0.0/16".parse().unwrap();
+        assert!(network.contains("10.1.200.3".parse().unwrap()));
+        assert!(!network.contains("10.2.0.1".parse().unwrap()));
+        assert!(network.contains("::ffff:10.1.0.1".parse().unwrap()));
+        let network: IpNetwork = "172.16.0.0/12".parse().unwrap();
+        assert!(network.contains("172.31.255.255".parse().unwrap()));
+        assert!(!network.contains("172.32.0.0".parse().unwrap()));
+        let network: IpNetwork = "fd00::/8".parse().unwrap();
+        assert!(network.contains("fd12::1".parse().unwrap()));
+        assert!(!network.contains("fe80::1".parse().unwrap()));
+        let network: IpNetwork = "192.0.2.1".parse().unwrap();
+        assert!(network.contains("192.0.2.1".parse().unwrap()));
+        assert!(!network.contains("192.0.2.2".parse().unwrap()));
+        assert!("10.0.0.0/33".parse::<IpNetwork>().is_err());
+        assert!("localhost".parse::<IpNetwork>().is_err());
+    }
+
+    #[test]
+    fn forwarded_headers_should_only_be_honored_from_trusted_proxies() {
+        let req = || {
+            TestRequest::get()
+                .header("Host", "app.internal:8080")
+                .header("X-Forwarded-Proto", "https")
+                .header("X-Forwarded-Host", "evil.example.org, app.example.org")
+        };
+        let peer = "10.0.0.5:40000".parse().unwrap();
+        assert_eq!(
+            origin(req().peer_addr(peer), &[]),
+            ("http".to_string(), "app.internal:8080".to_string())
+        );
+        assert_eq!(
+            origin(req().peer_addr(peer), &["10.0.0.0/8"]),
+            ("https".to_string(), "app.example.org".to_string())
+        );
+        assert_eq!(
+            origin(req(), &["10.0.0.0/8"]),
+            ("http".to_string(), "app.internal:8080".to_string())
+        );
+    }
+
+    #[test]
+    fn forwarded_should_be_read_from_outermost_trusted_proxy() {
+        let peer = "10.0.0.5:40000".parse().unwrap();
+        let req = TestRequest::get()
+            .header("Host", "app.internal")
+            .header(
+                "Forwarded",
+                "for=198.51.100.7;host=evil.example.org, \
+                 for=203.0.113.9;proto=https;host=app.example.org, for=\"10.0.0.4:3000\";host=lb",
+            )
+            .peer_addr(peer);
+        assert_eq!(
+            origin(req, &["10.0.0.0/8"]),
+            ("https".to_string(), "app.example.org".to_string())
+        );
+    }
+
+    #[test]
+    fn host_name_should_strip_port() {
+        assert_eq!(host_name("app.example.org:8443"), "app.example.org");
+        assert_eq!(host_name("app.example.org"), "app.example.org");
+        assert_eq!(host_name("[::1]:8080"), "[::1]");
+    }
+}
@@ -0,0 +1,208 @@
+use crate::session::{self, DEFAULT_SESSION_IDLE_TIMEOUT, DEFAULT_SESSION_MAX_LIFETIME};
+use cas_client_core::CasUser;
+use std::fmt;
+use std::time::Duration;
+
+use actix_http::RequestHead;
+use actix_session::UserSession;
+use actix_web::guard::Guard;
+
+enum GuardCheck {
+    Authenticated,
+    Anonymous,
+    User(Box<dyn Fn(&CasUser) -> bool>),
+}
+
+/// Route guard on the CAS authentication state, read from the session
+///
+/// Routes can then dispatch a path to different handlers whether the user is
+/// logged in or not:
+///
+/// ```
+/// use actix_web::{web, App, HttpResponse};
+/// use cas_client_actix::CasGuard;
+///
+/// let app = App::new()
+///     .route("/", web::get().guard(CasGuard::authenticated()).to(|| HttpResponse::Ok().body("Dashboard")))
+///     .route("/", web::get().to(|| HttpResponse::Ok().body("Welcome guest!")));
+/// ```
+///
+/// Users read from the session are subject to the default session timeouts,
+/// unless `with_session_timeouts` sets those of the middleware.
+pub struct CasGuard {
+    check: GuardCheck,
+    session_max_lifetime: Option<Duration>,
+    session_idle_timeout: Option<Duration>,
+}
+
+impl CasGuard {
+    // ################################################################################
+    // Constructors
+    // ################################################################################
+    /// Matches requests of logged in users
+    pub fn authenticated() -> Self {
+        CasGuard::new(GuardCheck::Authenticated)
+    }
+
+    /// Matches requests of users who are not logged in
+    pub fn anonymous() -> Self {
+        CasGuard::new(GuardCheck::Anonymous)
+    }
+
+    /// Matches requests of logged in users whose attribute `name` is `value`, or
+    /// has `value` among its values if multi-valued
+    ///
+    /// Attributes are split on `,` (see `CasUser::attribute_values`): a `value`
+    /// containing commas, such as an LDAP DN, never matches.
+    pub fn attribute(name: &str, value: &str) -> Self {
+        let name = name.to_string();
+        let value = value.to_string();
+        CasGuard::user(move |cas_user| cas_user.attribute_values(&name).contains(&value.as_str()))
+    }
+
+    /// Matches requests of logged in users satisfying `predicate`
+    pub fn user<F>(predicate: F) -> Self
+    where
+        F: Fn(&CasUser) -> bool + 'static,
+    {
+        CasGuard::new(GuardCheck::User(Box::new(predicate)))
+    }
+
+    fn new(check: GuardCheck) -> Self {
+        CasGuard {
+            check,
+            session_max_lifetime: Some(DEFAULT_SESSION_MAX_LIFETIME),
+            session_idle_timeout: Some(DEFAULT_SESSION_IDLE_TIMEOUT),
+        }
+    }
+
+    // ################################################################################
+    // Setters
+    // ################################################################################
+    /// Session timeouts of the users read from the session, as configured on the
+    /// middleware
+    pub fn with_session_timeouts(
+        mut self,
+        session_max_lifetime: Option<Duration>,
+        session_idle_timeout: Option<Duration>,
+    ) -> Self {
+        self.session_max_lifetime = session_max_lifetime;
+        self.session_idle_timeout = session_idle_timeout;
+        self
+    }
+}
+
+impl Guard for CasGuard {
+    fn check(&self, request: &RequestHead) -> bool {
+        let session = request.get_session();
+        let cas_user = session::cas_user_for_request(
+            &request.extensions(),
+            &session,
+            self.session_max_lifetime,
+            self.session_idle_timeout,
+        );
+        match (&self.check, cas_user) {
+            (GuardCheck::Authenticated, cas_user) => cas_user.is_some(),
+            (GuardCheck::Anonymous, cas_user) => cas_user.is_none(),
+            (GuardCheck::User(predicate), Some(cas_user)) => predicate(&cas_user),
+            (GuardCheck::User(_), None) => false,
+        }
+    }
+}
+
+impl fmt::Debug for CasGuard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let check = match self.check {
+            GuardCheck::Authenticated => "Authenticated",
+            GuardCheck::Anonymous => "Anonymous",
+            GuardCheck::User(_) => "User(..)",
+        };
+        f.debug_struct("CasGuard").field("check", &check).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::{CasAuthentication, CAS_AUTHENTICATION_SESSION_KEY};
+    use crate::testing::{cas_user, request_with_user, WithCasUser};
+    use actix_session::CookieSession;
+    use actix_web::test::{self, TestRequest};
+    use actix_web::{web, App, HttpResponse};
+
+    #[test]
+    fn should_check_authentication_state() {
+        let guest = TestRequest::get().to_http_request();
+        let jdoe = request_with_user(TestRequest::get(), cas_user("jdoe", &[("role", "admin")]));
+        assert!(CasGuard::authenticated().check(jdoe.head()));
+        assert!(!CasGuard::authenticated().check(guest.head()));
+        assert!(CasGuard::anonymous().check(guest.head()));
+        assert!(!CasGuard::anonymous().check(jdoe.head()));
+        assert!(CasGuard::attribute("role", "admin").check(jdoe.head()));
+        assert!(!CasGuard::attribute("role", "user").check(jdoe.head()));
+        assert!(!CasGuard::attribute("role", "admin").check(guest.head()));
+        assert!(CasGuard::user(|user| user.username() == "jdoe").check(jdoe.head()));
+    }
+
+    #[test]
+    fn should_check_multi_valued_attributes() {
+        let jdoe = request_with_user(TestRequest::get(), cas_user("jdoe", &[("role", "staff,admin")]));
+        assert!(CasGuard::attribute("role", "staff").check(jdoe.head()));
+        assert!(CasGuard::attribute("role", "admin").check(jdoe.head()));
+        assert!(!CasGuard::attribute("role", "staff,admin").check(jdoe.head()));
+        assert!(!CasGuard::attribute("role", "adm").check(jdoe.head()));
+    }
+
+    #[test]
+    fn should_not_match_values_with_commas() {
+        let dn = "cn=staff,dc=example,dc=org";
+        let jdoe = request_with_user(TestRequest::get(), cas_user("jdoe", &[("memberOf", dn)]));
+        assert!(!CasGuard::attribute("memberOf", dn).check(jdoe.head()));
+        assert!(CasGuard::attribute("memberOf", "cn=staff").check(jdoe.head()));
+    }
+
+    #[test]
+    fn should_check_session_timeouts() {
+        let jdoe = request_with_user(TestRequest::get(), cas_user("jdoe", &[]));
+        let authenticated_at = session::now() - 9 * 3_600;
+        jdoe.get_session()
+            .set(CAS_AUTHENTICATION_SESSION_KEY, CasAuthentication::new(authenticated_at))
+            .unwrap();
+        assert!(!CasGuard::authenticated().check(jdoe.head()));
+        assert!(CasGuard::anonymous().check(jdoe.head()));
+        let guard = CasGuard::authenticated().with_session_timeouts(None, None);
+        assert!(guard.check(jdoe.head()));
+    }
+
+    #[actix_rt::test]
+    async fn should_dispatch_routes() {
+        let app = || {
+            App::new()
+                .route(
+                    "/",
+                    web::get()
+                        .guard(CasGuard::authenticated())
+                        .to(|| HttpResponse::Ok().body("dashboard")),
+                )
+                .route(
+                    "/",
+                    web::get()
+                        .guard(CasGuard::anonymous())
+                        .to(|| HttpResponse::Ok().body("guest")),
+                )
+        };
+
+        let mut guest_app = test::init_service(app().wrap(CookieSession::signed(&[0; 32]))).await;
+        let req = TestRequest::get().uri("/").to_request();
+        assert_eq!(test::read_response(&mut guest_app, req).await, "guest");
+
+        let mut user_app = test::init_service(
+            app()
+                .wrap(WithCasUser::new(cas_user("jdoe", &[])))
+                .wrap(CookieSession::signed(&[0; 32])),
+        )
+        .await;
+        let req = TestRequest::get().uri("/").to_request();
+        assert_eq!(test::read_response(&mut user_app, req).await, "dashboard");
+    }
+}
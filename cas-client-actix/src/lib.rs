@@ -7,25 +7,84 @@ extern crate serde;
 
 extern crate cas_client_core;
 
+mod api;
+mod error_pages;
+mod events;
+mod extractors;
+mod forwarded;
+mod guard;
+mod logout;
+mod principal;
+mod redirect;
+mod return_url;
+mod rules;
+mod session;
+mod stateless;
+mod ticket_cache;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 pub mod urls;
 
+pub use crate::api::ApiDetection;
+pub use crate::error_pages::{DefaultErrorPages, ErrorPage, ErrorPageRenderer};
+pub use crate::events::{CasEventError, CasEventHandler, LoginDecision};
+pub use crate::extractors::{AuthenticatedCasUser, MaybeCasUser, Principal};
+pub use crate::guard::CasGuard;
+pub use crate::logout::{LogoutBehavior, LOGOUT_RETURN_PARAM};
+pub use crate::principal::PrincipalResolver;
+pub use crate::redirect::RedirectPolicy;
+pub use crate::return_url::ReturnUrlMode;
+pub use crate::rules::{RouteAccess, RouteRule};
+pub use crate::session::{
+    CasAuthentication, CAS_AUTHENTICATION_SESSION_KEY, CAS_PRINCIPAL_SESSION_KEY, CAS_USER_SESSION_KEY,
+};
+pub use crate::stateless::{StatelessCasClient, StatelessCasClientMiddleware};
+
+use crate::error_pages::SharedRenderer;
+use crate::events::SharedEventHandler;
+use crate::forwarded::IpNetwork;
+use crate::principal::SharedResolver;
+use crate::ticket_cache::{ticket_fingerprint, TicketCache};
 use cas_client_core::CasUser;
-use cas_client_core::{CasClient, NoAuthBehavior};
+use cas_client_core::{CasClient, CasValidationError, LoginOptions, NoAuthBehavior, NoAuthHandler, NoAuthOutcome, NoAuthRequest, SessionState};
+use crate::return_url::{
+    SigningKey, NONCE_PARAM, RETURN_URL_PARAM, STATE_COOKIE_PREFIX, STATE_PARAM,
+};
 use std::task::{Context, Poll};
 
 use actix_http::error::ErrorInternalServerError;
 use actix_service::{Service, Transform};
 use actix_session::{Session, UserSession};
 use actix_web::dev::Payload;
+use actix_web::cookie::{Cookie, SameSite};
 use actix_web::dev::{ServiceRequest, ServiceResponse};
 use actix_web::web;
-use actix_web::{http, Error, FromRequest, HttpRequest, HttpResponse};
+use actix_web::{http, Error, FromRequest, HttpMessage, HttpRequest, HttpResponse};
 use futures::future::{err, ok, ready, Either, FutureExt, LocalBoxFuture, Ready};
+use futures::stream::{self, StreamExt};
 
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
+use url::Url;
 
-const CAS_USER_SESSION_KEY: &str = "cas_user";
 const AFTER_LOGGED_IN_URL_SESSION_KEY: &str = "after_logged_in_url";
+// Parameters added by CAS or by this middleware to the service url
+const CAS_PARAMS: [&str; 4] = ["ticket", RETURN_URL_PARAM, STATE_PARAM, NONCE_PARAM];
+// Largest form read when looking for a ticket posted by CAS
+const MAX_TICKET_FORM_SIZE: usize = 16 * 1024;
+
+/// Ticket posted by CAS to the service, for logins with `method=POST`
+struct PostedTicket(String);
+
+/// Response of the middleware, which may wait for the `CasEventHandler`
+type ResponseFuture = LocalBoxFuture<'static, HttpResponse>;
+
+fn respond(response: HttpResponse) -> ResponseFuture {
+    ready(response).boxed_local()
+}
 
 #[derive(Clone, Debug)]
 pub struct ActixCasClient {
@@ -35,6 +94,27 @@ pub struct ActixCasClient {
     server_is_service: bool,
     url_to_403: Option<String>,
     url_to_404: Option<String>,
+    session_max_lifetime: Option<Duration>,
+    session_idle_timeout: Option<Duration>,
+    redirect_policy: RedirectPolicy,
+    return_url_mode: ReturnUrlMode,
+    return_url_key: SigningKey,
+    return_url_max_age: Duration,
+    login_csrf_protection: bool,
+    renew_session_on_login: bool,
+    session_keys_kept_on_login: Vec<String>,
+    route_rules: Vec<RouteRule>,
+    api_detection: ApiDetection,
+    error_pages: SharedRenderer,
+    max_validation_failures: u32,
+    ticket_cache: Option<Arc<TicketCache>>,
+    trusted_proxies: Vec<IpNetwork>,
+    allowed_hosts: Vec<String>,
+    login_options: LoginOptions,
+    locale_from_accept_language: bool,
+    logout_behavior: LogoutBehavior,
+    event_handler: Option<SharedEventHandler>,
+    principal_resolver: Option<SharedResolver>,
 }
 
 fn ticket_for_query_string(
@@ -45,25 +125,68 @@ fn ticket_for_query_string(
     Ok(params.get("ticket").cloned())
 }
 
+fn param_for_query_string(query_string: &str, name: &str) -> Option<String> {
+    web::Query::<HashMap<String, String>>::from_query(query_string)
+        .ok()
+        .and_then(|params| params.get(name).cloned())
+}
+
 struct RequestCasInfo {
-    session: Session,
+    // The login may be stored once the `CasEventHandler` answered
+    session: Rc<Session>,
     ticket: Result<Option<String>, actix_web::error::QueryPayloadError>,
     cas_user: Result<Option<CasUser>, Error>,
     url: String,
+    same_origins: Vec<String>,
     after_logged_in_url: Result<Option<String>, Error>,
+    return_token: Option<String>,
+    state: Option<String>,
+    state_cookie: Option<String>,
+    nonce: Option<String>,
+    api_request: bool,
+    login_options: LoginOptions,
+    ticket_posted: bool,
+    session_expired: bool,
 }
 
 impl RequestCasInfo {
-    fn from_service_request(req: &ServiceRequest) -> Self {
+    fn from_service_request(req: &ServiceRequest, app_url: &str, trusted_proxies: &[IpNetwork]) -> Self {
         let session = req.get_session();
-        let cas_user = session.get::<CasUser>("cas_user");
+        let cas_user = session.get::<CasUser>(CAS_USER_SESSION_KEY);
         let after_logged_in_url = session.get::<String>(AFTER_LOGGED_IN_URL_SESSION_KEY);
+        let posted_ticket = req.extensions().get::<PostedTicket>().map(|posted| posted.0.clone());
+        let state = param_for_query_string(req.query_string(), STATE_PARAM);
+        let state_cookie = state
+            .as_ref()
+            .and_then(|state| req.cookie(&format!("{}{}", STATE_COOKIE_PREFIX, state)))
+            .map(|cookie| cookie.value().to_string());
         RequestCasInfo {
-            session,
-            ticket: ticket_for_query_string(req.query_string()),
+            session: Rc::new(session),
+            ticket_posted: posted_ticket.is_some(),
+            ticket: match posted_ticket {
+                Some(ticket) => Ok(Some(ticket)),
+                None => ticket_for_query_string(req.query_string()),
+            },
             cas_user,
-            url: url_for_request(req),
+            url: url_for_request(req, trusted_proxies),
+            same_origins: same_origins(&host_scheme_for_request(req, trusted_proxies), app_url),
             after_logged_in_url,
+            return_token: param_for_query_string(req.query_string(), RETURN_URL_PARAM),
+            state,
+            state_cookie,
+            nonce: param_for_query_string(req.query_string(), NONCE_PARAM),
+            api_request: false,
+            login_options: LoginOptions::default(),
+            session_expired: false,
+        }
+    }
+
+    /// Status of the redirects answering the request: a posted ticket is followed
+    /// by a GET
+    fn redirect_status(&self) -> http::StatusCode {
+        match self.ticket_posted {
+            true => http::StatusCode::SEE_OTHER,
+            false => http::StatusCode::TEMPORARY_REDIRECT,
         }
     }
 }
@@ -75,6 +198,27 @@ impl ActixCasClient {
             server_is_service: false,
             url_to_403,
             url_to_404,
+            session_max_lifetime: Some(session::DEFAULT_SESSION_MAX_LIFETIME),
+            session_idle_timeout: Some(session::DEFAULT_SESSION_IDLE_TIMEOUT),
+            redirect_policy: RedirectPolicy::new(),
+            return_url_mode: ReturnUrlMode::default(),
+            return_url_key: SigningKey::process_key(),
+            return_url_max_age: Duration::from_secs(10 * 60),
+            login_csrf_protection: true,
+            renew_session_on_login: true,
+            session_keys_kept_on_login: Vec::new(),
+            route_rules: Vec::new(),
+            api_detection: ApiDetection::default(),
+            error_pages: SharedRenderer::default(),
+            max_validation_failures: 3,
+            ticket_cache: Some(Arc::new(TicketCache::new(Duration::from_secs(30)))),
+            trusted_proxies: Vec::new(),
+            allowed_hosts: Vec::new(),
+            login_options: LoginOptions::default(),
+            locale_from_accept_language: false,
+            logout_behavior: LogoutBehavior::default(),
+            event_handler: None,
+            principal_resolver: None,
         }
     }
 
@@ -98,6 +242,253 @@ impl ActixCasClient {
     pub fn set_default_after_logged_in_path(&mut self, default_after_logged_in_path: Option<String>) {
         self.cas_client.set_default_after_logged_in_path(default_after_logged_in_path);
     }
+
+    pub fn session_max_lifetime(&self) -> Option<Duration> {
+        self.session_max_lifetime
+    }
+
+    pub fn session_idle_timeout(&self) -> Option<Duration> {
+        self.session_idle_timeout
+    }
+
+    /// Absolute lifetime of a CAS session: the user is logged out this long after
+    /// logging in, whatever their activity. Defaults to 8 hours, `None` disables it
+    pub fn set_session_max_lifetime(&mut self, session_max_lifetime: Option<Duration>) -> &mut Self {
+        self.session_max_lifetime = session_max_lifetime;
+        self
+    }
+
+    /// Idle timeout of a CAS session: the user is logged out after this long
+    /// without any request. Defaults to 30 minutes, `None` disables it
+    ///
+    /// The activity is only recorded every tenth of it (at least a minute), so
+    /// the user may be logged out that much earlier.
+    pub fn set_session_idle_timeout(&mut self, session_idle_timeout: Option<Duration>) -> &mut Self {
+        self.session_idle_timeout = session_idle_timeout;
+        self
+    }
+
+    pub fn redirect_policy(&self) -> &RedirectPolicy {
+        &self.redirect_policy
+    }
+
+    /// Allow-list of the urls users are redirected to after logging in or out
+    pub fn set_redirect_policy(&mut self, redirect_policy: RedirectPolicy) -> &mut Self {
+        self.redirect_policy = redirect_policy;
+        self
+    }
+
+    pub fn return_url_mode(&self) -> ReturnUrlMode {
+        self.return_url_mode
+    }
+
+    /// Where the url to send the user back to after login is kept during the CAS
+    /// round-trip
+    pub fn set_return_url_mode(&mut self, return_url_mode: ReturnUrlMode) -> &mut Self {
+        self.return_url_mode = return_url_mode;
+        self
+    }
+
+    /// Key signing the return urls. Defaults to a random key of the process: set it
+    /// when several instances of the app serve the same users.
+    pub fn set_return_url_key(&mut self, return_url_key: &[u8]) -> &mut Self {
+        self.return_url_key = SigningKey::new(return_url_key);
+        self
+    }
+
+    /// How long a signed return url stays valid, i.e. how long the user has to log
+    /// in to CAS
+    pub fn set_return_url_max_age(&mut self, return_url_max_age: Duration) -> &mut Self {
+        self.return_url_max_age = return_url_max_age;
+        self
+    }
+
+    /// Only accept tickets coming back from a login started by the same browser,
+    /// so that nobody can log a user in with their own ticket. Enabled by default.
+    pub fn set_login_csrf_protection(&mut self, login_csrf_protection: bool) -> &mut Self {
+        self.login_csrf_protection = login_csrf_protection;
+        self
+    }
+
+    /// Clear the session and renew its id when a user logs in, against session
+    /// fixation. Enabled by default.
+    pub fn set_renew_session_on_login(&mut self, renew_session_on_login: bool) -> &mut Self {
+        self.renew_session_on_login = renew_session_on_login;
+        self
+    }
+
+    /// Session keys carried across the session renewal of a login
+    pub fn set_session_keys_kept_on_login(&mut self, session_keys_kept_on_login: Vec<String>) -> &mut Self {
+        self.session_keys_kept_on_login = session_keys_kept_on_login;
+        self
+    }
+
+    pub fn route_rules(&self) -> &[RouteRule] {
+        &self.route_rules
+    }
+
+    /// Add a rule after the existing ones. The first rule matching a request sets
+    /// its access; requests matching none fall back to the `NoAuthBehavior` of the
+    /// `CasClient`.
+    pub fn add_route_rule(&mut self, route_rule: RouteRule) -> &mut Self {
+        self.route_rules.push(route_rule);
+        self
+    }
+
+    pub fn api_detection(&self) -> ApiDetection {
+        self.api_detection
+    }
+
+    /// How API requests, answered with a 401 rather than redirected to CAS, are
+    /// detected on routes without their own detection
+    pub fn set_api_detection(&mut self, api_detection: ApiDetection) -> &mut Self {
+        self.api_detection = api_detection;
+        self
+    }
+
+    /// Renderer of the error pages, `DefaultErrorPages` by default. `url_to_403`
+    /// and `url_to_404` still take precedence over the forbidden and not found pages.
+    pub fn set_error_page_renderer<R>(&mut self, renderer: R) -> &mut Self
+    where
+        R: ErrorPageRenderer + 'static,
+    {
+        self.error_pages = SharedRenderer(Arc::new(renderer));
+        self
+    }
+
+    pub fn max_validation_failures(&self) -> u32 {
+        self.max_validation_failures
+    }
+
+    /// Consecutive ticket validation failures after which a session gets an
+    /// error page instead of another login. Breaks redirect loops with CAS, e.g.
+    /// when it answers `INVALID_SERVICE` to every ticket.
+    pub fn set_max_validation_failures(&mut self, max_validation_failures: u32) -> &mut Self {
+        self.max_validation_failures = max_validation_failures;
+        self
+    }
+
+    pub fn ticket_cache_ttl(&self) -> Option<Duration> {
+        self.ticket_cache.as_ref().map(|cache| cache.ttl())
+    }
+
+    /// How long the result of a ticket validation is reused by the repeated
+    /// requests of the session that presented the ticket, 30 seconds by default.
+    /// `None` disables the cache.
+    ///
+    /// The cache is shared by the clones of the client, e.g. by the workers of an
+    /// `HttpServer` whose factory clones it.
+    pub fn set_ticket_cache_ttl(&mut self, ttl: Option<Duration>) -> &mut Self {
+        self.ticket_cache = ttl.map(|ttl| Arc::new(TicketCache::new(ttl)));
+        self
+    }
+
+    /// Trust the `Forwarded` and `X-Forwarded-*` headers of the peers in
+    /// `network`, in CIDR notation (`10.0.0.0/8`) or a single address. These
+    /// headers are ignored by default. An invalid `network` is an error, rather
+    /// than a proxy silently left untrusted.
+    pub fn add_trusted_proxy(&mut self, network: &str) -> Result<&mut Self, String> {
+        self.trusted_proxies.push(network.parse::<IpNetwork>()?);
+        Ok(self)
+    }
+
+    pub fn allowed_hosts(&self) -> &[String] {
+        &self.allowed_hosts
+    }
+
+    /// Serve the requests for `host` (`app.example.org`, or with a port to only
+    /// allow that port). Once a host is allowed, the requests for other hosts
+    /// are rejected with a 400.
+    pub fn add_allowed_host(&mut self, host: &str) -> &mut Self {
+        self.allowed_hosts.push(host.to_ascii_lowercase());
+        self
+    }
+
+    pub fn login_options(&self) -> &LoginOptions {
+        &self.login_options
+    }
+
+    /// Parameters of the CAS logins started from routes without their own options
+    pub fn set_login_options(&mut self, login_options: LoginOptions) -> &mut Self {
+        self.login_options = login_options;
+        self
+    }
+
+    pub fn locale_from_accept_language(&self) -> bool {
+        self.locale_from_accept_language
+    }
+
+    /// Show the CAS login form in the preferred language of the `Accept-Language`
+    /// header, when the login options set no locale
+    pub fn set_locale_from_accept_language(&mut self, locale_from_accept_language: bool) -> &mut Self {
+        self.locale_from_accept_language = locale_from_accept_language;
+        self
+    }
+
+    pub fn logout_behavior(&self) -> LogoutBehavior {
+        self.logout_behavior
+    }
+
+    /// What the logout route does besides ending the session of the app, a CAS
+    /// logout returning to the app by default
+    pub fn set_logout_behavior(&mut self, logout_behavior: LogoutBehavior) -> &mut Self {
+        self.logout_behavior = logout_behavior;
+        self
+    }
+
+    /// Notify `event_handler` of the logins, logouts and validation failures,
+    /// replacing the previous one
+    pub fn set_event_handler<H>(&mut self, event_handler: H) -> &mut Self
+    where
+        H: CasEventHandler + 'static,
+    {
+        self.event_handler = Some(SharedEventHandler(Arc::new(event_handler)));
+        self
+    }
+
+    /// Resolve the principal of the logged in users with `resolver`, replacing the
+    /// previous one. Handlers extract it with `Principal<R::Principal>`, any other
+    /// type being a server error.
+    pub fn set_principal_resolver<R>(&mut self, resolver: R) -> &mut Self
+    where
+        R: PrincipalResolver + 'static,
+    {
+        self.principal_resolver = Some(SharedResolver::new(resolver));
+        self
+    }
+
+    /// Handler notified of the logins, logouts and validation failures
+    pub(crate) fn event_handler(&self) -> Option<&dyn CasEventHandler> {
+        self.event_handler.as_ref().map(|handler| handler.0.as_ref())
+    }
+
+    /// Scheme and host requested by the client of `req`
+    fn request_origin(&self, req: &HttpRequest) -> String {
+        let (scheme, host) = forwarded::request_origin(req.head(), req.app_config(), &self.trusted_proxies);
+        format!("{}://{}", scheme, host)
+    }
+
+    /// `url` resolved against the url of `req`
+    pub(crate) fn absolute_url(&self, req: &HttpRequest, url: &str) -> Option<String> {
+        let base = Url::parse(&self.request_origin(req)).and_then(|origin| origin.join(req.path()));
+        match base.and_then(|base| base.join(url)) {
+            Ok(url) => Some(url.to_string()),
+            Err(err) => {
+                error!("Invalid url {:?}! Error: {}", url, err);
+                None
+            }
+        }
+    }
+
+    /// Whether users of `req` may be redirected to `target`
+    pub(crate) fn is_redirect_allowed(&self, req: &HttpRequest, target: &str) -> bool {
+        let same_origins = same_origins(&self.request_origin(req), self.cas_client.app_url());
+        let allowed = self.redirect_policy.is_allowed(target, &same_origins);
+        if !allowed {
+            warn!("Rejected redirect to {:?}", target);
+        }
+        allowed
+    }
 }
 
 /// Enable ActixCasClient to be used in Actix "extractors".
@@ -129,7 +520,7 @@ impl FromRequest for ActixCasClient {
 
 impl<S, B> Transform<S> for ActixCasClient
 where
-    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
     S::Future: 'static,
     B: 'static,
 {
@@ -142,45 +533,167 @@ where
 
     fn new_transform(&self, service: S) -> Self::Future {
         ok(ActixCasClientMiddleware {
-            service,
-            cas_client: self.cas_client.clone(),
-            server_is_service: self.server_is_service,
-            url_to_403: self.url_to_403.clone(),
-            url_to_404: self.url_to_404.clone(),
+            service: Rc::new(RefCell::new(service)),
+            client: Rc::new(self.clone()),
         })
     }
 }
 pub struct ActixCasClientMiddleware<S> {
-    service: S,
-    cas_client: CasClient,
-    server_is_service: bool,
-    url_to_403: Option<String>,
-    url_to_404: Option<String>,
+    service: Rc<RefCell<S>>,
+    client: Rc<ActixCasClient>,
+}
+
+// The middleware handles the requests whose body it reads in a future of its own
+impl<S> Clone for ActixCasClientMiddleware<S> {
+    fn clone(&self) -> Self {
+        ActixCasClientMiddleware {
+            service: self.service.clone(),
+            client: self.client.clone(),
+        }
+    }
 }
 
-#[derive(Debug, Deserialize)]
-struct CasTicketQuery {
-    ticket: String,
+fn host_scheme_for_request(req: &ServiceRequest, trusted_proxies: &[IpNetwork]) -> String {
+    let (scheme, host) = forwarded::request_origin(req.head(), req.app_config(), trusted_proxies);
+    format!("{}://{}", scheme, host)
 }
 
-fn host_scheme_for_request(req: &ServiceRequest) -> String {
-    let connection_info = req.connection_info();
-    let the_url = format!("{}://{}", connection_info.scheme(), connection_info.host(),);
+fn url_for_request(req: &ServiceRequest, trusted_proxies: &[IpNetwork]) -> String {
+    let the_url = format!("{}{}", host_scheme_for_request(req, trusted_proxies), req.uri());
     the_url
 }
 
-fn url_for_request(req: &ServiceRequest) -> String {
-    let the_url = format!("{}{}", host_scheme_for_request(req), req.uri());
-    the_url
+/// Whether `host` is one of `allowed_hosts`, or any host when none is
+fn is_host_allowed(host: &str, allowed_hosts: &[String]) -> bool {
+    allowed_hosts.is_empty()
+        || allowed_hosts
+            .iter()
+            .any(|allowed| allowed == host || allowed == forwarded::host_name(host))
+}
+
+/// Language of the highest weight in an `Accept-Language` header, as a CAS
+/// locale: `fr-CA` becomes `fr_CA`
+fn preferred_locale(accept_language: &str) -> Option<String> {
+    let mut preferred: Option<(&str, f32)> = None;
+    for range in accept_language.split(',') {
+        let mut parts = range.split(';');
+        let language = parts.next().unwrap_or("").trim();
+        let weight = parts
+            .find_map(|param| param.trim().strip_prefix("q="))
+            .map(|weight| weight.trim().parse::<f32>().unwrap_or(0.0))
+            .unwrap_or(1.0);
+        let is_valid = !language.is_empty()
+            && language != "*"
+            && language.chars().all(|c| c.is_ascii_alphanumeric() || c == '-');
+        if is_valid && weight > 0.0 && !matches!(preferred, Some((_, best)) if best >= weight) {
+            preferred = Some((language, weight));
+        }
+    }
+    preferred.map(|(language, _)| language.replace('-', "_"))
+}
+
+/// Origins users may be redirected to when no origin is allowed explicitly: the
+/// origin of the request and the one of `app_url`
+fn same_origins(request_origin: &str, app_url: &str) -> Vec<String> {
+    let mut origins = vec![request_origin.to_string()];
+    if let Some(app_origin) = redirect::origin_of(app_url) {
+        origins.push(app_origin);
+    }
+    origins
+}
+
+/// Removes the `ticket` parameter added by CAS, and the ones carrying the return
+/// url, from `url`
+fn url_without_cas_params(url: &str) -> String {
+    match Url::parse(url) {
+        Ok(mut the_url) => {
+            let params: Vec<(String, String)> = the_url
+                .query_pairs()
+                .filter(|(key, _)| !CAS_PARAMS.contains(&key.as_ref()))
+                .map(|(key, value)| (key.into_owned(), value.into_owned()))
+                .collect();
+            match params.is_empty() {
+                true => the_url.set_query(None),
+                false => {
+                    the_url.query_pairs_mut().clear().extend_pairs(params);
+                }
+            };
+            the_url.to_string()
+        }
+        Err(_) => url.to_string(),
+    }
+}
+
+/// Adds `name=value` to the query string of `url`, which may be relative
+fn url_with_param(url: &str, name: &str, value: &str) -> String {
+    match Url::parse(url) {
+        Ok(mut the_url) => {
+            the_url.query_pairs_mut().append_pair(name, value);
+            the_url.to_string()
+        }
+        Err(_) => {
+            let separator = if url.contains('?') { '&' } else { '?' };
+            let param: String = url::form_urlencoded::Serializer::new(String::new())
+                .append_pair(name, value)
+                .finish();
+            format!("{}{}{}", url, separator, param)
+        }
+    }
+}
+
+/// Session of a request handed to a `NoAuthHandler`
+struct ActixSessionState<'s>(&'s Session);
+
+impl SessionState for ActixSessionState<'_> {
+    fn get(&self, key: &str) -> Option<serde_json::Value> {
+        self.0.get(key).unwrap_or_else(|err| {
+            error!("Error while reading {} from session! Error: {}", key, err);
+            None
+        })
+    }
+
+    fn set(&self, key: &str, value: serde_json::Value) {
+        if let Err(err) = self.0.set(key, value) {
+            error!("Error while saving {} in session! Error: {}", key, err);
+        }
+    }
+
+    fn remove(&self, key: &str) {
+        self.0.remove(key);
+    }
+}
+
+/// Store `cas_user` in the session of their login. Renewing the session keeps
+/// `kept_keys` only, and must come last.
+fn log_in(session: &Session, cas_user: CasUser, renew_session: bool, kept_keys: &[String]) {
+    if renew_session {
+        session::clear_for_login(session, kept_keys);
+    }
+    if let Err(err) = session::store_cas_user(session, cas_user) {
+        error!("Error while saving cas_user in session! Error: {}", err);
+    };
+    session::end_gateway(session);
+    if renew_session {
+        session.renew();
+    }
+}
+
+/// Cookie keeping the signed return url of the login identified by `state`
+fn state_cookie<'c>(state: &str, value: String) -> Cookie<'c> {
+    Cookie::build(format!("{}{}", STATE_COOKIE_PREFIX, state), value)
+        .path("/")
+        .http_only(true)
+        .same_site(SameSite::Lax)
+        .finish()
 }
 
 impl<S, B> ActixCasClientMiddleware<S>
 where
-    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
     S::Future: 'static,
     B: 'static,
 {
-    fn authenticate(&self, req_info: &RequestCasInfo) -> Option<HttpResponse> {
+    fn authenticate(&self, req_info: &RequestCasInfo) -> Option<ResponseFuture> {
         match req_info.cas_user {
             Ok(None) => self.authenticate_user(req_info), // No user or error
             _ => None, // User is logged in
@@ -188,19 +701,19 @@ where
     }
 
     fn authenticated_or_403(&self, req_info: &RequestCasInfo) -> Option<HttpResponse> {
-        self.authenticated_or_error(req_info, http::StatusCode::FORBIDDEN, self.url_to_403.clone())
+        self.authenticated_or_error(req_info, ErrorPage::Forbidden, self.client.url_to_403.clone())
     }
 
     fn authenticated_or_404(&self, req_info: &RequestCasInfo) -> Option<HttpResponse> {
-        self.authenticated_or_error(req_info, http::StatusCode::NOT_FOUND, self.url_to_404.clone())
+        self.authenticated_or_error(req_info, ErrorPage::NotFound, self.client.url_to_404.clone())
     }
 
-    fn force_authentication(&self, req_info: &RequestCasInfo) -> Option<HttpResponse> {
+    fn force_authentication(&self, req_info: &RequestCasInfo) -> Option<ResponseFuture> {
         self.authenticate_user(req_info)
     }
 
     // private functions
-    pub(self) fn authenticate_user(&self, req_info: &RequestCasInfo) -> Option<HttpResponse> {
+    pub(self) fn authenticate_user(&self, req_info: &RequestCasInfo) -> Option<ResponseFuture> {
         match &req_info.ticket {
             Ok(Some(ticket)) => {
                 info!("Ticket = {}!", ticket);
@@ -208,76 +721,479 @@ where
             }
             _ => {
                 info!("Ticket not found!");
-                self.handle_needs_authentication(req_info)
+                self.handle_needs_authentication(req_info).map(respond)
             }
         }
     }
 
-    pub(self) fn authenticated_or_error(&self, req_info: &RequestCasInfo, status_code: http::StatusCode, error_path: Option<String>) -> Option<HttpResponse> {
+    pub(self) fn authenticated_or_error(&self, req_info: &RequestCasInfo, page: ErrorPage, error_path: Option<String>) -> Option<HttpResponse> {
         if let Ok(None) = &req_info.cas_user {
-            let resp = match error_path {
-                Some(url) => Some(HttpResponse::build(http::StatusCode::TEMPORARY_REDIRECT)
-                    .header(http::header::LOCATION, url)
-                    .finish()),
-                _ => Some(HttpResponse::build(status_code).finish())
-            };
-            return resp
+            return Some(self.error_response(page, error_path))
         }
         None
     }
 
+    /// Error page of a request, or redirect to the page handling such errors
+    fn error_response(&self, page: ErrorPage, error_path: Option<String>) -> HttpResponse {
+        match error_path {
+            Some(url) => HttpResponse::build(http::StatusCode::TEMPORARY_REDIRECT)
+                .header(http::header::LOCATION, url)
+                .finish(),
+            _ => self.client.error_pages.0.render(&page),
+        }
+    }
+
+    /// Service url sent to CAS, without the return url: the requested url when the
+    /// server is the service, the login service url otherwise
+    fn base_service_url(&self, req_info: &RequestCasInfo) -> String {
+        match self.client.server_is_service {
+            true => url_without_cas_params(&req_info.url),
+            false => self.client.cas_client.service_url(),
+        }
+    }
+
+    /// Service url with the parameters of a login round-trip, always in the same
+    /// order so that the url validated matches the one sent to CAS
+    fn service_url_with(
+        &self,
+        req_info: &RequestCasInfo,
+        return_token: Option<&str>,
+        state: Option<&str>,
+        nonce: Option<&str>,
+    ) -> String {
+        let mut service_url = self.base_service_url(req_info);
+        let params = [(RETURN_URL_PARAM, return_token), (STATE_PARAM, state), (NONCE_PARAM, nonce)];
+        for (name, value) in params.iter() {
+            if let Some(value) = value {
+                service_url = url_with_param(&service_url, name, value);
+            }
+        }
+        service_url
+    }
+
+    /// Service url the ticket of the request was issued for
+    fn service_url(&self, req_info: &RequestCasInfo) -> String {
+        self.service_url_with(
+            req_info,
+            req_info.return_token.as_deref(),
+            req_info.state.as_deref(),
+            req_info.nonce.as_deref(),
+        )
+    }
+
+    /// Whether the ticket of the request comes back from a login started by the
+    /// same browser: its state cookie or its nonce in the session
+    fn is_login_initiated(&self, req_info: &RequestCasInfo) -> bool {
+        match self.client.return_url_mode {
+            ReturnUrlMode::StateCookie => req_info
+                .state_cookie
+                .as_ref()
+                .and_then(|state_cookie| self.client.return_url_key.verify(state_cookie, session::now()))
+                .is_some(),
+            _ => match &req_info.nonce {
+                Some(nonce) => session::take_login_nonce(&req_info.session, nonce),
+                None => false,
+            },
+        }
+    }
+
     fn handle_needs_authentication(&self, req_info: &RequestCasInfo) -> Option<HttpResponse> {
-        let url = req_info.url.clone();
-        let login_url = match self.server_is_service {
-            true => self
-                .cas_client
-                // .login_url_for_service(&host_scheme_for_request(req)),
-                .login_url_for_service(&url),
-            false => self.cas_client.login_url(),
+        // API clients are answered the login url rather than redirected there, bound
+        // to the request all the same
+        if req_info.login_options.gateway() && !req_info.api_request {
+            // Back from CAS without ticket: no single sign-on session
+            if session::gateway_started(&req_info.session) {
+                return None;
+            }
+            session::start_gateway(&req_info.session);
+        }
+        if self.client.ticket_cache.is_some() {
+            session::ensure_login_binding(&req_info.session);
+        }
+        let mut return_token = None;
+        let mut state = None;
+        let mut nonce = None;
+        let mut cookie = None;
+        if self.client.return_url_mode != ReturnUrlMode::Session {
+            let expires_at = session::now() + self.client.return_url_max_age.as_secs();
+            return_token = Some(
+                self.client.return_url_key
+                    .sign(&url_without_cas_params(&req_info.url), expires_at),
+            );
+        }
+        match self.client.return_url_mode {
+            // The state cookie binds the login to the browser
+            ReturnUrlMode::StateCookie => {
+                let state_id = return_url::random_id();
+                let mut state_cookie = state_cookie(&state_id, return_token.take().unwrap_or_default());
+                state_cookie.set_max_age(time::Duration::seconds(
+                    self.client.return_url_max_age.as_secs() as i64,
+                ));
+                state_cookie.set_secure(req_info.url.starts_with("https://"));
+                state = Some(state_id);
+                cookie = Some(state_cookie);
+            }
+            _ if self.client.login_csrf_protection => {
+                let nonce_id = return_url::random_id();
+                session::push_login_nonce(&req_info.session, &nonce_id);
+                nonce = Some(nonce_id);
+            }
+            _ => {}
         };
+        if req_info.login_options.renew() {
+            if let Some(login_id) = state.as_deref().or(nonce.as_deref()) {
+                session::push_renewed_login(&req_info.session, login_id);
+            }
+        }
+        let service_url = self.service_url_with(
+            req_info,
+            return_token.as_deref(),
+            state.as_deref(),
+            nonce.as_deref(),
+        );
+        let login_url = self
+            .client.cas_client
+            .login_url_with_options(&service_url, &req_info.login_options);
         let response = match login_url {
-            Some(login_url) => HttpResponse::build(http::StatusCode::TEMPORARY_REDIRECT)
-                .header(http::header::LOCATION, login_url)
-                .finish(),
-            None => HttpResponse::build(http::StatusCode::INTERNAL_SERVER_ERROR)
-                .body("CAS login URL not configured"),
+            Some(login_url) if req_info.api_request => {
+                let mut response = api::unauthenticated_response(&login_url);
+                if let Some(cookie) = cookie {
+                    if let Err(err) = response.add_cookie(&cookie) {
+                        error!("Error while setting the state cookie! Error: {}", err);
+                    }
+                }
+                response
+            }
+            Some(login_url) => {
+                let mut response = HttpResponse::build(req_info.redirect_status());
+                response.header(http::header::LOCATION, login_url);
+                if let Some(cookie) = cookie {
+                    response.cookie(cookie);
+                }
+                response.finish()
+            }
+            None => {
+                error!("CAS login url cannot be built for service {}", service_url);
+                self.client.error_pages.0.render(&ErrorPage::ConfigurationError)
+            }
         };
         Some(response)
     }
 
-    fn handle_ticket(&self, req_info: &RequestCasInfo, ticket: String) -> Option<HttpResponse> {
-        let user = self.cas_client.validate_service_ticket(&ticket);
-        match user {
-            Ok(Some(cas_user)) => self.handle_user(req_info, cas_user),
-            _ => self.handle_needs_authentication(req_info),
+    // The responses are awaitable themselves, yet answered as they are
+    #[allow(clippy::async_yields_async)]
+    fn handle_ticket(&self, req_info: &RequestCasInfo, ticket: String) -> Option<ResponseFuture> {
+        let service_url = self.service_url(req_info);
+        let cache = match (&self.client.ticket_cache, session::login_binding(&req_info.session)) {
+            (Some(cache), Some(binding)) => Some((cache, binding)),
+            _ => None,
+        };
+        // The login of a repeated request was checked by the first one
+        let reused = cache.as_ref().and_then(|(cache, binding)| cache.reuse(&ticket, binding));
+        let user = match reused {
+            Some(user) => {
+                debug!("Reusing the validation of ticket {}", ticket_fingerprint(&ticket));
+                user
+            }
+            None => {
+                if self.client.login_csrf_protection && !self.is_login_initiated(req_info) {
+                    warn!("Rejected ticket {} without a matching login", ticket_fingerprint(&ticket));
+                    return self.handle_needs_authentication(req_info).map(respond);
+                }
+                // CAS only enforces `renew` when validating the ticket, whether the
+                // callback asks for it or the login was started asking for it
+                let login_id = req_info.state.as_ref().or(req_info.nonce.as_ref());
+                let renewed_login = match login_id {
+                    Some(login_id) => session::take_renewed_login(&req_info.session, login_id),
+                    None => false,
+                };
+                let renew = renewed_login || req_info.login_options.renew();
+                let validate = || {
+                    self.client.cas_client
+                        .validate_ticket_with_renew(&ticket, &service_url, renew)
+                };
+                match &cache {
+                    Some((cache, binding)) => cache.validate(&ticket, binding, validate),
+                    None => validate(),
+                }
+            }
+        };
+        let error = match user {
+            Ok(cas_user) => {
+                session::reset_validation_failures(&req_info.session);
+                return self.handle_user(req_info, cas_user);
+            }
+            Err(error) => error,
+        };
+        // Visiting the url without the ticket starts a new login
+        let retry_url = url_without_cas_params(&req_info.url);
+        let response = match &error {
+            CasValidationError::Failure { code, .. } => {
+                let failures = session::record_validation_failure(&req_info.session);
+                if failures < self.client.max_validation_failures {
+                    self.handle_needs_authentication(req_info)
+                } else {
+                    error!(
+                        "CAS rejected {} tickets in a row with {}, stopping the login of {}. Check the service url registered in CAS: {}",
+                        failures, code, retry_url, service_url
+                    );
+                    session::reset_validation_failures(&req_info.session);
+                    let code = code.clone();
+                    Some(self.client.error_pages.0.render(&ErrorPage::ValidationFailed { retry_url, code }))
+                }
+            }
+            _ => Some(self.client.error_pages.0.render(&ErrorPage::CasUnavailable { retry_url })),
+        };
+        let event_handler = match &self.client.event_handler {
+            Some(event_handler) => event_handler.0.clone(),
+            None => return response.map(respond),
+        };
+        match response {
+            Some(response) => Some(
+                async move {
+                    event_handler.on_failure(&error).await;
+                    response
+                }
+                .boxed_local(),
+            ),
+            // The request goes on without waiting for the handler
+            None => {
+                actix_rt::spawn(async move { event_handler.on_failure(&error).await });
+                None
+            }
         }
     }
 
-    fn handle_user(&self, req_info: &RequestCasInfo, cas_user: CasUser) -> Option<HttpResponse> {
-        if let Err(err) = req_info.session.set(CAS_USER_SESSION_KEY, cas_user) {
-            error!("Error while saving cas_user in session! Error: {}", err);
+    // The responses are awaitable themselves, yet answered as they are
+    #[allow(clippy::async_yields_async)]
+    fn handle_user(&self, req_info: &RequestCasInfo, cas_user: CasUser) -> Option<ResponseFuture> {
+        let mut return_path = self.return_url(req_info);
+        if let Some(path) = &return_path {
+            if !self.client.redirect_policy.is_allowed(path, &req_info.same_origins) {
+                warn!("Rejected redirect after login to {:?}", path);
+                return_path = None;
+            }
+        }
+        // Writing to the session after renewing it keeps its id, the handler
+        // expects no posted ticket, and the event handler answers after the handler
+        // would run: redirect rather than letting the handler run
+        let must_redirect = self.client.renew_session_on_login
+            || req_info.ticket_posted
+            || self.client.event_handler.is_some();
+        if must_redirect && return_path.is_none() {
+            let current_url = url_without_cas_params(&req_info.url);
+            if self.client.redirect_policy.is_allowed(&current_url, &req_info.same_origins) {
+                return_path = Some(current_url);
+            }
+        }
+        let event_handler = match &self.client.event_handler {
+            Some(event_handler) => event_handler.0.clone(),
+            None => {
+                let client = &self.client;
+                log_in(&req_info.session, cas_user, client.renew_session_on_login, &client.session_keys_kept_on_login);
+                return return_path.map(|path| respond(self.login_redirect(req_info, path)));
+            }
         };
-        match &req_info.after_logged_in_url {
-            Ok(Some(return_path)) => {
-                req_info.session.remove(AFTER_LOGGED_IN_URL_SESSION_KEY);
-                Some(
-                    HttpResponse::build(http::StatusCode::TEMPORARY_REDIRECT)
-                        .header(http::header::LOCATION, return_path.clone())
-                        .finish(),
-                )
+        let redirect = self.login_redirect(req_info, return_path.unwrap_or_else(|| String::from("/")));
+        let session = req_info.session.clone();
+        let retry_url = url_without_cas_params(&req_info.url);
+        let renew_session = self.client.renew_session_on_login;
+        let kept_keys = self.client.session_keys_kept_on_login.clone();
+        let error_pages = self.client.error_pages.clone();
+        Some(
+            async move {
+                match event_handler.on_login(&cas_user).await {
+                    Ok(LoginDecision::Allow) => {
+                        log_in(&session, cas_user, renew_session, &kept_keys);
+                        redirect
+                    }
+                    Ok(LoginDecision::AllowAs(cas_user)) => {
+                        log_in(&session, cas_user, renew_session, &kept_keys);
+                        redirect
+                    }
+                    Ok(LoginDecision::Deny) => {
+                        info!("Login of CAS user {} denied", cas_user.username());
+                        session::end_gateway(&session);
+                        error_pages.0.render(&ErrorPage::LoginDenied)
+                    }
+                    Err(err) => {
+                        error!("Error while handling the login of {}! Error: {}", cas_user.username(), err);
+                        error_pages.0.render(&ErrorPage::LoginFailed { retry_url })
+                    }
+                }
             }
-            _ => None,
+            .boxed_local(),
+        )
+    }
+
+    /// Redirect to `return_path` answering a login
+    fn login_redirect(&self, req_info: &RequestCasInfo, return_path: String) -> HttpResponse {
+        let mut response = HttpResponse::build(req_info.redirect_status());
+        response.header(http::header::LOCATION, return_path);
+        if let (ReturnUrlMode::StateCookie, Some(state)) = (self.client.return_url_mode, &req_info.state) {
+            response.del_cookie(&state_cookie(state, String::new()));
+        }
+        response.finish()
+    }
+
+    /// Url to send the user back to after login
+    fn return_url(&self, req_info: &RequestCasInfo) -> Option<String> {
+        let return_token = match self.client.return_url_mode {
+            ReturnUrlMode::Session => {
+                return match &req_info.after_logged_in_url {
+                    Ok(Some(return_path)) => {
+                        req_info.session.remove(AFTER_LOGGED_IN_URL_SESSION_KEY);
+                        Some(return_path.clone())
+                    }
+                    _ => None,
+                }
+            }
+            ReturnUrlMode::SignedServiceUrl => req_info.return_token.as_ref(),
+            ReturnUrlMode::StateCookie => req_info.state_cookie.as_ref(),
+        }?;
+        let return_url = self.client.return_url_key.verify(return_token, session::now());
+        if return_url.is_none() {
+            warn!("Invalid or expired return url: {:?}", return_token);
         }
+        return_url
     }
 
-    fn no_auth_response(&mut self, req_info: &RequestCasInfo) -> Option<LocalBoxFuture<'static, HttpResponse>> {
-        let resp = match self.cas_client.no_auth_behavior() {
-            NoAuthBehavior::AuthenticatedOr403 => self.authenticated_or_403(req_info),
-            NoAuthBehavior::AuthenticatedOr404 => self.authenticated_or_404(req_info),
+    /// Request handed to the `NoAuthHandler` of a custom behavior
+    fn no_auth_request(&self, req: &ServiceRequest, req_info: &RequestCasInfo) -> NoAuthRequest {
+        let headers = req
+            .headers()
+            .iter()
+            .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+            .collect();
+        let mut request = NoAuthRequest::new(req.method().as_str(), &req_info.url, headers);
+        request
+            .set_ticket(req_info.ticket.as_ref().ok().cloned().flatten())
+            .set_login_url(
+                self.client.cas_client
+                    .login_url_with_options(&self.base_service_url(req_info), &req_info.login_options),
+            )
+            .set_session_expired(req_info.session_expired);
+        request
+    }
+
+    /// Response to the `outcome` of a `NoAuthHandler`, `None` to let the request
+    /// through
+    fn custom_response(&self, req_info: &RequestCasInfo, outcome: NoAuthOutcome) -> Option<ResponseFuture> {
+        let response = match outcome {
+            NoAuthOutcome::Continue => return None,
+            NoAuthOutcome::Authenticate => return self.authenticate_user(req_info),
+            NoAuthOutcome::Redirect(url) => {
+                if !self.client.redirect_policy.is_allowed(&url, &req_info.same_origins) {
+                    warn!("Rejected redirect of a user who is not logged in to {:?}", url);
+                    return Some(respond(HttpResponse::Forbidden().finish()));
+                }
+                HttpResponse::TemporaryRedirect()
+                    .header(http::header::LOCATION, url)
+                    .finish()
+            }
+            NoAuthOutcome::Respond { status, headers, body } => {
+                let status = http::StatusCode::from_u16(status).unwrap_or_else(|err| {
+                    error!("Invalid status code of a custom response! Error: {}", err);
+                    http::StatusCode::INTERNAL_SERVER_ERROR
+                });
+                let mut response = HttpResponse::build(status);
+                for (name, value) in headers {
+                    response.header(name.as_str(), value);
+                }
+                response.body(body)
+            }
+        };
+        Some(respond(response))
+    }
+
+    fn no_auth_response(&mut self, req_info: &RequestCasInfo, behavior: &NoAuthBehavior) -> Option<ResponseFuture> {
+        match behavior {
+            NoAuthBehavior::AuthenticatedOr403 => self.authenticated_or_403(req_info).map(respond),
+            NoAuthBehavior::AuthenticatedOr404 => self.authenticated_or_404(req_info).map(respond),
             NoAuthBehavior::Authenticate => self.authenticate(req_info),
             NoAuthBehavior::ForceAuthentication => self.force_authentication(req_info),
+            // Users who are not logged in are handed to `call_custom`
+            NoAuthBehavior::Custom(_) => None,
+        }
+    }
+
+    /// Login options of the route of `req`
+    fn login_options_for(&self, req: &ServiceRequest, rule: Option<&RouteRule>) -> LoginOptions {
+        let mut login_options = rule
+            .and_then(|rule| rule.login_options())
+            .unwrap_or(&self.client.login_options)
+            .clone();
+        if self.client.locale_from_accept_language && login_options.locale().is_none() {
+            let accept_language = req
+                .headers()
+                .get(http::header::ACCEPT_LANGUAGE)
+                .and_then(|value| value.to_str().ok());
+            login_options.set_locale(accept_language.and_then(preferred_locale));
+        }
+        login_options
+    }
+
+    /// Whether `req` may be CAS posting a ticket: a form posted to the service url
+    /// of a route whose logins use `method=POST`. When the server is the service,
+    /// the url must carry the parameters binding a login to the browser, so that
+    /// the other forms posted to the route are left alone.
+    fn may_carry_posted_ticket(&self, req: &ServiceRequest) -> bool {
+        if req.method() != http::Method::POST {
+            return false;
+        }
+        let header = |name| req.headers().get(name).and_then(|value| value.to_str().ok());
+        let is_form = header(http::header::CONTENT_TYPE)
+            .map(|content_type| content_type.starts_with("application/x-www-form-urlencoded"))
+            .unwrap_or(false);
+        let is_small = header(http::header::CONTENT_LENGTH)
+            .and_then(|length| length.parse::<usize>().ok())
+            .map(|length| length <= MAX_TICKET_FORM_SIZE)
+            .unwrap_or(true);
+        let is_service_url = match self.client.server_is_service {
+            true => [RETURN_URL_PARAM, STATE_PARAM, NONCE_PARAM]
+                .iter()
+                .any(|name| param_for_query_string(req.query_string(), name).is_some()),
+            false => {
+                let service_url = self.client.cas_client.service_url();
+                let service_path = Url::parse(&service_url)
+                    .map(|url| url.path().to_string())
+                    .unwrap_or(service_url);
+                req.path() == service_path
+            }
         };
-        resp.map(|r| ready(r).boxed_local())
+        if !(is_form && is_small && is_service_url) {
+            return false;
+        }
+        let rule = rules::rule_for_request(&self.client.route_rules, req.method(), req.path());
+        // Public routes take no ticket
+        let is_protected = rule
+            .map(|rule| rule.access().no_auth_behavior().is_some())
+            .unwrap_or(true);
+        is_protected && self.login_options_for(req, rule).post_response()
+    }
+
+    /// Look for a ticket in the posted form, then handle `req` with its body
+    /// restored for the handler
+    async fn call_with_posted_ticket(mut self, mut req: ServiceRequest) -> Result<ServiceResponse<B>, Error> {
+        let mut payload = req.take_payload();
+        let mut form = web::BytesMut::new();
+        while form.len() <= MAX_TICKET_FORM_SIZE {
+            match payload.next().await {
+                Some(chunk) => form.extend_from_slice(&chunk?),
+                None => break,
+            }
+        }
+        if form.len() <= MAX_TICKET_FORM_SIZE {
+            let ticket = url::form_urlencoded::parse(&form)
+                .find(|(name, _)| name == "ticket")
+                .map(|(_, ticket)| ticket.into_owned());
+            if let Some(ticket) = ticket {
+                req.extensions_mut().insert(PostedTicket(ticket));
+            }
+        }
+        let read = stream::once(ready(Ok(form.freeze())));
+        req.set_payload(Payload::Stream(Box::pin(read.chain(payload))));
+        self.do_call(req).await
     }
 
     fn do_call(
@@ -285,10 +1201,50 @@ where
         req: ServiceRequest,
     ) -> Either<S::Future, LocalBoxFuture<'static, Result<ServiceResponse<B>, Error>>> {
         debug!("*** BEGIN CAS CLIENT MIDDLEWARE ***");
-        debug!("*** CAS CLIENT MIDDLEWARE: CURRENT URL : {:?} ***", url_for_request(&req));
-        self.set_after_logged_in_url(&req);
-        let req_info = RequestCasInfo::from_service_request(&req);
-        let resp = self.no_auth_response(&req_info);
+        debug!(
+            "*** CAS CLIENT MIDDLEWARE: CURRENT URL : {:?} ***",
+            url_for_request(&req, &self.client.trusted_proxies)
+        );
+        let (_, host) = forwarded::request_origin(req.head(), req.app_config(), &self.client.trusted_proxies);
+        if !is_host_allowed(&host, &self.client.allowed_hosts) {
+            warn!("Rejected request for host {:?}", host);
+            let resp = self.client.error_pages.0.render(&ErrorPage::InvalidHost);
+            return Either::Right(ok(req.into_response(resp.into_body())).boxed_local());
+        }
+        let session_expired = session::enforce_session_timeouts(
+            &req.get_session(),
+            self.client.session_max_lifetime,
+            self.client.session_idle_timeout,
+        );
+        let rule = rules::rule_for_request(&self.client.route_rules, req.method(), req.path());
+        let access = rule.map(|rule| rule.access().clone());
+        let api_detection = rule
+            .and_then(|rule| rule.api_detection())
+            .unwrap_or(self.client.api_detection);
+        let login_options = self.login_options_for(&req, rule);
+        // Public routes have no behavior
+        let behavior = match &access {
+            Some(access) => access.no_auth_behavior().cloned(),
+            None => Some(self.client.cas_client.no_auth_behavior().clone()),
+        };
+        let api_request = api_detection.is_api_request(req.head());
+        // API requests are not pages to come back to after login
+        if behavior.is_some() && !api_request && self.client.return_url_mode == ReturnUrlMode::Session {
+            self.set_after_logged_in_url(&req);
+        }
+        let mut req_info = RequestCasInfo::from_service_request(&req, self.client.cas_client.app_url(), &self.client.trusted_proxies);
+        req_info.api_request = api_request;
+        req_info.login_options = login_options;
+        req_info.session_expired = session_expired;
+        if let Some(NoAuthBehavior::Custom(handler)) = &behavior {
+            if !matches!(req_info.cas_user, Ok(Some(_))) {
+                return Either::Right(self.call_custom(req, req_info, handler.clone()));
+            }
+        }
+        let resp = match &behavior {
+            Some(behavior) => self.no_auth_response(&req_info, behavior),
+            None => None,
+        };
         match resp {
             Some(resp) => {
                 debug!("*** CAS CLIENT MIDDLEWARE RESPONSE: INTERCEPT REQUEST ***");
@@ -297,15 +1253,93 @@ where
             }
             None => {
                 debug!("*** CAS CLIENT MIDDLEWARE RESPONSE: CONTINUE ***");
-                Either::Left(self.service.call(req))
+                // Spare the extractors another session deserialization
+                let cas_user = match req_info.cas_user {
+                    Ok(Some(cas_user)) => Some(cas_user),
+                    // Maybe logged in by this request
+                    _ => req_info.session.get::<CasUser>(CAS_USER_SESSION_KEY).unwrap_or(None),
+                };
+                if let (Some(access), Some(cas_user)) = (&access, &cas_user) {
+                    if !access.is_authorized(cas_user) {
+                        info!("CAS user {} is not authorized to access {}", cas_user.username(), req.path());
+                        let resp = self.error_response(ErrorPage::Forbidden, self.client.url_to_403.clone());
+                        return Either::Right(ok(req.into_response(resp.into_body())).boxed_local());
+                    }
+                }
+                if let Some(resolver) = &self.client.principal_resolver {
+                    // Checked by the `Principal` extractor
+                    req.extensions_mut().insert(resolver.clone());
+                }
+                if let (Some(resolver), Some(cas_user)) = (&self.client.principal_resolver, &cas_user) {
+                    if !session::has_principal(&req_info.session) {
+                        return Either::Right(self.call_with_principal(req, resolver.clone(), cas_user.clone()));
+                    }
+                }
+                if let Some(cas_user) = cas_user {
+                    req.extensions_mut().insert(cas_user);
+                }
+                Either::Left(self.service.borrow_mut().call(req))
+            }
+        }
+    }
+
+    /// Resolve the principal of `cas_user` before handling `req`, which gets a 403
+    /// if it cannot be
+    fn call_with_principal(
+        &self,
+        req: ServiceRequest,
+        resolver: SharedResolver,
+        cas_user: CasUser,
+    ) -> LocalBoxFuture<'static, Result<ServiceResponse<B>, Error>> {
+        let middleware = self.clone();
+        async move {
+            let principal = resolver.resolve(&cas_user).await.and_then(|principal| {
+                session::store_principal(&req.get_session(), principal)
+                    .map_err(|err| err.to_string().into())
+            });
+            if let Err(err) = principal {
+                error!("Cannot resolve the principal of CAS user {}! Error: {}", cas_user.username(), err);
+                let resp = middleware.error_response(ErrorPage::Forbidden, middleware.client.url_to_403.clone());
+                return Ok(req.into_response(resp.into_body()));
+            }
+            req.extensions_mut().insert(cas_user);
+            let service_resp = middleware.service.borrow_mut().call(req);
+            service_resp.await
+        }
+        .boxed_local()
+    }
+
+    /// Let the `NoAuthHandler` of a custom behavior decide on `req`, of a user who
+    /// is not logged in
+    fn call_custom(
+        &self,
+        req: ServiceRequest,
+        req_info: RequestCasInfo,
+        handler: Arc<dyn NoAuthHandler>,
+    ) -> LocalBoxFuture<'static, Result<ServiceResponse<B>, Error>> {
+        let request = self.no_auth_request(&req, &req_info);
+        let middleware = self.clone();
+        async move {
+            let session = ActixSessionState(&req_info.session);
+            let outcome = handler.handle(&request, &session).await;
+            match middleware.custom_response(&req_info, outcome) {
+                Some(resp) => {
+                    let resp = resp.await;
+                    Ok(req.into_response(resp.into_body()))
+                }
+                None => {
+                    let service_resp = middleware.service.borrow_mut().call(req);
+                    service_resp.await
+                }
             }
         }
+        .boxed_local()
     }
 
     pub(self) fn set_after_logged_in_url(&self, req: &ServiceRequest) {
         let session = req.get_session();
         if let Ok(None) = session.get::<String>(AFTER_LOGGED_IN_URL_SESSION_KEY) {
-            let after_logged_in_url = url_for_request(&req);
+            let after_logged_in_url = url_for_request(req, &self.client.trusted_proxies);
             let result = session.set(AFTER_LOGGED_IN_URL_SESSION_KEY, after_logged_in_url);
             if let Err(err) = result {
                 error!(
@@ -319,7 +1353,7 @@ where
 
 impl<S, B> Service for ActixCasClientMiddleware<S>
 where
-    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
     S::Future: 'static,
     B: 'static,
 {
@@ -333,10 +1367,13 @@ where
     >;
 
     fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
-        self.service.poll_ready(cx)
+        self.service.borrow_mut().poll_ready(cx)
     }
 
     fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        if self.may_carry_posted_ticket(&req) {
+            return Either::Right(self.clone().call_with_posted_ticket(req).boxed_local());
+        }
         self.do_call(req)
     }
 }
@@ -345,6 +1382,8 @@ where
 mod cas_client_actix_test {
     use super::*;
     use actix_http::httpmessage::HttpMessage;
+    use cas_client_core::HttpTransport;
+    use cas_client_testing::{MockCasServer, MockUser};
     use actix_session::CookieSession;
     use actix_web::{
         // client::{Client, ClientResponse},
@@ -367,7 +1406,7 @@ mod cas_client_actix_test {
         cas_url: &str,
         behavior: NoAuthBehavior,
     ) -> ActixCasClient {
-        let mut cas_client = CasClient::new(&cas_url).unwrap();
+        let mut cas_client = CasClient::new(cas_url).unwrap();
         cas_client.set_no_auth_behavior(behavior);
         cas_client.set_login_service(auth_service);
         let mut a = ActixCasClient::new(cas_client, None, None);
@@ -386,7 +1425,7 @@ mod cas_client_actix_test {
         cas_client: web::Data<ActixCasClient>,
     ) -> Result<HttpResponse, Error> {
         let session = req.get_session();
-        let user_session = session.get::<CasUser>("cas_user");
+        let user_session = session.get::<CasUser>(CAS_USER_SESSION_KEY);
         let user = user_session.unwrap_or(None);
         let username = match user {
             Some(user) => user.username().to_owned(),
@@ -414,7 +1453,7 @@ mod cas_client_actix_test {
         //  println!("{:?}", bytes);
         // You'd do that, e.g. if you needed to get the
         // servers full URL.
-        let srv = start(|| {
+        start(|| {
             let cas_with_auth = get_cas_client("auth/cas", CAS_URL, NoAuthBehavior::Authenticate);
             let cas_with_403 =
                 get_cas_client("auth/cas", CAS_URL, NoAuthBehavior::AuthenticatedOr403);
@@ -466,8 +1505,7 @@ mod cas_client_actix_test {
                     web::scope(LOGOUT_PATH)
                         .service(urls::cas_logout)
                 )
-        });
-        srv
+        })
     }
 
     #[actix_rt::test]
@@ -513,9 +1551,8 @@ mod cas_client_actix_test {
             .clone()
             .into_iter()
             .find(|c| c.name() == SESSION_COOKIE_NAME);
-        if let None = cookie_1 {
-            let msg = ["Expected to find cookie with name ", SESSION_COOKIE_NAME].join(" ");
-            panic!(msg);
+        if cookie_1.is_none() {
+            panic!("Expected to find cookie with name {}", SESSION_COOKIE_NAME);
         }
     }
 
@@ -573,4 +1610,1413 @@ mod cas_client_actix_test {
         println!("{:?}", resp);
         assert_ne!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
     }
+
+    #[actix_rt::test]
+    async fn test_login_flow_with_mock_cas() {
+        let cas = MockCasServer::start();
+        let mut jdoe = MockUser::new("jdoe", "secret");
+        jdoe.add_attribute("mail", "jdoe@example.org");
+        cas.add_user(jdoe);
+        cas.login_as("jdoe");
+
+        let cas_url = cas.url();
+        let srv = start(move || {
+            let cas_with_auth = get_cas_client("auth/cas", &cas_url, NoAuthBehavior::Authenticate);
+            App::new()
+                .wrap(CookieSession::signed(&[0; 32]).secure(false).name(SESSION_COOKIE_NAME))
+                .data(cas_with_auth.clone())
+                .service(
+                    web::scope(USER_PATH)
+                        .wrap(cas_with_auth.clone())
+                        .route("", web::get().to(user)),
+                )
+        });
+
+        // Not logged in: redirected to CAS with the requested url as service
+        let resp = srv.get(USER_PATH).send().await.unwrap();
+        assert_eq!(resp.status(), StatusCode::TEMPORARY_REDIRECT);
+        let cookie = resp.cookies().unwrap()[0].clone();
+        let login_url = resp.headers().get("location").unwrap().to_str().unwrap();
+        assert!(login_url.starts_with(&format!("{}/login?service=", cas.url())));
+
+        // CAS sends the browser back with a ticket
+        let cas_resp = HttpTransport::new().get(login_url).unwrap();
+        assert_eq!(cas_resp.status, 302);
+        let service_url = cas_resp.header("Location").unwrap();
+        let path = service_url.trim_start_matches(srv.url("").trim_end_matches('/'));
+        assert!(path.starts_with(&format!("{}?{}=", USER_PATH, NONCE_PARAM)));
+        assert!(path.contains("&ticket=ST-"));
+
+        // The ticket is validated and the user is sent back to the requested url
+        let resp = srv.get(path).cookie(cookie).send().await.unwrap();
+        assert_eq!(resp.status(), StatusCode::TEMPORARY_REDIRECT);
+        assert_eq!(
+            resp.headers().get("location").unwrap().to_str().unwrap(),
+            srv.url(USER_PATH)
+        );
+        let cookie = resp.cookies().unwrap()[0].clone();
+
+        let mut resp = srv.get(USER_PATH).cookie(cookie).send().await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = resp.body().await.unwrap();
+        assert!(String::from_utf8_lossy(&body).contains("Welcome <b>jdoe</b>"));
+        assert_eq!(
+            cas.received_requests(),
+            vec!["GET /cas/login".to_string(), "GET /cas/serviceValidate".to_string()]
+        );
+    }
+
+    /// Nonce of the login started by `resp`
+    fn login_nonce<B>(resp: &ServiceResponse<B>, cas_client: &ActixCasClient) -> String {
+        let service = testing::cas_login_service(resp, cas_client).unwrap();
+        Url::parse(&service)
+            .unwrap()
+            .query_pairs()
+            .find(|(key, _)| key == NONCE_PARAM)
+            .map(|(_, value)| value.into_owned())
+            .unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn test_tickets_without_login_are_rejected() {
+        let cas = MockCasServer::start();
+        cas.add_user(MockUser::new("attacker", "secret"));
+        for mode in &[ReturnUrlMode::Session, ReturnUrlMode::StateCookie] {
+            let mut cas_client = get_cas_client("auth/cas", &cas.url(), NoAuthBehavior::Authenticate);
+            cas_client.set_return_url_mode(*mode);
+            let mut app = actix_web::test::init_service(
+                App::new()
+                    .wrap(CookieSession::signed(&[0; 32]).secure(false).name(SESSION_COOKIE_NAME))
+                    .data(cas_client.clone())
+                    .service(
+                        web::scope(USER_PATH)
+                            .wrap(cas_client.clone())
+                            .route("", web::get().to(user)),
+                    ),
+            )
+            .await;
+
+            // The attacker starts a login and sends the victim the url CAS sends back
+            let req = actix_web::test::TestRequest::get().uri(USER_PATH).to_request();
+            let resp = actix_web::test::call_service(&mut app, req).await;
+            let service = testing::cas_login_service(&resp, &cas_client).unwrap();
+            let ticket = cas.issue_service_ticket("attacker", &service);
+            let callback = service.trim_start_matches("http://localhost:8080");
+            let req = actix_web::test::TestRequest::get()
+                .uri(&format!("{}&ticket={}", callback, ticket))
+                .to_request();
+            let resp = actix_web::test::call_service(&mut app, req).await;
+
+            // The victim is sent to their own login instead
+            let new_service = testing::cas_login_service(&resp, &cas_client);
+            assert!(new_service.is_some(), "{:?}", mode);
+            assert_ne!(new_service.unwrap(), service);
+        }
+        assert!(!cas
+            .received_requests()
+            .contains(&"GET /cas/serviceValidate".to_string()));
+    }
+
+    #[actix_rt::test]
+    async fn test_error_pages() {
+        let cas = MockCasServer::start();
+        cas.add_user(MockUser::new("jdoe", "secret"));
+        let mut cas_client = get_cas_client("auth/cas", &cas.url(), NoAuthBehavior::Authenticate);
+        cas_client.set_login_csrf_protection(false).set_max_validation_failures(2);
+        let mut app = actix_web::test::init_service(
+            App::new()
+                .wrap(cas_client.clone())
+                .wrap(CookieSession::signed(&[0; 32]).secure(false).name(SESSION_COOKIE_NAME))
+                .default_service(web::route().to(ok)),
+        )
+        .await;
+        let service = format!("http://localhost:8080{}", USER_PATH);
+
+        // A misconfigured service is sent back to CAS once, then gets an error page
+        let mut statuses = Vec::new();
+        let mut cookie = None;
+        for _ in 0..2 {
+            let ticket = cas.issue_service_ticket("jdoe", &service);
+            cas.fail_next_validation("INVALID_SERVICE", "Service not registered");
+            let mut req = actix_web::test::TestRequest::get()
+                .uri(&format!("{}?ticket={}", USER_PATH, ticket));
+            if let Some(cookie) = cookie.take() {
+                req = req.cookie(cookie);
+            }
+            let resp = actix_web::test::call_service(&mut app, req.to_request()).await;
+            statuses.push(resp.status());
+            cookie = resp.response().cookies().next().map(|cookie| cookie.into_owned());
+            if resp.status() == StatusCode::FORBIDDEN {
+                let body = actix_web::test::read_body(resp).await;
+                let body = String::from_utf8_lossy(&body);
+                assert!(body.contains("<code>INVALID_SERVICE</code>"));
+                assert!(body.contains(&format!("<a href=\"{}\">Log in again</a>", service)));
+            }
+        }
+        assert_eq!(statuses, vec![StatusCode::TEMPORARY_REDIRECT, StatusCode::FORBIDDEN]);
+
+        let ticket = cas.issue_service_ticket("jdoe", &service);
+        cas.set_unavailable(true);
+        let req = actix_web::test::TestRequest::get()
+            .uri(&format!("{}?ticket={}", USER_PATH, ticket))
+            .to_request();
+        let resp = actix_web::test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        // Templates can be plugged in
+        let mut cas_client = get_cas_client("auth/cas", &cas.url(), NoAuthBehavior::AuthenticatedOr404);
+        cas_client.set_error_page_renderer(|page: &ErrorPage| {
+            HttpResponse::build(page.status()).body(format!("branded {}", page.status().as_u16()))
+        });
+        let mut app = actix_web::test::init_service(
+            App::new()
+                .wrap(cas_client)
+                .default_service(web::route().to(ok)),
+        )
+        .await;
+        let req = actix_web::test::TestRequest::get().uri(USER_PATH).to_request();
+        let resp = actix_web::test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        assert_eq!(actix_web::test::read_body(resp).await, "branded 404");
+    }
+
+    #[actix_rt::test]
+    async fn test_spoofed_after_logged_in_url_is_rejected() {
+        let cas = MockCasServer::start();
+        cas.add_user(MockUser::new("jdoe", "secret"));
+        let cas_client = get_cas_client("auth/cas", &cas.url(), NoAuthBehavior::Authenticate);
+        let mut app = actix_web::test::init_service(
+            App::new()
+                .wrap(CookieSession::signed(&[0; 32]).secure(false).name(SESSION_COOKIE_NAME))
+                .data(cas_client.clone())
+                .service(
+                    web::scope(USER_PATH)
+                        .wrap(cas_client.clone())
+                        .route("", web::get().to(user)),
+                ),
+        )
+        .await;
+
+        // The url stored in the session is built from a forged host
+        let req = actix_web::test::TestRequest::get()
+            .uri(USER_PATH)
+            .header("X-Forwarded-Host", "evil.example.org")
+            .to_request();
+        let resp = actix_web::test::call_service(&mut app, req).await;
+        let nonce = login_nonce(&resp, &cas_client);
+        let cookie = resp.response().cookies().next().unwrap().into_owned();
+
+        // After login, the user is sent back to the page CAS returned to instead
+        let service = format!("http://localhost:8080/user?{}={}", NONCE_PARAM, nonce);
+        let ticket = cas.issue_service_ticket("jdoe", &service);
+        let req = actix_web::test::TestRequest::get()
+            .uri(&format!("{}?{}={}&ticket={}", USER_PATH, NONCE_PARAM, nonce, ticket))
+            .cookie(cookie)
+            .to_request();
+        let resp = actix_web::test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::TEMPORARY_REDIRECT);
+        assert_eq!(
+            resp.headers().get(http::header::LOCATION).unwrap(),
+            "http://localhost:8080/user"
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_cas_login_rejects_external_default_path() {
+        let mut cas_client = get_cas_client("auth/cas", CAS_URL, NoAuthBehavior::Authenticate);
+        cas_client.set_default_after_logged_in_path(Some(String::from("//evil.example.org/")));
+        let mut app = actix_web::test::init_service(
+            App::new()
+                .app_data(cas_client)
+                .service(web::scope(LOGIN_PATH).service(urls::cas_login)),
+        )
+        .await;
+        let req = actix_web::test::TestRequest::get()
+            .uri(&format!("{}/", LOGIN_PATH))
+            .to_request();
+        let resp = actix_web::test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::TEMPORARY_REDIRECT);
+        assert_eq!(resp.headers().get(http::header::LOCATION).unwrap(), "/");
+    }
+
+    #[actix_rt::test]
+    async fn test_concurrent_logins_return_to_their_own_url() {
+        let cas = MockCasServer::start();
+        cas.add_user(MockUser::new("jdoe", "secret"));
+        for mode in &[ReturnUrlMode::SignedServiceUrl, ReturnUrlMode::StateCookie] {
+            let mut cas_client = get_cas_client("auth/cas", &cas.url(), NoAuthBehavior::Authenticate);
+            cas_client.set_return_url_mode(*mode).set_return_url_key(b"secret");
+            let mut app = actix_web::test::init_service(
+                App::new()
+                    .wrap(CookieSession::signed(&[0; 32]).secure(false).name(SESSION_COOKIE_NAME))
+                    .data(cas_client.clone())
+                    .service(
+                        web::scope(USER_PATH)
+                            .wrap(cas_client.clone())
+                            .route("", web::get().to(user)),
+                    ),
+            )
+            .await;
+
+            // Two tabs start a login before any of them comes back from CAS
+            let mut logins = Vec::new();
+            for tab in 1..=2 {
+                let req = actix_web::test::TestRequest::get()
+                    .uri(&format!("{}?tab={}", USER_PATH, tab))
+                    .to_request();
+                let resp = actix_web::test::call_service(&mut app, req).await;
+                let service = testing::cas_login_service(&resp, &cas_client).unwrap();
+                let state_cookie = resp
+                    .response()
+                    .cookies()
+                    .find(|cookie| cookie.name().starts_with(STATE_COOKIE_PREFIX))
+                    .map(|cookie| cookie.into_owned());
+                let session_cookie = resp
+                    .response()
+                    .cookies()
+                    .find(|cookie| cookie.name() == SESSION_COOKIE_NAME)
+                    .map(|cookie| cookie.into_owned());
+                match mode {
+                    ReturnUrlMode::StateCookie => {
+                        let state_cookie = state_cookie.as_ref().unwrap();
+                        assert_eq!(state_cookie.same_site(), Some(SameSite::Lax));
+                        assert!(service.contains(STATE_PARAM));
+                    }
+                    _ => {
+                        assert!(state_cookie.is_none());
+                        assert!(service.contains(RETURN_URL_PARAM));
+                    }
+                };
+                logins.push((tab, service, state_cookie.or(session_cookie)));
+            }
+
+            for (tab, service, cookie) in logins.into_iter().rev() {
+                let ticket = cas.issue_service_ticket("jdoe", &service);
+                let callback = service.trim_start_matches("http://localhost:8080");
+                let req = actix_web::test::TestRequest::get()
+                    .uri(&format!("{}&ticket={}", callback, ticket))
+                    .cookie(cookie.unwrap());
+                let resp = actix_web::test::call_service(&mut app, req.to_request()).await;
+                assert_eq!(resp.status(), StatusCode::TEMPORARY_REDIRECT, "{:?}", mode);
+                assert_eq!(
+                    resp.headers().get(http::header::LOCATION).unwrap(),
+                    &format!("http://localhost:8080/user?tab={}", tab)
+                );
+            }
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_forged_return_url_is_rejected() {
+        let cas = MockCasServer::start();
+        cas.add_user(MockUser::new("jdoe", "secret"));
+        let mut cas_client = get_cas_client("auth/cas", &cas.url(), NoAuthBehavior::Authenticate);
+        cas_client.set_return_url_mode(ReturnUrlMode::SignedServiceUrl);
+        let mut app = actix_web::test::init_service(
+            App::new()
+                .wrap(CookieSession::signed(&[0; 32]).secure(false).name(SESSION_COOKIE_NAME))
+                .data(cas_client.clone())
+                .service(
+                    web::scope(USER_PATH)
+                        .wrap(cas_client.clone())
+                        .route("", web::get().to(user)),
+                ),
+        )
+        .await;
+
+        let req = actix_web::test::TestRequest::get().uri(USER_PATH).to_request();
+        let resp = actix_web::test::call_service(&mut app, req).await;
+        let nonce = login_nonce(&resp, &cas_client);
+        let cookie = resp.response().cookies().next().unwrap().into_owned();
+
+        let forged = SigningKey::new(b"forged").sign("http://localhost:8080/admin", u64::MAX);
+        let query = format!("{}={}&{}={}", RETURN_URL_PARAM, forged, NONCE_PARAM, nonce);
+        let ticket = cas.issue_service_ticket("jdoe", &format!("http://localhost:8080/user?{}", query));
+        let req = actix_web::test::TestRequest::get()
+            .uri(&format!("/user?{}&ticket={}", query, ticket))
+            .cookie(cookie)
+            .to_request();
+        let resp = actix_web::test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::TEMPORARY_REDIRECT);
+        assert_eq!(
+            resp.headers().get(http::header::LOCATION).unwrap(),
+            "http://localhost:8080/user"
+        );
+    }
+
+    async fn seed_keys(req: HttpRequest) -> HttpResponse {
+        let session = req.get_session();
+        session.set("cart", "book").unwrap();
+        session.set("tracking", "pre-login").unwrap();
+        HttpResponse::Ok().finish()
+    }
+
+    async fn show_keys(req: HttpRequest) -> HttpResponse {
+        let session = req.get_session();
+        HttpResponse::Ok().body(format!(
+            "{:?} {:?}",
+            session.get::<String>("cart").unwrap(),
+            session.get::<String>("tracking").unwrap()
+        ))
+    }
+
+    #[actix_rt::test]
+    async fn test_session_is_renewed_on_login() {
+        let cas = MockCasServer::start();
+        cas.add_user(MockUser::new("jdoe", "secret"));
+        for (renew, expected_keys) in &[
+            (true, r#"Some("book") None"#),
+            (false, r#"Some("book") Some("pre-login")"#),
+        ] {
+            let mut cas_client = get_cas_client("auth/cas", &cas.url(), NoAuthBehavior::Authenticate);
+            cas_client
+                .set_renew_session_on_login(*renew)
+                .set_session_keys_kept_on_login(vec!["cart".to_string()]);
+            let mut app = actix_web::test::init_service(
+                App::new()
+                    .wrap(CookieSession::signed(&[0; 32]).secure(false).name(SESSION_COOKIE_NAME))
+                    .route("/seed", web::get().to(seed_keys))
+                    .service(
+                        web::scope(USER_PATH)
+                            .wrap(cas_client.clone())
+                            .route("", web::get().to(show_keys)),
+                    ),
+            )
+            .await;
+
+            let req = actix_web::test::TestRequest::get().uri("/seed").to_request();
+            let resp = actix_web::test::call_service(&mut app, req).await;
+            let cookie = resp.response().cookies().next().unwrap().into_owned();
+            let req = actix_web::test::TestRequest::get().uri(USER_PATH).cookie(cookie).to_request();
+            let resp = actix_web::test::call_service(&mut app, req).await;
+            let service = testing::cas_login_service(&resp, &cas_client).unwrap();
+            let cookie = resp.response().cookies().next().unwrap().into_owned();
+
+            let ticket = cas.issue_service_ticket("jdoe", &service);
+            let callback = service.trim_start_matches("http://localhost:8080");
+            let req = actix_web::test::TestRequest::get()
+                .uri(&format!("{}&ticket={}", callback, ticket))
+                .cookie(cookie)
+                .to_request();
+            let resp = actix_web::test::call_service(&mut app, req).await;
+            assert_eq!(resp.status(), StatusCode::TEMPORARY_REDIRECT);
+            let cookie = resp.response().cookies().next().unwrap().into_owned();
+
+            let req = actix_web::test::TestRequest::get().uri(USER_PATH).cookie(cookie).to_request();
+            let body = actix_web::test::read_response(&mut app, req).await;
+            assert_eq!(body, expected_keys.as_bytes(), "renew: {}", renew);
+        }
+    }
+
+    /// Server side session store identifying its sessions with the `sid` cookie
+    #[derive(Clone, Default)]
+    struct IdSessionStore {
+        sessions: Rc<RefCell<HashMap<String, HashMap<String, String>>>>,
+        last_id: Rc<RefCell<u32>>,
+    }
+
+    impl IdSessionStore {
+        fn contains(&self, id: &str) -> bool {
+            self.sessions.borrow().contains_key(id)
+        }
+
+        fn new_id(&self) -> String {
+            *self.last_id.borrow_mut() += 1;
+            format!("sid-{}", self.last_id.borrow())
+        }
+
+        fn call<S>(&self, mut req: ServiceRequest, srv: &mut S) -> LocalBoxFuture<'static, Result<ServiceResponse, Error>>
+        where
+            S: Service<Request = ServiceRequest, Response = ServiceResponse, Error = Error>,
+            S::Future: 'static,
+        {
+            let id = req.cookie("sid").map(|c| c.value().to_string()).filter(|id| self.contains(id));
+            if let Some(state) = id.as_ref().and_then(|id| self.sessions.borrow().get(id).cloned()) {
+                Session::set_session(state.into_iter(), &mut req);
+            }
+            let store = self.clone();
+            let fut = srv.call(req);
+            async move {
+                let mut res = fut.await?;
+                let (status, state) = Session::get_changes(&mut res);
+                let state: HashMap<String, String> = state.map(Iterator::collect).unwrap_or_default();
+                let new_id = match (status, id) {
+                    (actix_session::SessionStatus::Unchanged, _) => None,
+                    (actix_session::SessionStatus::Changed, Some(id)) => {
+                        store.sessions.borrow_mut().insert(id, state);
+                        None
+                    }
+                    (actix_session::SessionStatus::Purged, id) => {
+                        id.map(|id| store.sessions.borrow_mut().remove(&id));
+                        None
+                    }
+                    (_, id) => {
+                        id.map(|id| store.sessions.borrow_mut().remove(&id));
+                        let new_id = store.new_id();
+                        store.sessions.borrow_mut().insert(new_id.clone(), state);
+                        Some(new_id)
+                    }
+                };
+                if let Some(new_id) = new_id {
+                    res.response_mut().add_cookie(&Cookie::new("sid", new_id)).unwrap();
+                }
+                Ok(res)
+            }
+            .boxed_local()
+        }
+    }
+
+    fn sid_cookie<B>(resp: &ServiceResponse<B>) -> Option<Cookie<'static>> {
+        resp.response().cookies().find(|c| c.name() == "sid").map(Cookie::into_owned)
+    }
+
+    #[actix_rt::test]
+    async fn test_session_id_is_rotated_on_login() {
+        let cas = MockCasServer::start();
+        cas.add_user(MockUser::new("jdoe", "secret"));
+        for renew in &[true, false] {
+            let store = IdSessionStore::default();
+            let mut cas_client = get_cas_client("auth/cas", &cas.url(), NoAuthBehavior::Authenticate);
+            cas_client
+                .set_renew_session_on_login(*renew)
+                .set_session_keys_kept_on_login(vec!["cart".to_string()]);
+            let session_store = store.clone();
+            let mut app = actix_web::test::init_service(
+                App::new()
+                    .wrap_fn(move |req, srv| session_store.call(req, srv))
+                    .route("/seed", web::get().to(seed_keys))
+                    .service(
+                        web::scope(USER_PATH)
+                            .wrap(cas_client.clone())
+                            .route("", web::get().to(show_keys)),
+                    ),
+            )
+            .await;
+
+            let req = actix_web::test::TestRequest::get().uri("/seed").to_request();
+            let resp = actix_web::test::call_service(&mut app, req).await;
+            let pre_login = sid_cookie(&resp).unwrap();
+            let req = actix_web::test::TestRequest::get()
+                .uri(USER_PATH)
+                .cookie(pre_login.clone())
+                .to_request();
+            let resp = actix_web::test::call_service(&mut app, req).await;
+            let service = testing::cas_login_service(&resp, &cas_client).unwrap();
+            assert!(sid_cookie(&resp).is_none());
+
+            let ticket = cas.issue_service_ticket("jdoe", &service);
+            let callback = service.trim_start_matches("http://localhost:8080");
+            let req = actix_web::test::TestRequest::get()
+                .uri(&format!("{}&ticket={}", callback, ticket))
+                .cookie(pre_login.clone())
+                .to_request();
+            let resp = actix_web::test::call_service(&mut app, req).await;
+            assert_eq!(resp.status(), StatusCode::TEMPORARY_REDIRECT);
+
+            let logged_in = match renew {
+                true => {
+                    let logged_in = sid_cookie(&resp).unwrap();
+                    assert_ne!(logged_in.value(), pre_login.value());
+                    assert!(!store.contains(pre_login.value()));
+                    logged_in
+                }
+                false => {
+                    assert!(sid_cookie(&resp).is_none());
+                    pre_login.clone()
+                }
+            };
+            let req = actix_web::test::TestRequest::get().uri(USER_PATH).cookie(logged_in).to_request();
+            let resp = actix_web::test::call_service(&mut app, req).await;
+            assert_eq!(resp.status(), StatusCode::OK, "renew: {}", renew);
+
+            // The pre-login session id no longer leads to the logged in session
+            let req = actix_web::test::TestRequest::get().uri(USER_PATH).cookie(pre_login).to_request();
+            let resp = actix_web::test::call_service(&mut app, req).await;
+            let expected_status = match renew {
+                true => StatusCode::TEMPORARY_REDIRECT,
+                false => StatusCode::OK,
+            };
+            assert_eq!(resp.status(), expected_status, "renew: {}", renew);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_repeated_ticket_reuses_validation() {
+        let cas = MockCasServer::start();
+        cas.add_user(MockUser::new("jdoe", "secret"));
+        let cas_client = get_cas_client("auth/cas", &cas.url(), NoAuthBehavior::Authenticate);
+        let mut app = actix_web::test::init_service(
+            App::new()
+                .wrap(CookieSession::signed(&[0; 32]).secure(false).name(SESSION_COOKIE_NAME))
+                .data(cas_client.clone())
+                .service(
+                    web::scope(USER_PATH)
+                        .wrap(cas_client.clone())
+                        .route("", web::get().to(user)),
+                ),
+        )
+        .await;
+
+        let req = actix_web::test::TestRequest::get().uri(USER_PATH).to_request();
+        let resp = actix_web::test::call_service(&mut app, req).await;
+        let service = testing::cas_login_service(&resp, &cas_client).unwrap();
+        let cookie = resp.response().cookies().next().unwrap().into_owned();
+        let ticket = cas.issue_service_ticket("jdoe", &service);
+        let callback = service.trim_start_matches("http://localhost:8080");
+
+        // e.g. a prefetch then the navigation, both sent before the first response
+        for _ in 0..2 {
+            let req = actix_web::test::TestRequest::get()
+                .uri(&format!("{}&ticket={}", callback, ticket))
+                .cookie(cookie.clone())
+                .to_request();
+            let resp = actix_web::test::call_service(&mut app, req).await;
+            assert_eq!(resp.status(), StatusCode::TEMPORARY_REDIRECT);
+            assert_eq!(
+                resp.headers().get(http::header::LOCATION).unwrap(),
+                &format!("http://localhost:8080{}", USER_PATH)
+            );
+        }
+        let validations = cas
+            .received_requests()
+            .iter()
+            .filter(|request| *request == "GET /cas/serviceValidate")
+            .count();
+        assert_eq!(validations, 1);
+
+        // Not reused by another session
+        let req = actix_web::test::TestRequest::get()
+            .uri(&format!("{}&ticket={}", callback, ticket))
+            .to_request();
+        let resp = actix_web::test::call_service(&mut app, req).await;
+        assert!(testing::cas_login_service(&resp, &cas_client).is_some());
+    }
+
+    async fn extension_username(req: HttpRequest, user: AuthenticatedCasUser) -> HttpResponse {
+        let from_extensions = req.extensions().get::<CasUser>().cloned();
+        assert_eq!(from_extensions.as_ref(), Some(&*user));
+        HttpResponse::Ok().body(user.username().to_string())
+    }
+
+    #[actix_rt::test]
+    async fn test_middleware_places_user_in_extensions() {
+        let cas_client = get_cas_client("auth/cas", CAS_URL, NoAuthBehavior::AuthenticatedOr403);
+        let mut app = actix_web::test::init_service(
+            App::new()
+                .wrap(cas_client)
+                .wrap(testing::WithCasUser::new(testing::cas_user("jdoe", &[])))
+                .route(USER_PATH, web::get().to(extension_username)),
+        )
+        .await;
+        let req = actix_web::test::TestRequest::get().uri(USER_PATH).to_request();
+        let body = actix_web::test::read_response(&mut app, req).await;
+        assert_eq!(body, "jdoe");
+    }
+
+    async fn ok() -> HttpResponse {
+        HttpResponse::Ok().finish()
+    }
+
+    #[actix_rt::test]
+    async fn test_route_rules() {
+        let mut cas_client = get_cas_client("auth/cas", CAS_URL, NoAuthBehavior::Authenticate);
+        let mut post_api = RouteRule::glob(
+            "/api/**",
+            RouteAccess::Authenticated(NoAuthBehavior::AuthenticatedOr403),
+        );
+        post_api.set_method(http::Method::POST);
+        cas_client
+            .add_route_rule(RouteRule::public("/health"))
+            .add_route_rule(RouteRule::public("/static/**"))
+            .add_route_rule(post_api)
+            .add_route_rule(RouteRule::public("/api/**"))
+            .add_route_rule(RouteRule::glob(
+                "/admin/**",
+                RouteAccess::Attribute {
+                    behavior: NoAuthBehavior::AuthenticatedOr404,
+                    name: "role".to_string(),
+                    values: vec!["admin".to_string()],
+                },
+            ));
+        let app = || {
+            App::new()
+                .wrap(cas_client.clone())
+                .default_service(web::route().to(ok))
+        };
+
+        let mut guest_app = actix_web::test::init_service(app()).await;
+        for (method, path, expected_status) in &[
+            (http::Method::GET, "/health", StatusCode::OK),
+            (http::Method::GET, "/static/app.css", StatusCode::OK),
+            (http::Method::GET, "/api/items", StatusCode::OK),
+            (http::Method::POST, "/api/items", StatusCode::FORBIDDEN),
+            (http::Method::GET, "/admin", StatusCode::NOT_FOUND),
+            (http::Method::GET, "/user", StatusCode::TEMPORARY_REDIRECT),
+        ] {
+            let req = actix_web::test::TestRequest::with_uri(path)
+                .method(method.clone())
+                .to_request();
+            let resp = actix_web::test::call_service(&mut guest_app, req).await;
+            assert_eq!(resp.status(), *expected_status, "guest {} {}", method, path);
+        }
+
+        for (user, expected_status) in &[
+            (testing::cas_user("jdoe", &[("role", "user")]), StatusCode::FORBIDDEN),
+            (testing::cas_user("root", &[("role", "admin")]), StatusCode::OK),
+        ] {
+            let mut user_app = actix_web::test::init_service(
+                app().wrap(testing::WithCasUser::new(user.clone())),
+            )
+            .await;
+            let req = actix_web::test::TestRequest::get().uri("/admin/users").to_request();
+            let resp = actix_web::test::call_service(&mut user_app, req).await;
+            assert_eq!(resp.status(), *expected_status, "{}", user.username());
+            let req = actix_web::test::TestRequest::post().uri("/api/items").to_request();
+            let resp = actix_web::test::call_service(&mut user_app, req).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_login_options() {
+        let mut cas_client = get_cas_client("auth/cas", CAS_URL, NoAuthBehavior::Authenticate);
+        let mut sensitive = LoginOptions::new();
+        sensitive.set_renew(true).set_authn_method(Some("mfa-duo".to_string()));
+        let mut sensitive_rule = RouteRule::glob(
+            "/settings/**",
+            RouteAccess::Authenticated(NoAuthBehavior::Authenticate),
+        );
+        sensitive_rule.set_login_options(sensitive);
+        let mut gateway = LoginOptions::new();
+        gateway.set_gateway(true).add_param("tenant", "acme");
+        let mut gateway_rule = RouteRule::glob("/", RouteAccess::Authenticated(NoAuthBehavior::Authenticate));
+        gateway_rule.set_login_options(gateway);
+        cas_client
+            .add_route_rule(sensitive_rule)
+            .add_route_rule(gateway_rule)
+            .set_locale_from_accept_language(true);
+        let mut app = actix_web::test::init_service(
+            App::new()
+                .wrap(cas_client)
+                .wrap(CookieSession::signed(&[0; 32]).secure(false).name(SESSION_COOKIE_NAME))
+                .default_service(web::route().to(ok)),
+        )
+        .await;
+        let location = |resp: &ServiceResponse| {
+            let location = resp.headers().get("location").unwrap().to_str().unwrap();
+            Url::parse(location).unwrap().query_pairs().into_owned().collect::<Vec<_>>()
+        };
+        let param = |name: &str, value: &str| (name.to_string(), value.to_string());
+
+        let req = actix_web::test::TestRequest::get()
+            .uri("/settings/password")
+            .header("Accept-Language", "de;q=0.5, fr-CA, *;q=0.1")
+            .to_request();
+        let resp = actix_web::test::call_service(&mut app, req).await;
+        let params = location(&resp);
+        assert!(params.contains(&param("renew", "true")));
+        assert!(params.contains(&param("authn_method", "mfa-duo")));
+        assert!(params.contains(&param("locale", "fr_CA")));
+
+        // Users without single sign-on session come back as guests
+        let req = actix_web::test::TestRequest::get().uri("/").to_request();
+        let resp = actix_web::test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::TEMPORARY_REDIRECT);
+        let params = location(&resp);
+        assert!(params.contains(&param("gateway", "true")));
+        assert!(params.contains(&param("tenant", "acme")));
+        assert!(!params.iter().any(|(name, _)| name == "locale"));
+        let cookie = resp.response().cookies().next().unwrap().into_owned();
+        let req = actix_web::test::TestRequest::get().uri("/").cookie(cookie).to_request();
+        let resp = actix_web::test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    async fn test_renew_is_enforced_at_validation() {
+        let cas = MockCasServer::start();
+        cas.add_user(MockUser::new("jdoe", "secret"));
+        cas.login_as("jdoe");
+        let mut cas_client = get_cas_client("auth/cas", &cas.url(), NoAuthBehavior::Authenticate);
+        let mut sensitive = LoginOptions::new();
+        sensitive.set_renew(true);
+        let mut sensitive_rule = RouteRule::glob(
+            "/settings/**",
+            RouteAccess::Authenticated(NoAuthBehavior::Authenticate),
+        );
+        sensitive_rule.set_login_options(sensitive);
+        cas_client.add_route_rule(sensitive_rule);
+        let mut app = actix_web::test::init_service(
+            App::new()
+                .wrap(cas_client)
+                .wrap(CookieSession::signed(&[0; 32]).secure(false).name(SESSION_COOKIE_NAME))
+                .default_service(web::route().to(ok)),
+        )
+        .await;
+        let transport = HttpTransport::new();
+        let callback = |cas_resp: cas_client_core::HttpResponse, cookie: Cookie<'static>| {
+            let location = cas_resp.header("Location").unwrap();
+            let uri = location.trim_start_matches("http://localhost:8080").to_string();
+            actix_web::test::TestRequest::get().uri(&uri).cookie(cookie).to_request()
+        };
+
+        let req = actix_web::test::TestRequest::get().uri("/settings/password").to_request();
+        let resp = actix_web::test::call_service(&mut app, req).await;
+        let cookie = resp.response().cookies().next().unwrap().into_owned();
+        let login_url = resp.headers().get("location").unwrap().to_str().unwrap();
+        assert!(login_url.ends_with("&renew=true"));
+
+        // Dropping `renew` from the login url reuses the single sign-on session
+        let cas_resp = transport.get(login_url.trim_end_matches("&renew=true")).unwrap();
+        let resp = actix_web::test::call_service(&mut app, callback(cas_resp, cookie)).await;
+        assert_eq!(resp.status(), StatusCode::TEMPORARY_REDIRECT);
+        let cookie = resp.response().cookies().next().unwrap().into_owned();
+        let login_url = resp.headers().get("location").unwrap().to_str().unwrap();
+        assert!(login_url.starts_with(&cas.url()));
+
+        // Entering the credentials again logs the user in
+        let service = Url::parse(login_url)
+            .unwrap()
+            .query_pairs()
+            .find(|(name, _)| name == "service")
+            .map(|(_, service)| service.into_owned())
+            .unwrap();
+        let credentials = [("username", "jdoe"), ("password", "secret"), ("service", service.as_str())];
+        let cas_resp = transport.post_form(&format!("{}/login", cas.url()), &credentials).unwrap();
+        let resp = actix_web::test::call_service(&mut app, callback(cas_resp, cookie)).await;
+        assert_eq!(resp.status(), StatusCode::TEMPORARY_REDIRECT);
+        assert_eq!(
+            resp.headers().get("location").unwrap().to_str().unwrap(),
+            "http://localhost:8080/settings/password"
+        );
+    }
+
+    async fn echo(body: String) -> HttpResponse {
+        HttpResponse::Ok().body(body)
+    }
+
+    #[actix_rt::test]
+    async fn test_posted_ticket() {
+        let cas = MockCasServer::start();
+        cas.add_user(MockUser::new("jdoe", "secret"));
+        cas.login_as("jdoe");
+        let mut cas_client = get_cas_client("auth/cas", &cas.url(), NoAuthBehavior::Authenticate);
+        let mut login_options = LoginOptions::new();
+        login_options.set_post_response(true);
+        cas_client.set_login_options(login_options);
+        let mut app = actix_web::test::init_service(
+            App::new()
+                .wrap(cas_client)
+                .wrap(CookieSession::signed(&[0; 32]).secure(false).name(SESSION_COOKIE_NAME))
+                .route("/echo", web::post().to(echo))
+                .default_service(web::route().to(ok)),
+        )
+        .await;
+        let form = |uri: &str, body: String, cookie: Cookie<'static>| {
+            actix_web::test::TestRequest::post()
+                .uri(uri)
+                .header("Content-Type", "application/x-www-form-urlencoded")
+                .cookie(cookie)
+                .set_payload(body)
+                .to_request()
+        };
+
+        let req = actix_web::test::TestRequest::get().uri(USER_PATH).to_request();
+        let resp = actix_web::test::call_service(&mut app, req).await;
+        let cookie = resp.response().cookies().next().unwrap().into_owned();
+        let login_url = resp.headers().get("location").unwrap().to_str().unwrap();
+        assert!(login_url.contains("&method=POST"));
+
+        // CAS posts the ticket to the service url
+        let cas_resp = HttpTransport::new().get(login_url).unwrap();
+        let action_start = cas_resp.body.find("action=\"").unwrap() + 8;
+        let action = &cas_resp.body[action_start..];
+        let action = action[..action.find('"').unwrap()].replace("&amp;", "&");
+        let ticket_start = cas_resp.body.find("ST-").unwrap();
+        let ticket = &cas_resp.body[ticket_start..];
+        let ticket = &ticket[..ticket.find('"').unwrap()];
+        let uri = action.trim_start_matches("http://localhost:8080");
+        let req = form(uri, format!("ticket={}", ticket), cookie);
+        let resp = actix_web::test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+        assert_eq!(
+            resp.headers().get("location").unwrap().to_str().unwrap(),
+            format!("http://localhost:8080{}", USER_PATH)
+        );
+        let cookie = resp.response().cookies().next().unwrap().into_owned();
+
+        // Other forms reach their handler intact
+        let req = form("/echo", "comment=ticket%3F".to_string(), cookie);
+        assert_eq!(actix_web::test::read_response(&mut app, req).await, "comment=ticket%3F");
+    }
+
+    #[actix_rt::test]
+    async fn test_forms_without_login_params_carry_no_ticket() {
+        let cas = MockCasServer::start();
+        cas.add_user(MockUser::new("jdoe", "secret"));
+        let mut cas_client = get_cas_client("auth/cas", &cas.url(), NoAuthBehavior::Authenticate);
+        let mut login_options = LoginOptions::new();
+        login_options.set_post_response(true);
+        cas_client.set_login_options(login_options).set_login_csrf_protection(false);
+        let mut app = actix_web::test::init_service(
+            App::new()
+                .wrap(cas_client)
+                .wrap(CookieSession::signed(&[0; 32]).secure(false).name(SESSION_COOKIE_NAME))
+                .route("/echo", web::post().to(echo)),
+        )
+        .await;
+
+        // A form of the application with a `ticket` field is no CAS callback
+        let ticket = cas.issue_service_ticket("jdoe", "http://localhost:8080/echo");
+        let req = actix_web::test::TestRequest::post()
+            .uri("/echo")
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .set_payload(format!("ticket={}", ticket))
+            .to_request();
+        let resp = actix_web::test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::TEMPORARY_REDIRECT);
+        let location = resp.headers().get(http::header::LOCATION).unwrap().to_str().unwrap();
+        assert!(location.starts_with(&format!("{}/login?", cas.url())), "{}", location);
+        assert!(cas.received_requests().is_empty());
+    }
+
+    #[test]
+    fn preferred_locale_should_follow_weights() {
+        assert_eq!(preferred_locale("fr-CH, fr;q=0.9, en;q=0.8"), Some("fr_CH".to_string()));
+        assert_eq!(preferred_locale("en;q=0.5, de"), Some("de".to_string()));
+        assert_eq!(preferred_locale("*, en;q=0"), None);
+        assert_eq!(preferred_locale(""), None);
+    }
+
+    #[actix_rt::test]
+    async fn test_api_requests_get_401() {
+        let mut cas_client = get_cas_client("auth/cas", CAS_URL, NoAuthBehavior::Authenticate);
+        let mut browser_only = RouteRule::glob(
+            "/legacy/**",
+            RouteAccess::Authenticated(NoAuthBehavior::Authenticate),
+        );
+        browser_only.set_api_detection(ApiDetection::Never);
+        cas_client.add_route_rule(browser_only);
+        let mut app = actix_web::test::init_service(
+            App::new()
+                .wrap(cas_client)
+                .default_service(web::route().to(ok)),
+        )
+        .await;
+
+        let req = actix_web::test::TestRequest::get()
+            .uri("/api/items")
+            .header("Accept", "application/json")
+            .to_request();
+        let resp = actix_web::test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let www_authenticate = resp.headers().get(http::header::WWW_AUTHENTICATE).unwrap();
+        assert!(www_authenticate.to_str().unwrap().starts_with("CAS "));
+        let body: serde_json::Value = actix_web::test::read_body_json(resp).await;
+        assert_eq!(body["error"], "unauthenticated");
+        let login_url = body["login_url"].as_str().unwrap();
+        let service_prefix = format!(
+            "{}/login?service=http%3A%2F%2Flocalhost%3A8080%2Fapi%2Fitems%3F{}%3D",
+            CAS_URL, NONCE_PARAM
+        );
+        assert!(login_url.starts_with(&service_prefix), "{}", login_url);
+
+        let req = actix_web::test::TestRequest::get()
+            .uri("/legacy/items")
+            .header("Accept", "application/json")
+            .to_request();
+        let resp = actix_web::test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::TEMPORARY_REDIRECT);
+    }
+
+    #[actix_rt::test]
+    async fn test_api_login_url_accepts_its_ticket() {
+        let cas = MockCasServer::start();
+        cas.add_user(MockUser::new("jdoe", "secret"));
+        let cas_client = get_cas_client("auth/cas", &cas.url(), NoAuthBehavior::Authenticate);
+        let mut app = actix_web::test::init_service(
+            App::new()
+                .wrap(CookieSession::signed(&[0; 32]).secure(false).name(SESSION_COOKIE_NAME))
+                .data(cas_client.clone())
+                .service(web::scope(USER_PATH).wrap(cas_client).route("", web::get().to(user))),
+        )
+        .await;
+
+        let req = actix_web::test::TestRequest::get()
+            .uri(USER_PATH)
+            .header("Accept", "application/json")
+            .to_request();
+        let resp = actix_web::test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let cookie = resp.response().cookies().next().unwrap().into_owned();
+        let body: serde_json::Value = actix_web::test::read_body_json(resp).await;
+        let login_url = url::Url::parse(body["login_url"].as_str().unwrap()).unwrap();
+        let (_, service) = login_url.query_pairs().find(|(name, _)| name == "service").unwrap();
+
+        // The browser navigates to the login url with the session of the API client
+        let ticket = cas.issue_service_ticket("jdoe", &service);
+        let callback = service.trim_start_matches("http://localhost:8080");
+        let req = actix_web::test::TestRequest::get()
+            .uri(&format!("{}&ticket={}", callback, ticket))
+            .cookie(cookie)
+            .to_request();
+        let resp = actix_web::test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::TEMPORARY_REDIRECT);
+        assert!(cas.received_requests().iter().any(|request| request.contains("serviceValidate")));
+        let cookie = resp.response().cookies().next().unwrap().into_owned();
+        let req = actix_web::test::TestRequest::get().uri(USER_PATH).cookie(cookie).to_request();
+        let resp = actix_web::test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    struct ApiKeyOrBrandedPage;
+
+    #[async_trait::async_trait(?Send)]
+    impl NoAuthHandler for ApiKeyOrBrandedPage {
+        async fn handle(&self, request: &NoAuthRequest, session: &dyn SessionState) -> NoAuthOutcome {
+            let tenant = session.get("tenant");
+            match (request.header("X-Api-Key"), request.url().contains("/old/"), tenant) {
+                (Some("secret"), _, _) => NoAuthOutcome::Continue,
+                _ if request.session_expired() => NoAuthOutcome::Respond {
+                    status: 401,
+                    headers: vec![],
+                    body: "Your session expired".to_string(),
+                },
+                (_, true, _) => NoAuthOutcome::Redirect("/new".to_string()),
+                (_, _, Some(tenant)) => NoAuthOutcome::Redirect(format!("/{}/login", tenant.as_str().unwrap())),
+                _ => NoAuthOutcome::Respond {
+                    status: 401,
+                    headers: vec![("Content-Type".to_string(), "text/html".to_string())],
+                    body: format!("<a href=\"{}\">Log in</a>", request.login_url().unwrap()),
+                },
+            }
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_custom_no_auth_behavior() {
+        let cas_client = get_cas_client(
+            "auth/cas",
+            CAS_URL,
+            NoAuthBehavior::custom(ApiKeyOrBrandedPage),
+        );
+        let mut app = actix_web::test::init_service(
+            App::new()
+                .wrap(cas_client)
+                .wrap(CookieSession::signed(&[0; 32]).secure(false).name(SESSION_COOKIE_NAME))
+                .route("/tenant", web::get().to(seed_tenant))
+                .route("/seed/{authenticated_ago}", web::get().to(seed_session))
+                .default_service(web::route().to(ok)),
+        )
+        .await;
+
+        let req = actix_web::test::TestRequest::get()
+            .uri("/items")
+            .header("X-Api-Key", "secret")
+            .to_request();
+        let resp = actix_web::test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let req = actix_web::test::TestRequest::get().uri("/old/items").to_request();
+        let resp = actix_web::test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::TEMPORARY_REDIRECT);
+        assert_eq!(resp.headers().get(http::header::LOCATION).unwrap(), "/new");
+
+        let req = actix_web::test::TestRequest::get().uri("/items").to_request();
+        let resp = actix_web::test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(resp.headers().get(http::header::CONTENT_TYPE).unwrap(), "text/html");
+        let body = actix_web::test::read_body(resp).await;
+        assert_eq!(
+            body,
+            format!(
+                "<a href=\"{}/login?service=http%3A%2F%2Flocalhost%3A8080%2Fitems\">Log in</a>",
+                CAS_URL
+            )
+        );
+
+        // Session state
+        for (seed, expected_status, expected_location) in &[
+            ("/tenant", StatusCode::TEMPORARY_REDIRECT, Some("/acme/login")),
+            ("/seed/36000", StatusCode::UNAUTHORIZED, None),
+        ] {
+            let req = actix_web::test::TestRequest::get()
+                .uri(seed)
+                .header("X-Api-Key", "secret")
+                .to_request();
+            let resp = actix_web::test::call_service(&mut app, req).await;
+            let cookie = resp.response().cookies().next().unwrap().into_owned();
+            let req = actix_web::test::TestRequest::get().uri("/items").cookie(cookie).to_request();
+            let resp = actix_web::test::call_service(&mut app, req).await;
+            assert_eq!(resp.status(), *expected_status, "{}", seed);
+            let location = resp.headers().get(http::header::LOCATION);
+            assert_eq!(location.map(|location| location.to_str().unwrap()), *expected_location);
+        }
+    }
+
+    async fn seed_tenant(req: HttpRequest) -> HttpResponse {
+        req.get_session().set("tenant", "acme").unwrap();
+        HttpResponse::Ok().finish()
+    }
+
+    async fn seed_session(req: HttpRequest, authenticated_ago: web::Path<u64>) -> HttpResponse {
+        let session = req.get_session();
+        session.set(CAS_USER_SESSION_KEY, CasUser::new("jdoe", None)).unwrap();
+        let authentication = CasAuthentication::new(session::now() - authenticated_ago.into_inner());
+        session.set(CAS_AUTHENTICATION_SESSION_KEY, authentication).unwrap();
+        HttpResponse::Ok().finish()
+    }
+
+    /// Records the users logging out
+    struct LoggedOut(Arc<std::sync::Mutex<Vec<Option<String>>>>);
+
+    #[async_trait::async_trait(?Send)]
+    impl CasEventHandler for LoggedOut {
+        async fn on_logout(&self, _req: &HttpRequest, cas_user: Option<&CasUser>) {
+            let username = cas_user.map(|cas_user| cas_user.username().to_string());
+            self.0.lock().unwrap().push(username);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_logout_behaviors() {
+        let logged_out = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let app = |behavior: LogoutBehavior| {
+            let mut cas_client = get_cas_client("auth/cas", CAS_URL, NoAuthBehavior::Authenticate);
+            cas_client.cas_client.set_app_url("http://localhost:8080/app");
+            cas_client
+                .set_logout_behavior(behavior)
+                .set_event_handler(LoggedOut(logged_out.clone()));
+            App::new()
+                .wrap(CookieSession::signed(&[0; 32]).secure(false).name(SESSION_COOKIE_NAME))
+                .app_data(cas_client)
+                .route("/seed/{authenticated_ago}", web::get().to(seed_session))
+                .service(web::scope(LOGOUT_PATH).service(urls::cas_logout))
+        };
+        let logout = |query: &str| actix_web::test::TestRequest::get().uri(&format!("{}/?{}", LOGOUT_PATH, query));
+
+        let mut app_with_return = actix_web::test::init_service(app(LogoutBehavior::CasWithReturn)).await;
+        let req = actix_web::test::TestRequest::get().uri("/seed/0").to_request();
+        let resp = actix_web::test::call_service(&mut app_with_return, req).await;
+        let cookie = resp.response().cookies().next().unwrap().into_owned();
+        for (query, expected_location) in &[
+            ("return_to=/app/bye", "http://fake.cas/logout?service=http%3A%2F%2Flocalhost%3A8080%2Fapp%2Fbye"),
+            // Not allowed by the redirect policy
+            ("return_to=https://evil.example.org", "http://fake.cas/logout?service=http%3A%2F%2Flocalhost%3A8080%2Fapp"),
+        ] {
+            let req = logout(query).cookie(cookie.clone()).to_request();
+            let resp = actix_web::test::call_service(&mut app_with_return, req).await;
+            assert_eq!(resp.status(), StatusCode::TEMPORARY_REDIRECT);
+            assert_eq!(resp.headers().get("location").unwrap(), expected_location);
+        }
+        assert_eq!(*logged_out.lock().unwrap(), vec![Some("jdoe".to_string()), Some("jdoe".to_string())]);
+
+        let mut local = actix_web::test::init_service(app(LogoutBehavior::Local)).await;
+        let resp = actix_web::test::call_service(&mut local, logout("return_to=/app/bye").to_request()).await;
+        assert_eq!(resp.headers().get("location").unwrap(), "http://localhost:8080/app/bye");
+
+        // Single-page apps get the url to go to
+        let mut cas = actix_web::test::init_service(app(LogoutBehavior::Cas)).await;
+        let req = logout("return_to=/app/bye").header("Accept", "application/json").to_request();
+        let resp = actix_web::test::call_service(&mut cas, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: serde_json::Value = actix_web::test::read_body_json(resp).await;
+        assert_eq!(body["redirect_url"], "http://fake.cas/logout");
+        assert_eq!(logged_out.lock().unwrap().last(), Some(&None));
+    }
+
+    /// Denies `banned`, logs `jdoe` in under their directory name and records the
+    /// other events
+    #[derive(Default)]
+    struct Directory {
+        events: std::sync::Mutex<Vec<String>>,
+    }
+
+    #[async_trait::async_trait(?Send)]
+    impl CasEventHandler for Arc<Directory> {
+        async fn on_login(&self, cas_user: &CasUser) -> Result<LoginDecision, CasEventError> {
+            match cas_user.username() {
+                "banned" => Ok(LoginDecision::Deny),
+                "jdoe" => Ok(LoginDecision::AllowAs(CasUser::new("john.doe", None))),
+                _ => Err("directory unavailable".into()),
+            }
+        }
+
+        async fn on_logout(&self, _req: &HttpRequest, cas_user: Option<&CasUser>) {
+            let username = cas_user.map(|cas_user| cas_user.username()).unwrap_or("nobody");
+            self.events.lock().unwrap().push(format!("logout {}", username));
+        }
+
+        async fn on_failure(&self, error: &CasValidationError) {
+            self.events.lock().unwrap().push(format!("failure {:?}", error));
+        }
+    }
+
+    #[derive(Debug, Deserialize, PartialEq, Serialize)]
+    struct AppUser {
+        id: u64,
+        username: String,
+    }
+
+    /// Resolves every user to an `AppUser`, unless it is unavailable
+    #[derive(Default)]
+    struct AppUsers {
+        lookups: std::sync::atomic::AtomicUsize,
+        unavailable: std::sync::atomic::AtomicBool,
+    }
+
+    #[async_trait::async_trait(?Send)]
+    impl PrincipalResolver for Arc<AppUsers> {
+        type Principal = AppUser;
+
+        async fn resolve(&self, cas_user: &CasUser) -> Result<AppUser, CasEventError> {
+            use std::sync::atomic::Ordering;
+            if self.unavailable.load(Ordering::SeqCst) {
+                return Err("database unavailable".into());
+            }
+            let id = self.lookups.fetch_add(1, Ordering::SeqCst) as u64 + 1;
+            Ok(AppUser { id, username: cas_user.username().to_string() })
+        }
+    }
+
+    async fn app_user(user: Principal<AppUser>) -> HttpResponse {
+        HttpResponse::Ok().body(format!("{} {}", user.id, user.username))
+    }
+
+    #[actix_rt::test]
+    async fn test_principal_resolver() {
+        let app_users = Arc::new(AppUsers::default());
+        let mut cas_client = get_cas_client("auth/cas", CAS_URL, NoAuthBehavior::Authenticate);
+        cas_client.set_principal_resolver(app_users.clone());
+        let mut app = actix_web::test::init_service(
+            App::new()
+                .wrap(CookieSession::signed(&[0; 32]).secure(false).name(SESSION_COOKIE_NAME))
+                .route("/seed/{authenticated_ago}", web::get().to(seed_session))
+                .service(
+                    web::scope("/app")
+                        .wrap(cas_client)
+                        .route("/me", web::get().to(app_user)),
+                ),
+        )
+        .await;
+        let seed = |app_users: &AppUsers, unavailable: bool| {
+            app_users.unavailable.store(unavailable, std::sync::atomic::Ordering::SeqCst);
+            actix_web::test::TestRequest::get().uri("/seed/0").to_request()
+        };
+
+        // Resolved once per login
+        let resp = actix_web::test::call_service(&mut app, seed(&app_users, false)).await;
+        let mut cookie = resp.response().cookies().next().unwrap().into_owned();
+        for _ in 0..2 {
+            let req = actix_web::test::TestRequest::get().uri("/app/me").cookie(cookie.clone());
+            let resp = actix_web::test::call_service(&mut app, req.to_request()).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            if let Some(new_cookie) = resp.response().cookies().next() {
+                cookie = new_cookie.into_owned();
+            }
+            assert_eq!(actix_web::test::read_body(resp).await, "1 jdoe");
+        }
+
+        let resp = actix_web::test::call_service(&mut app, seed(&app_users, true)).await;
+        let cookie = resp.response().cookies().next().unwrap().into_owned();
+        let req = actix_web::test::TestRequest::get().uri("/app/me").cookie(cookie.clone());
+        let resp = actix_web::test::call_service(&mut app, req.to_request()).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        // The failure is remembered rather than retried by every request
+        app_users.unavailable.store(false, std::sync::atomic::Ordering::SeqCst);
+        let req = actix_web::test::TestRequest::get().uri("/app/me").cookie(cookie);
+        let resp = actix_web::test::call_service(&mut app, req.to_request()).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        assert_eq!(app_users.lookups.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    #[actix_rt::test]
+    async fn test_event_handler() {
+        let cas = MockCasServer::start();
+        for username in &["jdoe", "banned", "guest"] {
+            cas.add_user(MockUser::new(username, "secret"));
+        }
+        let directory = Arc::new(Directory::default());
+        let mut cas_client = get_cas_client("auth/cas", &cas.url(), NoAuthBehavior::Authenticate);
+        cas_client
+            .set_login_csrf_protection(false)
+            .set_event_handler(directory.clone());
+        let mut app = actix_web::test::init_service(
+            App::new()
+                .wrap(CookieSession::signed(&[0; 32]).secure(false).name(SESSION_COOKIE_NAME))
+                .app_data(cas_client.clone())
+                .data(cas_client.clone())
+                .service(web::scope(LOGOUT_PATH).service(urls::cas_logout))
+                .service(
+                    web::scope(USER_PATH)
+                        .wrap(cas_client.clone())
+                        .route("", web::get().to(user)),
+                ),
+        )
+        .await;
+        let service = format!("http://localhost:8080{}", USER_PATH);
+        let ticket_request = |username: &str| {
+            let ticket = cas.issue_service_ticket(username, &service);
+            actix_web::test::TestRequest::get().uri(&format!("{}?ticket={}", USER_PATH, ticket))
+        };
+
+        // The handler picks the user stored in the session
+        let resp = actix_web::test::call_service(&mut app, ticket_request("jdoe").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::TEMPORARY_REDIRECT);
+        let cookie = resp.response().cookies().next().unwrap().into_owned();
+        let req = actix_web::test::TestRequest::get().uri(USER_PATH).cookie(cookie.clone());
+        let body = actix_web::test::read_response(&mut app, req.to_request()).await;
+        assert!(String::from_utf8_lossy(&body).contains("Welcome <b>john.doe</b>"));
+        let req = actix_web::test::TestRequest::get().uri(&format!("{}/", LOGOUT_PATH)).cookie(cookie);
+        actix_web::test::call_service(&mut app, req.to_request()).await;
+
+        let resp = actix_web::test::call_service(&mut app, ticket_request("banned").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let mut req = actix_web::test::TestRequest::get().uri(USER_PATH);
+        if let Some(cookie) = resp.response().cookies().next() {
+            req = req.cookie(cookie.into_owned());
+        }
+        let resp = actix_web::test::call_service(&mut app, req.to_request()).await;
+        assert_eq!(resp.status(), StatusCode::TEMPORARY_REDIRECT);
+        assert!(testing::cas_login_service(&resp, &cas_client).is_some());
+
+        let resp = actix_web::test::call_service(&mut app, ticket_request("guest").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        cas.fail_next_validation("INVALID_TICKET", "Ticket not recognized");
+        let resp = actix_web::test::call_service(&mut app, ticket_request("jdoe").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::TEMPORARY_REDIRECT);
+        assert_eq!(
+            *directory.events.lock().unwrap(),
+            vec![
+                "logout john.doe".to_string(),
+                format!(
+                    "failure {:?}",
+                    CasValidationError::Failure {
+                        code: "INVALID_TICKET".to_string(),
+                        description: "Ticket not recognized".to_string(),
+                    }
+                ),
+            ]
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_allowed_hosts() {
+        let mut cas_client = get_cas_client("auth/cas", CAS_URL, NoAuthBehavior::AuthenticatedOr403);
+        assert!(cas_client.add_trusted_proxy("10.0.0.0/33").is_err());
+        assert!(cas_client.add_trusted_proxy("proxy.example.org").is_err());
+        cas_client
+            .add_trusted_proxy("10.0.0.0/8")
+            .unwrap()
+            .add_allowed_host("app.example.org");
+        let mut app = actix_web::test::init_service(
+            App::new()
+                .wrap(cas_client)
+                .default_service(web::route().to(ok)),
+        )
+        .await;
+        let proxy = "10.0.0.5:40000".parse().unwrap();
+        let stranger = "198.51.100.7:40000".parse().unwrap();
+        let request = |host: &str| actix_web::test::TestRequest::get().uri(USER_PATH).header("Host", host);
+        let cases = vec![
+            (request("App.example.org:8443"), StatusCode::FORBIDDEN),
+            (request("evil.example.org"), StatusCode::BAD_REQUEST),
+            // Forwarded hosts are only honored from trusted proxies
+            (
+                request("app.example.org").header("X-Forwarded-Host", "evil.example.org").peer_addr(stranger),
+                StatusCode::FORBIDDEN,
+            ),
+            (
+                request("app.example.org").header("X-Forwarded-Host", "evil.example.org").peer_addr(proxy),
+                StatusCode::BAD_REQUEST,
+            ),
+        ];
+        for (req, expected_status) in cases {
+            let resp = actix_web::test::call_service(&mut app, req.to_request()).await;
+            assert_eq!(resp.status(), expected_status);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_session_timeouts() {
+        // The defaults: 8 hours absolute, 30 minutes idle
+        let cas_client = get_cas_client("auth/cas", CAS_URL, NoAuthBehavior::AuthenticatedOr403);
+        let mut app = actix_web::test::init_service(
+            App::new()
+                .wrap(CookieSession::signed(&[0; 32]).secure(false).name(SESSION_COOKIE_NAME))
+                .data(cas_client.clone())
+                .route("/seed/{authenticated_ago}", web::get().to(seed_session))
+                .service(
+                    web::scope(PROTECTED_PATH_403)
+                        .wrap(cas_client)
+                        .route("", web::get().to(user)),
+                ),
+        )
+        .await;
+
+        for (authenticated_ago, expected_status) in &[
+            (0, StatusCode::OK),
+            (29 * 60, StatusCode::OK),
+            (31 * 60, StatusCode::FORBIDDEN),
+            (9 * 3_600, StatusCode::FORBIDDEN),
+        ] {
+            let req = actix_web::test::TestRequest::get()
+                .uri(&format!("/seed/{}", authenticated_ago))
+                .to_request();
+            let resp = actix_web::test::call_service(&mut app, req).await;
+            let cookie = resp.response().cookies().next().unwrap().into_owned();
+
+            let req = actix_web::test::TestRequest::get()
+                .uri(PROTECTED_PATH_403)
+                .cookie(cookie)
+                .to_request();
+            let resp = actix_web::test::call_service(&mut app, req).await;
+            assert_eq!(resp.status(), *expected_status, "{}s ago", authenticated_ago);
+        }
+
+    }
+
+    #[actix_rt::test]
+    async fn test_activity_is_recorded_sparingly() {
+        // With a 30 minutes idle timeout, once every 3 minutes at most
+        let mut cas_client = get_cas_client("auth/cas", CAS_URL, NoAuthBehavior::AuthenticatedOr403);
+        // Keeps the after logged in url out of the session
+        cas_client.set_return_url_mode(ReturnUrlMode::SignedServiceUrl);
+        let mut app = actix_web::test::init_service(
+            App::new()
+                .wrap(CookieSession::signed(&[0; 32]).secure(false).name(SESSION_COOKIE_NAME))
+                .data(cas_client.clone())
+                .route("/seed/{authenticated_ago}", web::get().to(seed_session))
+                .service(
+                    web::scope(PROTECTED_PATH_403)
+                        .wrap(cas_client)
+                        .route("", web::get().to(user)),
+                ),
+        )
+        .await;
+
+        for (authenticated_ago, expect_recorded) in &[(0, false), (2 * 60, false), (4 * 60, true)] {
+            let req = actix_web::test::TestRequest::get()
+                .uri(&format!("/seed/{}", authenticated_ago))
+                .to_request();
+            let resp = actix_web::test::call_service(&mut app, req).await;
+            let cookie = resp.response().cookies().next().unwrap().into_owned();
+
+            let req = actix_web::test::TestRequest::get().uri(PROTECTED_PATH_403).cookie(cookie).to_request();
+            let resp = actix_web::test::call_service(&mut app, req).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            let recorded = resp.response().cookies().next().map(|cookie| cookie.into_owned());
+            assert_eq!(recorded.is_some(), *expect_recorded, "{}s ago", authenticated_ago);
+
+            // Within the interval of the activity just recorded
+            if let Some(cookie) = recorded {
+                let req = actix_web::test::TestRequest::get().uri(PROTECTED_PATH_403).cookie(cookie).to_request();
+                let resp = actix_web::test::call_service(&mut app, req).await;
+                assert_eq!(resp.status(), StatusCode::OK);
+                assert!(resp.response().cookies().next().is_none());
+            }
+        }
+    }
 }
@@ -0,0 +1,68 @@
+use crate::ActixCasClient;
+use actix_session::UserSession;
+use actix_web::{http, web, HttpRequest, HttpResponse};
+use std::collections::HashMap;
+
+/// Query parameter of the logout route with the url to return to afterwards
+pub const LOGOUT_RETURN_PARAM: &str = "return_to";
+
+/// What the logout route does besides ending the session of the app
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum LogoutBehavior {
+    /// Nothing: the user stays logged in to CAS and is sent to the return url
+    Local,
+    /// Log the user out of CAS, leaving them on the CAS logout page
+    Cas,
+    /// Log the user out of CAS, which sends them back to the return url
+    #[default]
+    CasWithReturn,
+}
+
+/// Log the user of `req` out according to the `LogoutBehavior` of `cas_client`.
+/// `None` if the CAS logout url cannot be built.
+///
+/// API requests get the url to go to in JSON, `{"logged_out": true,
+/// "redirect_url": "..."}`, rather than a redirect.
+pub(crate) fn logout(req: &HttpRequest, cas_client: &ActixCasClient) -> Option<HttpResponse> {
+    let session = req.get_session();
+    session.purge();
+
+    let return_url = return_url(req, cas_client);
+    let redirect_url = match cas_client.logout_behavior {
+        LogoutBehavior::Local => Some(return_url.unwrap_or_else(|| String::from("/"))),
+        LogoutBehavior::Cas => cas_client.cas_client.logout_url_with_return(None),
+        LogoutBehavior::CasWithReturn => cas_client
+            .cas_client
+            .logout_url_with_return(return_url.as_deref()),
+    }?;
+    if cas_client.api_detection.is_api_request(req.head()) {
+        return Some(HttpResponse::Ok().json(serde_json::json!({
+            "logged_out": true,
+            "redirect_url": redirect_url,
+        })));
+    }
+    Some(
+        HttpResponse::build(http::StatusCode::TEMPORARY_REDIRECT)
+            .header(http::header::LOCATION, redirect_url)
+            .finish(),
+    )
+}
+
+/// Url to send the user to after logging out: the `return_to` of the request if
+/// the redirect policy allows it, the app url otherwise
+fn return_url(req: &HttpRequest, cas_client: &ActixCasClient) -> Option<String> {
+    let requested = web::Query::<HashMap<String, String>>::from_query(req.query_string())
+        .ok()
+        .and_then(|params| params.get(LOGOUT_RETURN_PARAM).cloned());
+    if let Some(requested) = requested {
+        if cas_client.is_redirect_allowed(req, &requested) {
+            // CAS needs an absolute url
+            return cas_client.absolute_url(req, &requested);
+        }
+    }
+    let app_url = cas_client.app_url();
+    match !app_url.is_empty() && cas_client.is_redirect_allowed(req, &app_url) {
+        true => Some(app_url),
+        false => None,
+    }
+}
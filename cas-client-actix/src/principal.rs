@@ -0,0 +1,264 @@
+use crate::events::CasEventError;
+use async_trait::async_trait;
+use cas_client_core::CasUser;
+use futures::channel::oneshot;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::any::{self, TypeId};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Maps the logged in `CasUser` to the user type of the application, e.g. by
+/// looking it up in a database
+///
+/// The middleware resolves the principal once per login and stores it in the
+/// session along with the `CasUser`, where the `Principal<Self::Principal>`
+/// extractor reads it. The concurrent requests of a user share one resolution.
+/// Users whose principal cannot be resolved get a 403, and the failure is
+/// remembered for 30 seconds rather than retried by each of their requests.
+///
+/// ```
+/// use async_trait::async_trait;
+/// use cas_client_actix::{CasEventError, PrincipalResolver};
+/// use cas_client_core::CasUser;
+/// use serde::{Deserialize, Serialize};
+///
+/// #[derive(Deserialize, Serialize)]
+/// struct User {
+///     id: u64,
+///     username: String,
+/// }
+///
+/// struct Users;
+///
+/// #[async_trait(?Send)]
+/// impl PrincipalResolver for Users {
+///     type Principal = User;
+///
+///     async fn resolve(&self, cas_user: &CasUser) -> Result<User, CasEventError> {
+///         match cas_user.username() {
+///             "jdoe" => Ok(User { id: 1, username: "jdoe".to_string() }),
+///             username => Err(format!("Unknown user {}", username).into()),
+///         }
+///     }
+/// }
+/// ```
+#[async_trait(?Send)]
+pub trait PrincipalResolver: Send + Sync {
+    type Principal: Serialize + DeserializeOwned + 'static;
+
+    async fn resolve(&self, cas_user: &CasUser) -> Result<Self::Principal, CasEventError>;
+}
+
+// Resolvers with their principal serialized, as it is stored in the session
+#[async_trait(?Send)]
+trait ErasedResolver: Send + Sync {
+    async fn resolve_value(&self, cas_user: &CasUser) -> Result<serde_json::Value, CasEventError>;
+}
+
+#[async_trait(?Send)]
+impl<R: PrincipalResolver> ErasedResolver for R {
+    async fn resolve_value(&self, cas_user: &CasUser) -> Result<serde_json::Value, CasEventError> {
+        let principal = self.resolve(cas_user).await?;
+        Ok(serde_json::to_value(principal)?)
+    }
+}
+
+type Resolution = Result<serde_json::Value, String>;
+
+/// Resolution of the principal of a user, in flight or failed
+enum Flight {
+    // Requests waiting for the resolution in flight
+    InFlight(Vec<oneshot::Sender<Resolution>>),
+    Failed { error: String, expires_at: Instant },
+}
+
+#[derive(Clone)]
+pub(crate) struct SharedResolver {
+    resolver: Arc<dyn ErasedResolver>,
+    principal_type: TypeId,
+    principal_type_name: &'static str,
+    failure_ttl: Duration,
+    // Keyed by username
+    flights: Arc<Mutex<HashMap<String, Flight>>>,
+}
+
+impl SharedResolver {
+    pub(crate) fn new<R: PrincipalResolver + 'static>(resolver: R) -> Self {
+        SharedResolver {
+            resolver: Arc::new(resolver),
+            principal_type: TypeId::of::<R::Principal>(),
+            principal_type_name: any::type_name::<R::Principal>(),
+            failure_ttl: Duration::from_secs(30),
+            flights: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Whether the principals resolved are of type `P`
+    pub(crate) fn resolves<P: 'static>(&self) -> bool {
+        self.principal_type == TypeId::of::<P>()
+    }
+
+    /// Principal of `cas_user`, serialized. Waits for the resolution in flight
+    /// for the same user, if any.
+    pub(crate) async fn resolve(&self, cas_user: &CasUser) -> Result<serde_json::Value, CasEventError> {
+        let key = cas_user.username().to_string();
+        let waiting = {
+            let mut flights = self.flights.lock().expect("Principal flights lock poisoned");
+            let now = Instant::now();
+            flights.retain(|_, flight| match flight {
+                Flight::Failed { expires_at, .. } => *expires_at > now,
+                Flight::InFlight(_) => true,
+            });
+            match flights.get_mut(&key) {
+                Some(Flight::Failed { error, .. }) => return Err(error.clone().into()),
+                Some(Flight::InFlight(waiters)) => {
+                    let (sender, receiver) = oneshot::channel();
+                    waiters.push(sender);
+                    Some(receiver)
+                }
+                None => {
+                    flights.insert(key.clone(), Flight::InFlight(Vec::new()));
+                    None
+                }
+            }
+        };
+        if let Some(receiver) = waiting {
+            let resolution = receiver
+                .await
+                .unwrap_or_else(|_| Err("Resolution of the principal aborted".to_string()));
+            return resolution.map_err(Into::into);
+        }
+        let mut guard = FlightGuard {
+            resolver: self,
+            key: Some(key),
+        };
+        let resolution = self
+            .resolver
+            .resolve_value(cas_user)
+            .await
+            .map_err(|err| err.to_string());
+        guard.complete(resolution.clone(), true);
+        resolution.map_err(Into::into)
+    }
+}
+
+/// Completes the flight of a resolution, even if its future is dropped: the
+/// requests waiting for it then get an error rather than waiting forever
+struct FlightGuard<'r> {
+    resolver: &'r SharedResolver,
+    key: Option<String>,
+}
+
+impl FlightGuard<'_> {
+    /// Hand `resolution` to the waiting requests, and remember it if it is a
+    /// failure and `keep_failure`. Successes are stored in the sessions.
+    fn complete(&mut self, resolution: Resolution, keep_failure: bool) {
+        let key = match self.key.take() {
+            Some(key) => key,
+            None => return,
+        };
+        // Never panics: it may run while unwinding
+        let mut flights = self.resolver.flights.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let waiters = match flights.remove(&key) {
+            Some(Flight::InFlight(waiters)) => waiters,
+            _ => Vec::new(),
+        };
+        if let (Err(error), true) = (&resolution, keep_failure) {
+            let expires_at = Instant::now() + self.resolver.failure_ttl;
+            flights.insert(key, Flight::Failed { error: error.clone(), expires_at });
+        }
+        drop(flights);
+        for waiter in waiters {
+            let _ = waiter.send(resolution.clone());
+        }
+    }
+}
+
+impl Drop for FlightGuard<'_> {
+    fn drop(&mut self) {
+        if self.key.is_some() {
+            warn!("Resolution of a principal aborted");
+            self.complete(Err("Resolution of the principal aborted".to_string()), false);
+        }
+    }
+}
+
+impl fmt::Debug for SharedResolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PrincipalResolver<{}>(..)", self.principal_type_name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Resolves `jdoe` to their id after a while, fails for the other users
+    #[derive(Default)]
+    struct SlowIds {
+        calls: AtomicUsize,
+    }
+
+    #[async_trait(?Send)]
+    impl PrincipalResolver for Arc<SlowIds> {
+        type Principal = u64;
+
+        async fn resolve(&self, cas_user: &CasUser) -> Result<u64, CasEventError> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            actix_rt::time::delay_for(Duration::from_millis(10)).await;
+            match cas_user.username() {
+                "jdoe" => Ok(42),
+                username => Err(format!("Unknown user {}", username).into()),
+            }
+        }
+    }
+
+    #[actix_rt::test]
+    async fn concurrent_resolutions_should_be_shared() {
+        let ids = Arc::new(SlowIds::default());
+        let resolver = SharedResolver::new(ids.clone());
+        let jdoe = CasUser::new("jdoe", None);
+        let (first, second) = futures::join!(resolver.resolve(&jdoe), resolver.resolve(&jdoe));
+        assert_eq!(first.unwrap(), serde_json::json!(42));
+        assert_eq!(second.unwrap(), serde_json::json!(42));
+        assert_eq!(ids.calls.load(Ordering::SeqCst), 1);
+
+        // Successes are kept by the sessions
+        resolver.resolve(&jdoe).await.unwrap();
+        assert_eq!(ids.calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[actix_rt::test]
+    async fn failures_should_be_remembered() {
+        let ids = Arc::new(SlowIds::default());
+        let resolver = SharedResolver::new(ids.clone());
+        let unknown = CasUser::new("unknown", None);
+        for _ in 0..2 {
+            let error = resolver.resolve(&unknown).await.unwrap_err();
+            assert_eq!(error.to_string(), "Unknown user unknown");
+        }
+        assert_eq!(ids.calls.load(Ordering::SeqCst), 1);
+        assert!(resolver.resolves::<u64>());
+        assert!(!resolver.resolves::<String>());
+    }
+
+    #[actix_rt::test]
+    async fn aborted_resolution_should_release_its_waiters() {
+        let ids = Arc::new(SlowIds::default());
+        let resolver = SharedResolver::new(ids.clone());
+        let jdoe = CasUser::new("jdoe", None);
+        let mut first = Box::pin(resolver.resolve(&jdoe));
+        // Started, then dropped while a second request waits for it
+        assert!(futures::poll!(first.as_mut()).is_pending());
+        let mut second = Box::pin(resolver.resolve(&jdoe));
+        assert!(futures::poll!(second.as_mut()).is_pending());
+        drop(first);
+        assert!(second.await.is_err());
+        // Not remembered
+        assert_eq!(resolver.resolve(&jdoe).await.unwrap(), serde_json::json!(42));
+    }
+}
@@ -0,0 +1,183 @@
+use url::Url;
+
+// Base used to resolve relative redirect targets. A relative target resolving to
+// another host (e.g. `//evil.example.org`) is not relative.
+const RELATIVE_BASE: &str = "http://relative.invalid";
+
+/// Allow-list of the urls users may be redirected to after logging in or out
+///
+/// Without allowed origins, only same-origin urls (the origin of the request or of
+/// the `app_url`) and relative paths are allowed. Without allowed paths, any path
+/// of an allowed origin is.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RedirectPolicy {
+    allowed_origins: Vec<String>,
+    allowed_paths: Vec<String>,
+}
+
+impl RedirectPolicy {
+    // ################################################################################
+    // Constructor
+    // ################################################################################
+    pub fn new() -> Self {
+        RedirectPolicy::default()
+    }
+
+    // ################################################################################
+    // Getters / Setters
+    // ################################################################################
+    pub fn allowed_origins(&self) -> &[String] {
+        &self.allowed_origins
+    }
+
+    /// Allow redirects to `origin`, e.g. `https://app.example.org`
+    pub fn add_allowed_origin(&mut self, origin: &str) -> &mut Self {
+        match origin_of(origin) {
+            Some(origin) => self.allowed_origins.push(origin),
+            None => error!("Invalid redirect origin: {:?}", origin),
+        };
+        self
+    }
+
+    pub fn allowed_paths(&self) -> &[String] {
+        &self.allowed_paths
+    }
+
+    /// Only allow redirects to paths under `path_prefix`, e.g. `/app`
+    pub fn add_allowed_path(&mut self, path_prefix: &str) -> &mut Self {
+        match path_prefix.starts_with('/') {
+            true => self.allowed_paths.push(path_prefix.to_string()),
+            false => error!("Invalid redirect path: {:?}", path_prefix),
+        };
+        self
+    }
+
+    // ################################################################################
+    // Public functions
+    // ################################################################################
+    /// Whether users may be redirected to `target`. `same_origins` are the origins
+    /// allowed when no origin is configured.
+    pub fn is_allowed(&self, target: &str, same_origins: &[String]) -> bool {
+        if target.is_empty()
+            || target.contains('\\')
+            || target.chars().any(|c| c.is_control() || c.is_whitespace())
+        {
+            return false;
+        }
+        let path = match Url::parse(target) {
+            Ok(url) => {
+                if !matches!(url.scheme(), "http" | "https")
+                    || !url.username().is_empty()
+                    || url.password().is_some()
+                {
+                    return false;
+                }
+                let origin = url.origin().ascii_serialization();
+                let origin_allowed = match self.allowed_origins.is_empty() {
+                    true => same_origins.contains(&origin),
+                    false => self.allowed_origins.contains(&origin),
+                };
+                if !origin_allowed {
+                    return false;
+                }
+                url.path().to_string()
+            }
+            Err(_) => {
+                // Scheme-relative urls (`//host/path`) are not relative paths
+                if !target.starts_with('/') || target.starts_with("//") {
+                    return false;
+                }
+                match Url::parse(RELATIVE_BASE).and_then(|base| base.join(target)) {
+                    Ok(url) if url.origin().ascii_serialization() == RELATIVE_BASE => {
+                        url.path().to_string()
+                    }
+                    _ => return false,
+                }
+            }
+        };
+        self.allowed_paths.is_empty()
+            || self
+                .allowed_paths
+                .iter()
+                .any(|prefix| path_is_under(&path, prefix))
+    }
+}
+
+/// Origin (`scheme://host[:port]`) of `url`
+pub(crate) fn origin_of(url: &str) -> Option<String> {
+    match Url::parse(url) {
+        Ok(url) if url.has_host() => Some(url.origin().ascii_serialization()),
+        _ => None,
+    }
+}
+
+fn path_is_under(path: &str, prefix: &str) -> bool {
+    let prefix = prefix.trim_end_matches('/');
+    prefix.is_empty()
+        || path == prefix
+        || (path.starts_with(prefix) && path[prefix.len()..].starts_with('/'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn same_origins() -> Vec<String> {
+        vec!["https://app.example.org".to_string()]
+    }
+
+    #[test]
+    fn should_allow_same_origin_by_default() {
+        let policy = RedirectPolicy::new();
+        assert!(policy.is_allowed("https://app.example.org/user?a=b", &same_origins()));
+        assert!(policy.is_allowed("/user", &same_origins()));
+        assert!(!policy.is_allowed("https://evil.example.org/user", &same_origins()));
+        assert!(!policy.is_allowed("http://app.example.org/user", &same_origins()));
+        assert!(!policy.is_allowed("https://app.example.org:8443/user", &same_origins()));
+    }
+
+    #[test]
+    fn should_reject_dangerous_urls() {
+        let policy = RedirectPolicy::new();
+        for target in &[
+            "",
+            "//evil.example.org/user",
+            "/\\evil.example.org",
+            "\\\\evil.example.org",
+            " //evil.example.org",
+            "javascript:alert(1)",
+            "JavaScript:alert(1)",
+            "data:text/html,<script>alert(1)</script>",
+            "https://user@app.example.org/",
+            "user",
+            "/user\r\nSet-Cookie: a=b",
+        ] {
+            assert!(!policy.is_allowed(target, &same_origins()), "{:?}", target);
+        }
+    }
+
+    #[test]
+    fn should_check_allowed_origins() {
+        let mut policy = RedirectPolicy::new();
+        policy
+            .add_allowed_origin("https://portal.example.org/ignored/path")
+            .add_allowed_origin("not an origin");
+        assert_eq!(policy.allowed_origins(), &["https://portal.example.org".to_string()]);
+        assert!(policy.is_allowed("https://portal.example.org/home", &same_origins()));
+        assert!(!policy.is_allowed("https://app.example.org/home", &same_origins()));
+        assert!(policy.is_allowed("/home", &same_origins()));
+    }
+
+    #[test]
+    fn should_check_allowed_paths() {
+        let mut policy = RedirectPolicy::new();
+        policy.add_allowed_path("/app/").add_allowed_path("invalid");
+        assert_eq!(policy.allowed_paths(), &["/app/".to_string()]);
+        assert!(policy.is_allowed("/app", &same_origins()));
+        assert!(policy.is_allowed("/app/user?a=b", &same_origins()));
+        assert!(policy.is_allowed("https://app.example.org/app/user", &same_origins()));
+        assert!(!policy.is_allowed("/application", &same_origins()));
+        assert!(!policy.is_allowed("/app/../admin", &same_origins()));
+        assert!(!policy.is_allowed("/admin", &same_origins()));
+    }
+}
@@ -0,0 +1,137 @@
+use hmac::{Hmac, Mac, NewMac};
+use once_cell::sync::Lazy;
+use rand::RngCore;
+use sha2::Sha256;
+use std::fmt;
+
+/// Query parameter of the service url carrying the signed return url
+pub(crate) const RETURN_URL_PARAM: &str = "cas_return";
+/// Query parameter of the service url identifying the state cookie of a login
+pub(crate) const STATE_PARAM: &str = "cas_state";
+/// Query parameter of the service url binding a login to the session it started in
+pub(crate) const NONCE_PARAM: &str = "cas_nonce";
+/// Prefix of the state cookie names, followed by the `cas_state` value
+pub(crate) const STATE_COOKIE_PREFIX: &str = "cas_state_";
+
+// Shared by all the clients of the process that have no key configured
+static PROCESS_KEY: Lazy<Vec<u8>> = Lazy::new(|| random_bytes(32));
+
+/// Where the url to send the user back to after login is kept during the CAS
+/// round-trip
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ReturnUrlMode {
+    /// In the session, only if no login is pending. Concurrent logins share it,
+    /// and it is lost when the session cookie is `SameSite=Strict`.
+    #[default]
+    Session,
+    /// Signed into the `cas_return` parameter of the service url
+    SignedServiceUrl,
+    /// Signed into a short-lived `SameSite=Lax` cookie, one per login, named after
+    /// the `cas_state` parameter of the service url
+    StateCookie,
+}
+
+/// HMAC-SHA256 key signing the return urls
+#[derive(Clone, PartialEq)]
+pub(crate) struct SigningKey(Vec<u8>);
+
+impl SigningKey {
+    pub fn new(key: &[u8]) -> Self {
+        SigningKey(key.to_vec())
+    }
+
+    /// Random key of the process. Signatures are not valid across processes.
+    pub fn process_key() -> Self {
+        SigningKey(PROCESS_KEY.clone())
+    }
+
+    fn mac(&self, payload: &str) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_varkey(&self.0).expect("HMAC accepts keys of any size");
+        mac.update(payload.as_bytes());
+        mac
+    }
+
+    /// Token carrying `return_url` until `expires_at` (seconds since the UNIX epoch)
+    pub fn sign(&self, return_url: &str, expires_at: u64) -> String {
+        let payload = format!("{}:{}", expires_at, return_url);
+        let signature = self.mac(&payload).finalize().into_bytes();
+        format!(
+            "{}.{}",
+            base64::encode_config(&payload, base64::URL_SAFE_NO_PAD),
+            base64::encode_config(signature, base64::URL_SAFE_NO_PAD)
+        )
+    }
+
+    /// Return url of `token`, `None` if the token is forged or expired
+    pub fn verify(&self, token: &str, now: u64) -> Option<String> {
+        let mut parts = token.splitn(2, '.');
+        let payload = base64::decode_config(parts.next()?, base64::URL_SAFE_NO_PAD).ok()?;
+        let signature = base64::decode_config(parts.next()?, base64::URL_SAFE_NO_PAD).ok()?;
+        let payload = String::from_utf8(payload).ok()?;
+        self.mac(&payload).verify(&signature).ok()?;
+
+        let mut parts = payload.splitn(2, ':');
+        let expires_at = parts.next()?.parse::<u64>().ok()?;
+        let return_url = parts.next()?;
+        match now <= expires_at {
+            true => Some(return_url.to_string()),
+            false => None,
+        }
+    }
+}
+
+// Never log the key
+impl fmt::Debug for SigningKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SigningKey(..)")
+    }
+}
+
+pub(crate) fn random_bytes(len: usize) -> Vec<u8> {
+    let mut bytes = vec![0; len];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes
+}
+
+/// Random url-safe identifier
+pub(crate) fn random_id() -> String {
+    base64::encode_config(random_bytes(16), base64::URL_SAFE_NO_PAD)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_verify_signed_return_url() {
+        let key = SigningKey::new(b"secret");
+        let token = key.sign("https://app.example.org/user?a=b:c", 1_000);
+        assert_eq!(
+            key.verify(&token, 1_000),
+            Some("https://app.example.org/user?a=b:c".to_string())
+        );
+        assert_eq!(key.verify(&token, 1_001), None);
+        assert_eq!(SigningKey::new(b"other").verify(&token, 1_000), None);
+    }
+
+    #[test]
+    fn should_reject_forged_tokens() {
+        let key = SigningKey::new(b"secret");
+        let token = key.sign("https://app.example.org/user", 1_000);
+        let signature = token.split('.').nth(1).unwrap();
+        let forged = format!(
+            "{}.{}",
+            base64::encode_config("1000:https://evil.example.org/", base64::URL_SAFE_NO_PAD),
+            signature
+        );
+        assert_eq!(key.verify(&forged, 1_000), None);
+        assert_eq!(key.verify("", 1_000), None);
+        assert_eq!(key.verify("not.a-token", 1_000), None);
+    }
+
+    #[test]
+    fn should_not_log_key() {
+        assert_eq!(format!("{:?}", SigningKey::new(b"secret")), "SigningKey(..)");
+        assert_ne!(random_id(), random_id());
+    }
+}
@@ -0,0 +1,275 @@
+use crate::api::ApiDetection;
+use actix_web::http::Method;
+use cas_client_core::{CasUser, LoginOptions, NoAuthBehavior};
+use regex::Regex;
+
+/// What a route requires from the user
+#[derive(Clone, Debug, PartialEq)]
+pub enum RouteAccess {
+    /// Anyone, logged in or not
+    Public,
+    /// A logged in user. Others are handled according to the `NoAuthBehavior`.
+    Authenticated(NoAuthBehavior),
+    /// A logged in user whose attribute `name` has one of `values`. Other logged
+    /// in users get a 403.
+    ///
+    /// The values are those of `CasUser::attribute_values`, split on `,`: values
+    /// containing commas, such as LDAP DNs, never match whole.
+    Attribute {
+        behavior: NoAuthBehavior,
+        name: String,
+        values: Vec<String>,
+    },
+}
+
+impl RouteAccess {
+    /// Behavior for users who are not logged in, `None` for public routes
+    pub fn no_auth_behavior(&self) -> Option<&NoAuthBehavior> {
+        match self {
+            RouteAccess::Public => None,
+            RouteAccess::Authenticated(behavior) => Some(behavior),
+            RouteAccess::Attribute { behavior, .. } => Some(behavior),
+        }
+    }
+
+    /// Whether the logged in `cas_user` may access the route. Any value of a
+    /// multi-valued attribute grants the access.
+    pub fn is_authorized(&self, cas_user: &CasUser) -> bool {
+        match self {
+            RouteAccess::Attribute { name, values, .. } => cas_user
+                .attribute_values(name)
+                .iter()
+                .any(|value| values.iter().any(|allowed| allowed == value)),
+            _ => true,
+        }
+    }
+}
+
+/// Access rule of the requests whose path matches a glob or a regex, and
+/// optionally whose method matches
+///
+/// Globs match a whole path: `*` matches within a path segment, `**` across
+/// segments, and a trailing `/**` also matches the path without it (`/static/**`
+/// matches `/static`).
+#[derive(Clone, Debug)]
+pub struct RouteRule {
+    pattern: Regex,
+    method: Option<Method>,
+    access: RouteAccess,
+    api_detection: Option<ApiDetection>,
+    login_options: Option<LoginOptions>,
+}
+
+impl RouteRule {
+    // ################################################################################
+    // Constructors
+    // ################################################################################
+    pub fn glob(glob: &str, access: RouteAccess) -> Self {
+        RouteRule {
+            pattern: Regex::new(&glob_to_regex(glob)).expect("Escaped glob is a valid regex"),
+            method: None,
+            access,
+            api_detection: None,
+            login_options: None,
+        }
+    }
+
+    /// Rule of the paths matching `regex`. The regex is not anchored.
+    pub fn regex(regex: &str, access: RouteAccess) -> Result<Self, regex::Error> {
+        Ok(RouteRule {
+            pattern: Regex::new(regex)?,
+            method: None,
+            access,
+            api_detection: None,
+            login_options: None,
+        })
+    }
+
+    /// Rule excluding the paths matching `glob` from authentication
+    pub fn public(glob: &str) -> Self {
+        RouteRule::glob(glob, RouteAccess::Public)
+    }
+
+    // ################################################################################
+    // Getters / Setters
+    // ################################################################################
+    pub fn method(&self) -> Option<&Method> {
+        self.method.as_ref()
+    }
+
+    /// Only apply the rule to requests with this method
+    pub fn set_method(&mut self, method: Method) -> &mut Self {
+        self.method = Some(method);
+        self
+    }
+
+    pub fn access(&self) -> &RouteAccess {
+        &self.access
+    }
+
+    pub fn api_detection(&self) -> Option<ApiDetection> {
+        self.api_detection
+    }
+
+    /// How API requests are detected on the route, instead of the detection of
+    /// the `ActixCasClient`
+    pub fn set_api_detection(&mut self, api_detection: ApiDetection) -> &mut Self {
+        self.api_detection = Some(api_detection);
+        self
+    }
+
+    pub fn login_options(&self) -> Option<&LoginOptions> {
+        self.login_options.as_ref()
+    }
+
+    /// Parameters of the CAS logins started from the route, instead of the
+    /// options of the `ActixCasClient`. `renew` is enforced when validating the
+    /// ticket, `authn_method` is not (see `LoginOptions::set_authn_method`).
+    pub fn set_login_options(&mut self, login_options: LoginOptions) -> &mut Self {
+        self.login_options = Some(login_options);
+        self
+    }
+
+    // ################################################################################
+    // Public functions
+    // ################################################################################
+    pub fn matches(&self, method: &Method, path: &str) -> bool {
+        let method_matches = match &self.method {
+            Some(rule_method) => rule_method == method,
+            None => true,
+        };
+        method_matches && self.pattern.is_match(path)
+    }
+}
+
+impl PartialEq for RouteRule {
+    fn eq(&self, other: &Self) -> bool {
+        self.pattern.as_str() == other.pattern.as_str()
+            && self.method == other.method
+            && self.access == other.access
+            && self.api_detection == other.api_detection
+            && self.login_options == other.login_options
+    }
+}
+
+/// First rule matching the request
+pub(crate) fn rule_for_request<'r>(
+    rules: &'r [RouteRule],
+    method: &Method,
+    path: &str,
+) -> Option<&'r RouteRule> {
+    rules.iter().find(|rule| rule.matches(method, path))
+}
+
+fn glob_to_regex(glob: &str) -> String {
+    let (glob, any_suffix) = match glob.strip_suffix("/**") {
+        Some(prefix) => (prefix, true),
+        None => (glob, false),
+    };
+    let mut regex = String::from("^");
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                regex.push_str(".*");
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    if any_suffix {
+        regex.push_str("(/.*)?");
+    }
+    regex.push('$');
+    regex
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn glob_should_match_whole_path() {
+        let rule = RouteRule::public("/static/**");
+        assert!(rule.matches(&Method::GET, "/static"));
+        assert!(rule.matches(&Method::GET, "/static/css/app.css"));
+        assert!(!rule.matches(&Method::GET, "/statics"));
+        assert!(!rule.matches(&Method::GET, "/api/static"));
+
+        let rule = RouteRule::public("/api/*/health");
+        assert!(rule.matches(&Method::GET, "/api/v1/health"));
+        assert!(!rule.matches(&Method::GET, "/api/v1/x/health"));
+
+        let rule = RouteRule::public("/file.?");
+        assert!(rule.matches(&Method::GET, "/file.c"));
+        assert!(!rule.matches(&Method::GET, "/fileXc"));
+    }
+
+    #[test]
+    fn rule_should_match_method() {
+        let mut rule = RouteRule::regex(
+            "^/api/",
+            RouteAccess::Authenticated(NoAuthBehavior::AuthenticatedOr403),
+        )
+        .unwrap();
+        rule.set_method(Method::POST);
+        assert!(rule.matches(&Method::POST, "/api/items"));
+        assert!(!rule.matches(&Method::GET, "/api/items"));
+        assert!(RouteRule::regex("(", RouteAccess::Public).is_err());
+    }
+
+    #[test]
+    fn first_matching_rule_should_win() {
+        let rules = vec![
+            RouteRule::public("/health"),
+            RouteRule::glob("/**", RouteAccess::Authenticated(NoAuthBehavior::Authenticate)),
+        ];
+        assert_eq!(rule_for_request(&rules, &Method::GET, "/health"), Some(&rules[0]));
+        assert_eq!(rule_for_request(&rules, &Method::GET, "/user"), Some(&rules[1]));
+        assert_eq!(rule_for_request(&rules[..1], &Method::GET, "/user"), None);
+    }
+
+    #[test]
+    fn attribute_access_should_check_user() {
+        let access = RouteAccess::Attribute {
+            behavior: NoAuthBehavior::Authenticate,
+            name: "role".to_string(),
+            values: vec!["admin".to_string()],
+        };
+        let mut attributes = HashMap::new();
+        attributes.insert("role".to_string(), "admin".to_string());
+        assert!(access.is_authorized(&CasUser::new("root", Some(attributes))));
+        assert!(!access.is_authorized(&CasUser::new("jdoe", None)));
+        assert_eq!(access.no_auth_behavior(), Some(&NoAuthBehavior::Authenticate));
+        assert_eq!(RouteAccess::Public.no_auth_behavior(), None);
+    }
+
+    #[test]
+    fn attribute_access_should_check_each_value() {
+        let access = RouteAccess::Attribute {
+            behavior: NoAuthBehavior::Authenticate,
+            name: "role".to_string(),
+            values: vec!["admin".to_string()],
+        };
+        for (roles, authorized) in &[("staff,admin", true), ("admin,staff", true), ("staff,superadmin", false)] {
+            let mut attributes = HashMap::new();
+            attributes.insert("role".to_string(), roles.to_string());
+            assert_eq!(access.is_authorized(&CasUser::new("jdoe", Some(attributes))), *authorized, "{}", roles);
+        }
+    }
+
+    #[test]
+    fn attribute_access_should_not_match_values_with_commas() {
+        let access = RouteAccess::Attribute {
+            behavior: NoAuthBehavior::Authenticate,
+            name: "memberOf".to_string(),
+            values: vec!["cn=staff,dc=example,dc=org".to_string()],
+        };
+        let mut attributes = HashMap::new();
+        attributes.insert("memberOf".to_string(), "cn=staff,dc=example,dc=org".to_string());
+        assert!(!access.is_authorized(&CasUser::new("jdoe", Some(attributes))));
+    }
+}
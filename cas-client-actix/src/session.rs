@@ -0,0 +1,398 @@
+use actix_http::Extensions;
+use actix_session::Session;
+use actix_web::Error;
+use cas_client_core::CasUser;
+use serde::de::IgnoredAny;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Session key of the logged in `CasUser`
+pub const CAS_USER_SESSION_KEY: &str = "cas_user";
+/// Session key of the `CasAuthentication` metadata of the logged in user
+pub const CAS_AUTHENTICATION_SESSION_KEY: &str = "cas_authentication";
+/// Session key of the principal resolved from the logged in `CasUser`
+pub const CAS_PRINCIPAL_SESSION_KEY: &str = "cas_principal";
+/// Absolute lifetime of CAS sessions, unless configured otherwise
+pub(crate) const DEFAULT_SESSION_MAX_LIFETIME: Duration = Duration::from_secs(8 * 60 * 60);
+/// Idle timeout of CAS sessions, unless configured otherwise
+pub(crate) const DEFAULT_SESSION_IDLE_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+// Nonces of the logins started from this session, most recent last
+const LOGIN_NONCES_SESSION_KEY: &str = "cas_login_nonces";
+// Logins a session may have pending at once, e.g. in several tabs
+const MAX_PENDING_LOGINS: usize = 5;
+// Nonces or states of the pending logins which asked for `renew`
+const RENEWED_LOGINS_SESSION_KEY: &str = "cas_renewed_logins";
+// Random id of the session, binding the tickets it validated to it
+const LOGIN_BINDING_SESSION_KEY: &str = "cas_login_binding";
+// Consecutive ticket validation failures of the session
+const VALIDATION_FAILURES_SESSION_KEY: &str = "cas_validation_failures";
+// Set once the session tried a `gateway` login, until a user logs in
+const GATEWAY_SESSION_KEY: &str = "cas_gateway";
+
+/// Metadata stored in the session along with the logged in `CasUser`
+///
+/// Timestamps are seconds since the UNIX epoch.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct CasAuthentication {
+    authenticated_at: u64,
+    last_activity_at: u64,
+}
+
+impl CasAuthentication {
+    // ################################################################################
+    // Constructor
+    // ################################################################################
+    pub fn new(authenticated_at: u64) -> Self {
+        CasAuthentication {
+            authenticated_at,
+            last_activity_at: authenticated_at,
+        }
+    }
+
+    // ################################################################################
+    // Getters
+    // ################################################################################
+    /// When the user logged in
+    pub fn authenticated_at(&self) -> u64 {
+        self.authenticated_at
+    }
+
+    /// Last request of the user
+    pub fn last_activity_at(&self) -> u64 {
+        self.last_activity_at
+    }
+
+    // ################################################################################
+    // Public functions
+    // ################################################################################
+    /// Whether the session outlived `max_lifetime` (absolute timeout) or was idle
+    /// for longer than `idle_timeout`
+    pub fn is_expired(
+        &self,
+        now: u64,
+        max_lifetime: Option<Duration>,
+        idle_timeout: Option<Duration>,
+    ) -> bool {
+        let outlived = |since: u64, limit: Option<Duration>| match limit {
+            Some(limit) => now.saturating_sub(since) > limit.as_secs(),
+            None => false,
+        };
+        outlived(self.authenticated_at, max_lifetime) || outlived(self.last_activity_at, idle_timeout)
+    }
+}
+
+/// Seconds since the UNIX epoch
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+/// Store the logged in user in the session
+pub(crate) fn store_cas_user(session: &Session, cas_user: CasUser) -> Result<(), Error> {
+    // Resolved again for the new user
+    session.remove(CAS_PRINCIPAL_SESSION_KEY);
+    session.set(CAS_USER_SESSION_KEY, cas_user)?;
+    session.set(CAS_AUTHENTICATION_SESSION_KEY, CasAuthentication::new(now()))
+}
+
+/// Remove the logged in user from the session
+pub(crate) fn remove_cas_user(session: &Session) {
+    session.remove(CAS_USER_SESSION_KEY);
+    session.remove(CAS_AUTHENTICATION_SESSION_KEY);
+    session.remove(CAS_PRINCIPAL_SESSION_KEY);
+}
+
+/// Whether the principal of the logged in user was resolved
+pub(crate) fn has_principal(session: &Session) -> bool {
+    matches!(session.get::<IgnoredAny>(CAS_PRINCIPAL_SESSION_KEY), Ok(Some(_)))
+}
+
+/// Store the principal resolved from the logged in user
+pub(crate) fn store_principal(session: &Session, principal: serde_json::Value) -> Result<(), Error> {
+    session.set(CAS_PRINCIPAL_SESSION_KEY, principal)
+}
+
+/// Drop everything but `kept_keys` from the session before logging a user in, so
+/// that nothing set before the login outlives it
+pub(crate) fn clear_for_login(session: &Session, kept_keys: &[String]) {
+    let kept: Vec<(&String, serde_json::Value)> = kept_keys
+        .iter()
+        .filter_map(|key| match session.get::<serde_json::Value>(key) {
+            Ok(Some(value)) => Some((key, value)),
+            _ => None,
+        })
+        .collect();
+    session.clear();
+    for (key, value) in kept {
+        if let Err(err) = session.set(key, value) {
+            error!("Error while saving {} in session! Error: {}", key, err);
+        }
+    }
+}
+
+/// Id binding the tickets validated by the session to it, if it started a login
+pub(crate) fn login_binding(session: &Session) -> Option<String> {
+    session.get::<String>(LOGIN_BINDING_SESSION_KEY).unwrap_or(None)
+}
+
+/// Id binding the tickets validated by the session to it, created if needed
+pub(crate) fn ensure_login_binding(session: &Session) {
+    if login_binding(session).is_none() {
+        let binding = crate::return_url::random_id();
+        if let Err(err) = session.set(LOGIN_BINDING_SESSION_KEY, binding) {
+            error!("Error while saving cas_login_binding in session! Error: {}", err);
+        }
+    }
+}
+
+/// Record a ticket validation failure, returning the count of consecutive ones
+pub(crate) fn record_validation_failure(session: &Session) -> u32 {
+    let failures = session
+        .get::<u32>(VALIDATION_FAILURES_SESSION_KEY)
+        .unwrap_or(None)
+        .unwrap_or(0)
+        + 1;
+    if let Err(err) = session.set(VALIDATION_FAILURES_SESSION_KEY, failures) {
+        error!("Error while saving cas_validation_failures in session! Error: {}", err);
+    }
+    failures
+}
+
+pub(crate) fn reset_validation_failures(session: &Session) {
+    session.remove(VALIDATION_FAILURES_SESSION_KEY);
+}
+
+/// Record that a `gateway` login was started from the session
+pub(crate) fn start_gateway(session: &Session) {
+    if let Err(err) = session.set(GATEWAY_SESSION_KEY, true) {
+        error!("Error while saving cas_gateway in session! Error: {}", err);
+    }
+}
+
+/// Whether a `gateway` login was started from the session. If the user is still
+/// not logged in, they came back without single sign-on session.
+pub(crate) fn gateway_started(session: &Session) -> bool {
+    session.get::<bool>(GATEWAY_SESSION_KEY).unwrap_or(None).unwrap_or(false)
+}
+
+pub(crate) fn end_gateway(session: &Session) {
+    session.remove(GATEWAY_SESSION_KEY);
+}
+
+/// Record that a login bound to `nonce` was started from the session
+pub(crate) fn push_login_nonce(session: &Session, nonce: &str) {
+    push_pending_login(session, LOGIN_NONCES_SESSION_KEY, nonce);
+}
+
+/// Whether a login bound to `nonce` was started from the session. A nonce can
+/// only be taken once.
+pub(crate) fn take_login_nonce(session: &Session, nonce: &str) -> bool {
+    take_pending_login(session, LOGIN_NONCES_SESSION_KEY, nonce)
+}
+
+/// Record that the login identified by `login_id` (its nonce or state) asked CAS
+/// for the credentials of the user again
+pub(crate) fn push_renewed_login(session: &Session, login_id: &str) {
+    push_pending_login(session, RENEWED_LOGINS_SESSION_KEY, login_id);
+}
+
+/// Whether the login identified by `login_id` asked CAS for the credentials of
+/// the user again. A login can only be taken once.
+pub(crate) fn take_renewed_login(session: &Session, login_id: &str) -> bool {
+    take_pending_login(session, RENEWED_LOGINS_SESSION_KEY, login_id)
+}
+
+fn push_pending_login(session: &Session, key: &str, login_id: &str) {
+    let mut login_ids = session
+        .get::<Vec<String>>(key)
+        .unwrap_or(None)
+        .unwrap_or_default();
+    login_ids.push(login_id.to_string());
+    if login_ids.len() > MAX_PENDING_LOGINS {
+        login_ids.remove(0);
+    }
+    if let Err(err) = session.set(key, login_ids) {
+        error!("Error while saving {} in session! Error: {}", key, err);
+    }
+}
+
+fn take_pending_login(session: &Session, key: &str, login_id: &str) -> bool {
+    let mut login_ids = match session.get::<Vec<String>>(key) {
+        Ok(Some(login_ids)) => login_ids,
+        _ => return false,
+    };
+    let count = login_ids.len();
+    login_ids.retain(|pending| pending != login_id);
+    if login_ids.len() == count {
+        return false;
+    }
+    match login_ids.is_empty() {
+        true => session.remove(key),
+        false => {
+            if let Err(err) = session.set(key, login_ids) {
+                error!("Error while saving {} in session! Error: {}", key, err);
+            }
+        }
+    };
+    true
+}
+
+/// Log the user out if the session expired, record the activity otherwise, unless
+/// recorded recently.
+/// Whether the user was logged out.
+pub(crate) fn enforce_session_timeouts(
+    session: &Session,
+    max_lifetime: Option<Duration>,
+    idle_timeout: Option<Duration>,
+) -> bool {
+    if max_lifetime.is_none() && idle_timeout.is_none() {
+        return false;
+    }
+    if let Ok(None) = session.get::<CasUser>(CAS_USER_SESSION_KEY) {
+        return false;
+    }
+    let now = now();
+    match session.get::<CasAuthentication>(CAS_AUTHENTICATION_SESSION_KEY) {
+        Ok(Some(authentication)) if !authentication.is_expired(now, max_lifetime, idle_timeout) => {
+            if now.saturating_sub(authentication.last_activity_at)
+                >= activity_recording_interval(idle_timeout)
+            {
+                let authentication = CasAuthentication {
+                    last_activity_at: now,
+                    ..authentication
+                };
+                if let Err(err) = session.set(CAS_AUTHENTICATION_SESSION_KEY, authentication) {
+                    error!("Error while saving cas_authentication in session! Error: {}", err);
+                }
+            }
+            false
+        }
+        // Expired, or logged in before timeouts were recorded
+        _ => {
+            info!("CAS session expired");
+            remove_cas_user(session);
+            true
+        }
+    }
+}
+
+// Seconds between two recordings of the activity, sparing most requests a session
+// write (and with cookie sessions, a `Set-Cookie`): a tenth of the idle timeout, at
+// least a minute, yet at most half the idle timeout
+fn activity_recording_interval(idle_timeout: Option<Duration>) -> u64 {
+    match idle_timeout {
+        Some(idle_timeout) => (idle_timeout.as_secs() / 10).max(60).min(idle_timeout.as_secs() / 2),
+        None => 60,
+    }
+}
+
+/// Logged in user of a request: placed in the `extensions` by the middleware, or
+/// read from the session unless it expired. Unlike the middleware, it neither
+/// records the activity nor logs expired users out.
+pub(crate) fn cas_user_for_request(
+    extensions: &Extensions,
+    session: &Session,
+    max_lifetime: Option<Duration>,
+    idle_timeout: Option<Duration>,
+) -> Option<CasUser> {
+    if let Some(cas_user) = extensions.get::<CasUser>() {
+        return Some(cas_user.clone());
+    }
+    let cas_user = session.get::<CasUser>(CAS_USER_SESSION_KEY).unwrap_or(None)?;
+    if max_lifetime.is_none() && idle_timeout.is_none() {
+        return Some(cas_user);
+    }
+    match session.get::<CasAuthentication>(CAS_AUTHENTICATION_SESSION_KEY) {
+        Ok(Some(authentication)) if !authentication.is_expired(now(), max_lifetime, idle_timeout) => {
+            Some(cas_user)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_session::UserSession;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn clear_for_login_should_keep_listed_keys() {
+        let req = TestRequest::default().to_http_request();
+        let session = req.get_session();
+        session.set("locale", "fr").unwrap();
+        session.set("cart", vec![1, 2]).unwrap();
+        session.set(CAS_USER_SESSION_KEY, CasUser::new("attacker", None)).unwrap();
+        clear_for_login(&session, &["cart".to_string(), "missing".to_string()]);
+        assert_eq!(session.get::<Vec<u32>>("cart").unwrap(), Some(vec![1, 2]));
+        assert_eq!(session.get::<String>("locale").unwrap(), None);
+        assert_eq!(session.get::<CasUser>(CAS_USER_SESSION_KEY).unwrap(), None);
+        assert_eq!(session.get::<String>("missing").unwrap(), None);
+    }
+
+    #[test]
+    fn login_nonces_should_be_taken_once() {
+        let req = TestRequest::default().to_http_request();
+        let session = req.get_session();
+        assert!(!take_login_nonce(&session, "n0"));
+        for nonce in &["n0", "n1", "n2", "n3", "n4", "n5"] {
+            push_login_nonce(&session, nonce);
+        }
+        // Only the most recent logins are kept
+        assert!(!take_login_nonce(&session, "n0"));
+        assert!(take_login_nonce(&session, "n3"));
+        assert!(!take_login_nonce(&session, "n3"));
+        assert!(take_login_nonce(&session, "n5"));
+    }
+
+    #[test]
+    fn renewed_logins_should_be_taken_once() {
+        let req = TestRequest::default().to_http_request();
+        let session = req.get_session();
+        push_login_nonce(&session, "n0");
+        push_renewed_login(&session, "n1");
+        assert!(!take_renewed_login(&session, "n0"));
+        assert!(take_renewed_login(&session, "n1"));
+        assert!(!take_renewed_login(&session, "n1"));
+        assert!(take_login_nonce(&session, "n0"));
+    }
+
+    #[test]
+    fn validation_failures_should_be_counted() {
+        let req = TestRequest::default().to_http_request();
+        let session = req.get_session();
+        assert_eq!(record_validation_failure(&session), 1);
+        assert_eq!(record_validation_failure(&session), 2);
+        reset_validation_failures(&session);
+        assert_eq!(record_validation_failure(&session), 1);
+    }
+
+    #[test]
+    fn is_expired_should_check_max_lifetime() {
+        let authentication = CasAuthentication::new(1_000);
+        let eight_hours = Some(Duration::from_secs(8 * 3_600));
+        assert!(!authentication.is_expired(1_000 + 8 * 3_600, eight_hours, None));
+        assert!(authentication.is_expired(1_001 + 8 * 3_600, eight_hours, None));
+        assert!(!authentication.is_expired(1_000_000, None, None));
+    }
+
+    #[test]
+    fn is_expired_should_check_idle_timeout() {
+        let authentication = CasAuthentication {
+            authenticated_at: 1_000,
+            last_activity_at: 2_000,
+        };
+        let thirty_minutes = Some(Duration::from_secs(30 * 60));
+        assert!(!authentication.is_expired(2_000 + 30 * 60, None, thirty_minutes));
+        assert!(authentication.is_expired(2_001 + 30 * 60, None, thirty_minutes));
+    }
+
+    #[test]
+    fn activity_should_be_recorded_sparingly() {
+        assert_eq!(activity_recording_interval(Some(Duration::from_secs(30 * 60))), 180);
+        assert_eq!(activity_recording_interval(Some(Duration::from_secs(5 * 60))), 60);
+        assert_eq!(activity_recording_interval(Some(Duration::from_secs(60))), 30);
+        assert_eq!(activity_recording_interval(None), 60);
+    }
+}
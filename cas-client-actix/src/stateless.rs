@@ -0,0 +1,390 @@
+use crate::ticket_cache::{ticket_fingerprint, TicketCache};
+use cas_client_core::{CasClient, CasValidationError, ProxyValidation};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use actix_service::{Service, Transform};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::{header, StatusCode};
+use actix_web::{Error, HttpMessage, HttpResponse};
+use futures::future::{ok, Either, Ready};
+
+/// Authentication scheme of the `Authorization` header carrying a ticket
+const AUTHORIZATION_SCHEME: &str = "CAS";
+// Binding of the cached validations: the tickets are not bound to a session
+const STATELESS_BINDING: &str = "";
+/// Failure code of the tickets whose proxy chain is not allowed
+const PROXY_CHAIN_NOT_ALLOWED: &str = "PROXY_CHAIN_NOT_ALLOWED";
+
+/// Middleware authenticating the requests of other services with the CAS ticket
+/// they send, without session nor redirect
+///
+/// The ticket is read from `Authorization: CAS <ticket>`, or from the header set
+/// with `set_ticket_header`, and validated with `proxyValidate` for the service
+/// url the callers request their proxy tickets for. The user is then placed in
+/// the request extensions, for the `AuthenticatedCasUser` and `MaybeCasUser`
+/// extractors, along with the `ProxyValidation` holding the proxy chain.
+///
+/// ```
+/// use actix_web::{web, App, HttpResponse};
+/// use cas_client_actix::{AuthenticatedCasUser, StatelessCasClient};
+/// use cas_client_core::CasClient;
+///
+/// let cas_client = CasClient::new("https://cas.example.org/cas").unwrap();
+/// let stateless = StatelessCasClient::new(cas_client, "https://api.example.org");
+///
+/// let app = App::new().service(
+///     web::scope("/api")
+///         .wrap(stateless)
+///         .route("/me", web::get().to(|user: AuthenticatedCasUser| {
+///             HttpResponse::Ok().body(user.username().to_string())
+///         })),
+/// );
+/// ```
+#[derive(Clone, Debug)]
+pub struct StatelessCasClient {
+    cas_client: CasClient,
+    service_url: String,
+    ticket_header: Option<String>,
+    proxy_tickets: bool,
+    required: bool,
+    allowed_proxy_chains: Option<Vec<Vec<String>>>,
+    ticket_cache: Option<Arc<TicketCache<ProxyValidation>>>,
+}
+
+impl StatelessCasClient {
+    // ################################################################################
+    // Constructor
+    // ################################################################################
+    pub fn new(cas_client: CasClient, service_url: &str) -> Self {
+        StatelessCasClient {
+            cas_client,
+            service_url: service_url.to_string(),
+            ticket_header: None,
+            proxy_tickets: true,
+            required: true,
+            allowed_proxy_chains: None,
+            ticket_cache: Some(Arc::new(TicketCache::successes_only(Duration::from_secs(60)))),
+        }
+    }
+
+    // ################################################################################
+    // Getters / Setters
+    // ################################################################################
+    /// Service url the tickets are issued for
+    pub fn service_url(&self) -> &str {
+        &self.service_url
+    }
+
+    pub fn ticket_header(&self) -> Option<&str> {
+        self.ticket_header.as_deref()
+    }
+
+    /// Header carrying the bare ticket, read when there is no `Authorization: CAS`
+    pub fn set_ticket_header(&mut self, ticket_header: &str) -> &mut Self {
+        self.ticket_header = Some(ticket_header.to_string());
+        self
+    }
+
+    pub fn proxy_tickets(&self) -> bool {
+        self.proxy_tickets
+    }
+
+    /// Validate with `proxyValidate`, accepting proxy and service tickets, or
+    /// with `serviceValidate`, accepting service tickets only. `true` by default.
+    pub fn set_proxy_tickets(&mut self, proxy_tickets: bool) -> &mut Self {
+        self.proxy_tickets = proxy_tickets;
+        self
+    }
+
+    pub fn required(&self) -> bool {
+        self.required
+    }
+
+    /// Reject the requests without a ticket with a 401. `true` by default;
+    /// otherwise they go through without user.
+    pub fn set_required(&mut self, required: bool) -> &mut Self {
+        self.required = required;
+        self
+    }
+
+    pub fn allowed_proxy_chains(&self) -> Option<&[Vec<String>]> {
+        self.allowed_proxy_chains.as_deref()
+    }
+
+    /// Proxy chains whose tickets are accepted, each listing its proxies the most
+    /// recent first as CAS does. `None`, the default, accepts any chain. Service
+    /// tickets, without proxies, are always accepted.
+    pub fn set_allowed_proxy_chains(&mut self, allowed_proxy_chains: Option<Vec<Vec<String>>>) -> &mut Self {
+        self.allowed_proxy_chains = allowed_proxy_chains;
+        self
+    }
+
+    pub fn ticket_cache_ttl(&self) -> Option<Duration> {
+        self.ticket_cache.as_ref().map(|cache| cache.ttl())
+    }
+
+    /// How long a validated ticket is accepted again without asking CAS, 60
+    /// seconds by default. `None` disables the cache. Rejected tickets are not
+    /// cached.
+    pub fn set_ticket_cache_ttl(&mut self, ttl: Option<Duration>) -> &mut Self {
+        self.ticket_cache = ttl.map(|ttl| Arc::new(TicketCache::successes_only(ttl)));
+        self
+    }
+}
+
+impl<S, B> Transform<S> for StatelessCasClient
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = StatelessCasClientMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(StatelessCasClientMiddleware {
+            service,
+            client: self.clone(),
+        })
+    }
+}
+
+pub struct StatelessCasClientMiddleware<S> {
+    service: S,
+    client: StatelessCasClient,
+}
+
+impl<S> StatelessCasClientMiddleware<S> {
+    fn ticket(&self, req: &ServiceRequest) -> Option<String> {
+        let header = |name: &str| {
+            req.headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(|value| value.trim().to_string())
+        };
+        let from_authorization = header(header::AUTHORIZATION.as_str()).and_then(|value| {
+            let (scheme, ticket) = value.split_once(' ')?;
+            match scheme.eq_ignore_ascii_case(AUTHORIZATION_SCHEME) {
+                true => Some(ticket.trim().to_string()),
+                false => None,
+            }
+        });
+        from_authorization
+            .or_else(|| self.client.ticket_header.as_deref().and_then(header))
+            .filter(|ticket| !ticket.is_empty())
+    }
+
+    fn validate(&self, ticket: &str) -> Result<ProxyValidation, CasValidationError> {
+        let cas_client = &self.client.cas_client;
+        let service_url = &self.client.service_url;
+        let validate = || match self.client.proxy_tickets {
+            true => cas_client.validate_proxy_ticket(ticket, service_url),
+            false => cas_client
+                .validate_ticket(ticket, service_url)
+                .map(|cas_user| ProxyValidation::new(cas_user, Vec::new())),
+        };
+        let validation = match &self.client.ticket_cache {
+            Some(cache) => cache.validate(ticket, STATELESS_BINDING, validate),
+            None => validate(),
+        }?;
+        let proxies = validation.proxies();
+        match &self.client.allowed_proxy_chains {
+            Some(chains) if !proxies.is_empty() && !chains.iter().any(|chain| chain.as_slice() == proxies) => {
+                warn!("Rejected ticket {} proxied by {:?}", ticket_fingerprint(ticket), proxies);
+                Err(CasValidationError::Failure {
+                    code: PROXY_CHAIN_NOT_ALLOWED.to_string(),
+                    description: format!("Proxy chain {:?} is not allowed", proxies),
+                })
+            }
+            _ => Ok(validation),
+        }
+    }
+}
+
+/// 401 of the requests without a valid ticket
+fn unauthorized_response(error: &str, code: Option<&str>) -> HttpResponse {
+    HttpResponse::build(StatusCode::UNAUTHORIZED)
+        .header(header::WWW_AUTHENTICATE, "CAS realm=\"CAS\"")
+        .json(serde_json::json!({
+            "error": error,
+            "code": code,
+        }))
+}
+
+impl<S, B> Service for StatelessCasClientMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Either<S::Future, Ready<Result<Self::Response, Self::Error>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let ticket = match self.ticket(&req) {
+            Some(ticket) => ticket,
+            None if self.client.required => {
+                let resp = unauthorized_response("missing_ticket", None);
+                return Either::Right(ok(req.into_response(resp.into_body())));
+            }
+            None => return Either::Left(self.service.call(req)),
+        };
+        match self.validate(&ticket) {
+            Ok(validation) => {
+                req.extensions_mut().insert(validation.user().clone());
+                req.extensions_mut().insert(validation);
+                Either::Left(self.service.call(req))
+            }
+            Err(err) => {
+                let resp = match err.code() {
+                    Some(code) => unauthorized_response("invalid_ticket", Some(code)),
+                    None => HttpResponse::ServiceUnavailable().finish(),
+                };
+                Either::Right(ok(req.into_response(resp.into_body())))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::extractors::{AuthenticatedCasUser, MaybeCasUser};
+    use actix_web::test::{self, TestRequest};
+    use actix_web::{web, App, HttpRequest};
+    use cas_client_core::HttpTransport;
+    use cas_client_testing::{MockCasServer, MockUser};
+    use url::Url;
+
+    const SERVICE_URL: &str = "https://api.example.org";
+
+    async fn me(user: AuthenticatedCasUser) -> HttpResponse {
+        HttpResponse::Ok().body(user.username().to_string())
+    }
+
+    async fn maybe_me(user: MaybeCasUser) -> HttpResponse {
+        let username = user.as_ref().map(|user| user.username().to_string());
+        HttpResponse::Ok().body(username.unwrap_or_else(|| "guest".to_string()))
+    }
+
+    /// Proxy ticket for `SERVICE_URL`, as the calling service would get it
+    fn proxy_ticket(cas: &MockCasServer, username: &str) -> String {
+        let pgt = cas.issue_proxy_granting_ticket(username, "https://caller.example.org/pgt");
+        let url = Url::parse_with_params(
+            &format!("{}/proxy", cas.url()),
+            &[("pgt", pgt.as_str()), ("targetService", SERVICE_URL)],
+        )
+        .unwrap();
+        let body = HttpTransport::new().get(url.as_str()).unwrap().body;
+        let start = body.find("PT-").unwrap();
+        body[start..body[start..].find('<').unwrap() + start].to_string()
+    }
+
+    #[actix_rt::test]
+    async fn should_authenticate_with_proxy_ticket() {
+        let cas = MockCasServer::start();
+        cas.add_user(MockUser::new("batch", "secret"));
+        let client = StatelessCasClient::new(CasClient::new(&cas.url()).unwrap(), SERVICE_URL);
+        let mut app = test::init_service(App::new().wrap(client).route("/me", web::get().to(me))).await;
+
+        let ticket = proxy_ticket(&cas, "batch");
+        for _ in 0..2 {
+            let req = TestRequest::get()
+                .uri("/me")
+                .header("Authorization", format!("CAS {}", ticket))
+                .to_request();
+            assert_eq!(test::read_response(&mut app, req).await, "batch");
+        }
+        // The second request reused the first validation
+        assert_eq!(
+            cas.received_requests(),
+            vec!["GET /cas/proxy".to_string(), "GET /cas/proxyValidate".to_string()]
+        );
+
+        let req = TestRequest::get().uri("/me").to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        // Rejected tickets are not cached: each request asks CAS
+        for _ in 0..2 {
+            let req = TestRequest::get()
+                .uri("/me")
+                .header("Authorization", "CAS PT-unknown")
+                .to_request();
+            let resp = test::call_service(&mut app, req).await;
+            assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+            let body: serde_json::Value = test::read_body_json(resp).await;
+            assert_eq!(body["code"], "INVALID_TICKET");
+        }
+        let validations = cas.received_requests().iter().filter(|r| r.ends_with("proxyValidate")).count();
+        assert_eq!(validations, 3);
+    }
+
+    async fn proxies(req: HttpRequest) -> HttpResponse {
+        let extensions = req.extensions();
+        let validation = extensions.get::<ProxyValidation>().unwrap();
+        HttpResponse::Ok().body(validation.proxies().join(" "))
+    }
+
+    #[actix_rt::test]
+    async fn should_enforce_allowed_proxy_chains() {
+        let cas = MockCasServer::start();
+        cas.add_user(MockUser::new("batch", "secret"));
+        for (allowed_chain, expected_status) in &[
+            ("https://caller.example.org/pgt", StatusCode::OK),
+            ("https://other.example.org/pgt", StatusCode::UNAUTHORIZED),
+        ] {
+            let mut client = StatelessCasClient::new(CasClient::new(&cas.url()).unwrap(), SERVICE_URL);
+            client.set_allowed_proxy_chains(Some(vec![vec![allowed_chain.to_string()]]));
+            let mut app =
+                test::init_service(App::new().wrap(client).route("/proxies", web::get().to(proxies))).await;
+
+            let req = TestRequest::get()
+                .uri("/proxies")
+                .header("Authorization", format!("CAS {}", proxy_ticket(&cas, "batch")))
+                .to_request();
+            let resp = test::call_service(&mut app, req).await;
+            assert_eq!(resp.status(), *expected_status, "{}", allowed_chain);
+            match *expected_status {
+                StatusCode::OK => {
+                    assert_eq!(test::read_body(resp).await, "https://caller.example.org/pgt");
+                }
+                _ => {
+                    let body: serde_json::Value = test::read_body_json(resp).await;
+                    assert_eq!(body["code"], PROXY_CHAIN_NOT_ALLOWED);
+                }
+            }
+        }
+    }
+
+    #[actix_rt::test]
+    async fn should_read_configured_header() {
+        let cas = MockCasServer::start();
+        cas.add_user(MockUser::new("batch", "secret"));
+        let mut client = StatelessCasClient::new(CasClient::new(&cas.url()).unwrap(), SERVICE_URL);
+        client.set_ticket_header("X-CAS-Ticket").set_required(false);
+        let mut app =
+            test::init_service(App::new().wrap(client).route("/me", web::get().to(maybe_me))).await;
+
+        let req = TestRequest::get()
+            .uri("/me")
+            .header("X-CAS-Ticket", proxy_ticket(&cas, "batch"))
+            .to_request();
+        assert_eq!(test::read_response(&mut app, req).await, "batch");
+
+        let req = TestRequest::get().uri("/me").to_request();
+        assert_eq!(test::read_response(&mut app, req).await, "guest");
+    }
+}
@@ -0,0 +1,328 @@
+//! Test utilities for applications using the CAS middleware
+//!
+//! - [`request_with_user`](fn.request_with_user.html) and
+//!   [`service_request_with_user`](fn.service_request_with_user.html) build requests
+//!   whose session already holds a logged in `CasUser`.
+//! - [`WithCasUser`](struct.WithCasUser.html) logs every request of a test `App` in.
+//! - [`assert_cas_redirect`](fn.assert_cas_redirect.html) checks that a response
+//!   redirects to CAS with a given service url.
+use crate::session::store_cas_user;
+use crate::{url_without_cas_params, ActixCasClient};
+use cas_client_core::CasUser;
+use std::collections::HashMap;
+use std::task::{Context, Poll};
+
+use actix_service::{Service, Transform};
+use actix_session::UserSession;
+use actix_web::client::ClientResponse;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::{header, HeaderMap, StatusCode};
+use actix_web::test::TestRequest;
+use actix_web::{Error, HttpRequest, HttpResponse};
+use futures::future::{ok, Ready};
+use url::Url;
+
+/// Build a `CasUser` from a username and attributes
+///
+/// # Examples
+/// ```
+/// use cas_client_actix::testing::cas_user;
+///
+/// let user = cas_user("jdoe", &[("mail", "jdoe@example.org")]);
+/// assert_eq!(user.username(), "jdoe");
+/// assert_eq!(user.attributes()["mail"], "jdoe@example.org");
+/// ```
+pub fn cas_user(username: &str, attributes: &[(&str, &str)]) -> CasUser {
+    let attributes: HashMap<String, String> = attributes
+        .iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect();
+    CasUser::new(username, Some(attributes))
+}
+
+/// Log `cas_user` in the session of `req`, as the CAS middleware does after a
+/// successful ticket validation
+pub fn login<R: UserSession>(req: &R, cas_user: CasUser) {
+    store_cas_user(&req.get_session(), cas_user).expect("Cannot store CAS user in session");
+}
+
+/// Build an `HttpRequest` whose session holds `cas_user`
+///
+/// # Examples
+/// ```
+/// use actix_session::UserSession;
+/// use actix_web::test::TestRequest;
+/// use cas_client_actix::testing::{cas_user, request_with_user};
+/// use cas_client_actix::CAS_USER_SESSION_KEY;
+/// use cas_client_core::CasUser;
+///
+/// let req = request_with_user(TestRequest::get().uri("/user"), cas_user("jdoe", &[]));
+/// let user = req.get_session().get::<CasUser>(CAS_USER_SESSION_KEY).unwrap();
+/// assert_eq!(user.unwrap().username(), "jdoe");
+/// ```
+pub fn request_with_user(req: TestRequest, cas_user: CasUser) -> HttpRequest {
+    let req = req.to_http_request();
+    login(&req, cas_user);
+    req
+}
+
+/// Build a `ServiceRequest` whose session holds `cas_user`, e.g. to call a
+/// middleware directly
+pub fn service_request_with_user(req: TestRequest, cas_user: CasUser) -> ServiceRequest {
+    let req = req.to_srv_request();
+    login(&req, cas_user);
+    req
+}
+
+/// Middleware logging `cas_user` in for every request of a test `App`
+///
+/// Handlers then find the user in the session without any CAS round-trip. Wrap
+/// it inside the session middleware (if any) and outside `ActixCasClient`:
+///
+/// ```
+/// use actix_session::CookieSession;
+/// use actix_web::{test, web, App, HttpResponse};
+/// use cas_client_actix::testing::{cas_user, WithCasUser};
+///
+/// # actix_rt::System::new("doc").block_on(async {
+/// let mut app = test::init_service(
+///     App::new()
+///         .wrap(WithCasUser::new(cas_user("jdoe", &[])))
+///         .wrap(CookieSession::signed(&[0; 32]).secure(false))
+///         .route("/", web::get().to(|| HttpResponse::Ok())),
+/// )
+/// .await;
+/// let resp = test::call_service(&mut app, test::TestRequest::get().uri("/").to_request()).await;
+/// assert!(resp.status().is_success());
+/// # });
+/// ```
+#[derive(Clone, Debug)]
+pub struct WithCasUser {
+    cas_user: CasUser,
+}
+
+impl WithCasUser {
+    pub fn new(cas_user: CasUser) -> Self {
+        WithCasUser { cas_user }
+    }
+}
+
+impl<S, B> Transform<S> for WithCasUser
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = WithCasUserMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(WithCasUserMiddleware {
+            service,
+            cas_user: self.cas_user.clone(),
+        })
+    }
+}
+
+pub struct WithCasUserMiddleware<S> {
+    service: S,
+    cas_user: CasUser,
+}
+
+impl<S, B> Service for WithCasUserMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        login(&req, self.cas_user.clone());
+        self.service.call(req)
+    }
+}
+
+/// Responses that `assert_cas_redirect` can inspect
+pub trait TestResponse {
+    fn status(&self) -> StatusCode;
+    fn headers(&self) -> &HeaderMap;
+}
+
+impl<B> TestResponse for ServiceResponse<B> {
+    fn status(&self) -> StatusCode {
+        self.status()
+    }
+
+    fn headers(&self) -> &HeaderMap {
+        self.headers()
+    }
+}
+
+impl<B> TestResponse for HttpResponse<B> {
+    fn status(&self) -> StatusCode {
+        self.status()
+    }
+
+    fn headers(&self) -> &HeaderMap {
+        self.headers()
+    }
+}
+
+impl<S> TestResponse for ClientResponse<S> {
+    fn status(&self) -> StatusCode {
+        self.status()
+    }
+
+    fn headers(&self) -> &HeaderMap {
+        self.headers()
+    }
+}
+
+/// Service url of a response redirecting to the CAS login page of `cas_client`,
+/// `None` if the response is not such a redirect
+///
+/// The parameters binding the login to the browser (`cas_nonce`, `cas_state`,
+/// `cas_return`) are removed.
+pub fn cas_redirect_service<R: TestResponse>(
+    resp: &R,
+    cas_client: &ActixCasClient,
+) -> Option<String> {
+    cas_login_service(resp, cas_client).map(|service| url_without_cas_params(&service))
+}
+
+/// Service url sent to CAS by a response redirecting to its login page
+pub(crate) fn cas_login_service<R: TestResponse>(
+    resp: &R,
+    cas_client: &ActixCasClient,
+) -> Option<String> {
+    if !resp.status().is_redirection() {
+        return None;
+    }
+    let location = resp.headers().get(header::LOCATION)?.to_str().ok()?;
+    let location = Url::parse(location).ok()?;
+    let login_url = format!(
+        "{}{}",
+        cas_client.cas_client.cas_base_url(),
+        cas_client.cas_client.login_prefix()
+    );
+    if location[..url::Position::AfterPath] != login_url {
+        return None;
+    }
+    location
+        .query_pairs()
+        .find(|(key, _)| key == "service")
+        .map(|(_, value)| value.into_owned())
+}
+
+/// Assert that `resp` redirects to the CAS login page of `cas_client` with
+/// `service_url` as service
+///
+/// # Panics
+/// Panics if the response is not a redirect to CAS, or if the service url differs.
+pub fn assert_cas_redirect<R: TestResponse>(resp: &R, cas_client: &ActixCasClient, service_url: &str) {
+    let location = resp
+        .headers()
+        .get(header::LOCATION)
+        .and_then(|location| location.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+    match cas_redirect_service(resp, cas_client) {
+        Some(service) => assert_eq!(
+            service, service_url,
+            "CAS redirect has an unexpected service url (location: {})",
+            location
+        ),
+        None => panic!(
+            "Expected a redirect to CAS, got status {} (location: {:?})",
+            resp.status(),
+            location
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CAS_USER_SESSION_KEY;
+    use actix_web::{test, web, App};
+    use cas_client_core::{CasClient, NoAuthBehavior};
+
+    const CAS_URL: &str = "https://cas.example.org/cas";
+
+    fn actix_cas_client() -> ActixCasClient {
+        let mut cas_client = CasClient::new(CAS_URL).unwrap();
+        cas_client.set_no_auth_behavior(NoAuthBehavior::Authenticate);
+        let mut actix_cas_client = ActixCasClient::new(cas_client, None, None);
+        actix_cas_client.set_server_is_service(true);
+        actix_cas_client
+    }
+
+    async fn username(req: HttpRequest) -> HttpResponse {
+        match req.get_session().get::<CasUser>(CAS_USER_SESSION_KEY) {
+            Ok(Some(user)) => HttpResponse::Ok().body(user.username().to_string()),
+            _ => HttpResponse::Unauthorized().finish(),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn request_with_user_should_log_user_in() {
+        let req = request_with_user(TestRequest::get(), cas_user("jdoe", &[("mail", "j@x")]));
+        let user = req.get_session().get::<CasUser>(CAS_USER_SESSION_KEY).unwrap();
+        assert_eq!(user, Some(cas_user("jdoe", &[("mail", "j@x")])));
+
+        let req = service_request_with_user(TestRequest::get(), cas_user("jdoe", &[]));
+        let user = req.get_session().get::<CasUser>(CAS_USER_SESSION_KEY).unwrap();
+        assert_eq!(user, Some(cas_user("jdoe", &[])));
+    }
+
+    #[actix_rt::test]
+    async fn with_cas_user_should_pass_cas_middleware() {
+        let mut app = test::init_service(
+            App::new()
+                .wrap(actix_cas_client())
+                .wrap(WithCasUser::new(cas_user("jdoe", &[])))
+                .route("/user", web::get().to(username)),
+        )
+        .await;
+        let req = TestRequest::get().uri("/user").to_request();
+        let body = test::read_response(&mut app, req).await;
+        assert_eq!(body, "jdoe");
+    }
+
+    #[actix_rt::test]
+    async fn assert_cas_redirect_should_check_service_url() {
+        let cas_client = actix_cas_client();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(cas_client.clone())
+                .route("/user", web::get().to(username)),
+        )
+        .await;
+        let req = TestRequest::get().uri("/user?a=b").to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_cas_redirect(&resp, &cas_client, "http://localhost:8080/user?a=b");
+        assert_eq!(cas_redirect_service(&HttpResponse::Ok().finish(), &cas_client), None);
+    }
+
+    #[actix_rt::test]
+    #[should_panic(expected = "unexpected service url")]
+    async fn assert_cas_redirect_should_panic_on_other_service() {
+        let cas_client = actix_cas_client();
+        let resp = HttpResponse::TemporaryRedirect()
+            .header(header::LOCATION, cas_client.cas_client.login_url_for_service("https://a").unwrap())
+            .finish();
+        assert_cas_redirect(&resp, &cas_client, "https://b");
+    }
+}
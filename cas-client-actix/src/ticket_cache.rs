@@ -0,0 +1,303 @@
+use cas_client_core::{CasUser, CasValidationError};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+pub(crate) type Validation<T = CasUser> = Result<T, CasValidationError>;
+
+#[derive(Debug)]
+struct FlightState<T> {
+    result: Option<Validation<T>>,
+    expires_at: Option<Instant>,
+}
+
+/// Validation of a ticket, in flight or done
+#[derive(Debug)]
+struct Flight<T> {
+    // Login binding of the session that presented the ticket first
+    binding: String,
+    state: Mutex<FlightState<T>>,
+    done: Condvar,
+}
+
+impl<T: Clone> Flight<T> {
+    fn wait(&self) -> Validation<T> {
+        let mut state = self.state.lock().expect("Ticket cache lock poisoned");
+        loop {
+            if let Some(result) = &state.result {
+                return result.clone();
+            }
+            state = self.done.wait(state).expect("Ticket cache lock poisoned");
+        }
+    }
+
+    fn is_expired(&self, now: Instant) -> bool {
+        let state = self.state.lock().expect("Ticket cache lock poisoned");
+        matches!(state.expires_at, Some(expires_at) if expires_at <= now)
+    }
+}
+
+/// Results of the ticket validations of the last `ttl`, keyed by ticket hash
+///
+/// Browsers may load the service url twice (prefetch, double click, back
+/// navigation), and CAS only validates a ticket once. The repeated requests of the
+/// same session reuse the result of the first one, or wait for it if it is still
+/// in flight.
+#[derive(Debug)]
+pub(crate) struct TicketCache<T = CasUser> {
+    ttl: Duration,
+    keep_failures: bool,
+    flights: Mutex<HashMap<String, Arc<Flight<T>>>>,
+}
+
+impl<T: Clone> TicketCache<T> {
+    pub fn new(ttl: Duration) -> Self {
+        TicketCache {
+            ttl,
+            keep_failures: true,
+            flights: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Cache keeping the successful validations only: a rejected ticket is
+    /// validated again by the next request presenting it
+    pub fn successes_only(ttl: Duration) -> Self {
+        TicketCache {
+            keep_failures: false,
+            ..TicketCache::new(ttl)
+        }
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    fn flight(&self, key: &str) -> Option<Arc<Flight<T>>> {
+        let mut flights = self.flights.lock().expect("Ticket cache lock poisoned");
+        let now = Instant::now();
+        flights.retain(|_, flight| !flight.is_expired(now));
+        flights.get(key).cloned()
+    }
+
+    /// Result of the validation of `ticket` by the session bound to `binding`
+    pub fn reuse(&self, ticket: &str, binding: &str) -> Option<Validation<T>> {
+        let flight = self.flight(&ticket_hash(ticket))?;
+        match flight.binding == binding {
+            true => Some(flight.wait()),
+            false => None,
+        }
+    }
+
+    /// Validates `ticket` with `validate`, unless the session bound to `binding`
+    /// already did
+    pub fn validate<F>(&self, ticket: &str, binding: &str, validate: F) -> Validation<T>
+    where
+        F: FnOnce() -> Validation<T>,
+    {
+        let key = ticket_hash(ticket);
+        let flight = {
+            let mut flights = self.flights.lock().expect("Ticket cache lock poisoned");
+            let now = Instant::now();
+            flights.retain(|_, flight| !flight.is_expired(now));
+            match flights.get(&key) {
+                Some(flight) if flight.binding == binding => {
+                    let flight = flight.clone();
+                    drop(flights);
+                    return flight.wait();
+                }
+                // Another session presents the ticket: not shared with it
+                Some(_) => None,
+                None => {
+                    let flight = Arc::new(Flight {
+                        binding: binding.to_string(),
+                        state: Mutex::new(FlightState {
+                            result: None,
+                            expires_at: None,
+                        }),
+                        done: Condvar::new(),
+                    });
+                    flights.insert(key.clone(), flight.clone());
+                    Some(flight)
+                }
+            }
+        };
+        let guard = flight.map(|flight| FlightGuard {
+            cache: self,
+            key,
+            flight: Some(flight),
+        });
+        let result = validate();
+        if let Some(mut guard) = guard {
+            guard.complete(result.clone());
+        }
+        result
+    }
+}
+
+/// Completes the flight of a validation, even if the validation panics: the
+/// requests waiting for it then get an error rather than waiting forever
+struct FlightGuard<'c, T> {
+    cache: &'c TicketCache<T>,
+    key: String,
+    flight: Option<Arc<Flight<T>>>,
+}
+
+impl<T> FlightGuard<'_, T> {
+    fn complete(&mut self, result: Validation<T>) {
+        let flight = match self.flight.take() {
+            Some(flight) => flight,
+            None => return,
+        };
+        // Only the answers of CAS are kept: a new request retries when CAS
+        // could not be reached
+        let keep = match &result {
+            Ok(_) => true,
+            Err(err) => self.cache.keep_failures && err.code().is_some(),
+        };
+        // Never panics: it may run while unwinding
+        {
+            let mut state = flight.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            state.result = Some(result);
+            state.expires_at = Some(Instant::now() + self.cache.ttl);
+            flight.done.notify_all();
+        }
+        if !keep {
+            let mut flights = self.cache.flights.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            if matches!(flights.get(&self.key), Some(cached) if Arc::ptr_eq(cached, &flight)) {
+                flights.remove(&self.key);
+            }
+        }
+    }
+}
+
+impl<T> Drop for FlightGuard<'_, T> {
+    fn drop(&mut self) {
+        if self.flight.is_some() {
+            error!("Validation of a ticket aborted");
+            self.complete(Err(CasValidationError::Transport(
+                "Validation of the ticket aborted".to_string(),
+            )));
+        }
+    }
+}
+
+fn ticket_hash(ticket: &str) -> String {
+    format!("{:x}", Sha256::digest(ticket.as_bytes()))
+}
+
+/// Short hash of `ticket`, telling it apart in the logs without disclosing it
+pub(crate) fn ticket_fingerprint(ticket: &str) -> String {
+    ticket_hash(ticket)[..12].to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    #[test]
+    fn repeated_ticket_should_reuse_result() {
+        let cache = TicketCache::<CasUser>::new(Duration::from_secs(30));
+        let calls = AtomicUsize::new(0);
+        let validate = || {
+            calls.fetch_add(1, Ordering::SeqCst);
+            Ok(CasUser::new("jdoe", None))
+        };
+        assert!(cache.reuse("ST-1", "session-a").is_none());
+        assert_eq!(cache.validate("ST-1", "session-a", validate).unwrap().username(), "jdoe");
+        assert_eq!(cache.reuse("ST-1", "session-a").unwrap().unwrap().username(), "jdoe");
+        assert_eq!(cache.validate("ST-1", "session-a", validate).unwrap().username(), "jdoe");
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // Not shared with another session
+        assert!(cache.reuse("ST-1", "session-b").is_none());
+        cache.validate("ST-1", "session-b", validate).unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn results_should_expire() {
+        let cache = TicketCache::<CasUser>::new(Duration::from_secs(0));
+        cache.validate("ST-1", "session-a", || Ok(CasUser::new("jdoe", None))).unwrap();
+        assert!(cache.reuse("ST-1", "session-a").is_none());
+    }
+
+    #[test]
+    fn unavailable_cas_should_not_be_cached() {
+        let cache = TicketCache::<CasUser>::new(Duration::from_secs(30));
+        let result = cache.validate("ST-1", "session-a", || {
+            Err(CasValidationError::Transport("timeout".to_string()))
+        });
+        assert!(result.is_err());
+        assert!(cache.reuse("ST-1", "session-a").is_none());
+    }
+
+    #[test]
+    fn failures_should_be_cached_unless_successes_only() {
+        let failure = || {
+            Err(CasValidationError::Failure {
+                code: "INVALID_TICKET".to_string(),
+                description: "Ticket not recognized".to_string(),
+            })
+        };
+        let cache = TicketCache::<CasUser>::new(Duration::from_secs(30));
+        assert!(cache.validate("ST-1", "session-a", failure).is_err());
+        assert!(cache.reuse("ST-1", "session-a").unwrap().is_err());
+
+        let cache = TicketCache::<CasUser>::successes_only(Duration::from_secs(30));
+        assert!(cache.validate("ST-1", "session-a", failure).is_err());
+        assert!(cache.reuse("ST-1", "session-a").is_none());
+        cache.validate("ST-1", "session-a", || Ok(CasUser::new("jdoe", None))).unwrap();
+        assert!(cache.reuse("ST-1", "session-a").unwrap().is_ok());
+    }
+
+    #[test]
+    fn panicking_validation_should_complete_its_flight() {
+        let cache = Arc::new(TicketCache::<CasUser>::new(Duration::from_secs(30)));
+        let (started_tx, started_rx) = std::sync::mpsc::channel();
+        let panicking = {
+            let cache = cache.clone();
+            thread::spawn(move || {
+                cache.validate("ST-1", "session-a", || {
+                    started_tx.send(()).unwrap();
+                    thread::sleep(Duration::from_millis(50));
+                    panic!("validation failed");
+                })
+            })
+        };
+        started_rx.recv().unwrap();
+        // Waits for the panicking validation, then gets an error
+        let result = cache.reuse("ST-1", "session-a").unwrap();
+        assert!(matches!(result, Err(CasValidationError::Transport(_))));
+        assert!(panicking.join().is_err());
+        // Not cached: the ticket can be validated again
+        assert!(cache.reuse("ST-1", "session-a").is_none());
+        let result = cache.validate("ST-1", "session-a", || Ok(CasUser::new("jdoe", None)));
+        assert_eq!(result.unwrap().username(), "jdoe");
+    }
+
+    #[test]
+    fn concurrent_validations_should_be_deduplicated() {
+        let cache = Arc::new(TicketCache::<CasUser>::new(Duration::from_secs(30)));
+        let calls = Arc::new(AtomicUsize::new(0));
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let cache = cache.clone();
+                let calls = calls.clone();
+                thread::spawn(move || {
+                    cache.validate("ST-1", "session-a", || {
+                        calls.fetch_add(1, Ordering::SeqCst);
+                        thread::sleep(Duration::from_millis(50));
+                        Ok(CasUser::new("jdoe", None))
+                    })
+                })
+            })
+            .collect();
+        for thread in threads {
+            assert_eq!(thread.join().unwrap().unwrap().username(), "jdoe");
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+}
@@ -1,15 +1,21 @@
 use super::ActixCasClient;
+use crate::logout;
+use crate::session::CAS_USER_SESSION_KEY;
 use actix_session::UserSession;
 use actix_web::http;
 use actix_web::{get, HttpRequest, HttpResponse, Responder};
+use cas_client_core::CasUser;
 
 #[get("/")]
-pub async fn cas_login(cas_client: ActixCasClient) -> impl Responder {
+pub async fn cas_login(req: HttpRequest, cas_client: ActixCasClient) -> impl Responder {
     debug!("*** CAS LOGIN: {:?} ***", cas_client);
-    let after_logged_in_path = match cas_client.cas_client.default_after_logged_in_path() {
+    let mut after_logged_in_path = match cas_client.cas_client.default_after_logged_in_path() {
         Some(url) => url,
         _ => cas_client.app_url()
     };
+    if !cas_client.is_redirect_allowed(&req, &after_logged_in_path) {
+        after_logged_in_path = String::from("/");
+    }
     HttpResponse::build(http::StatusCode::TEMPORARY_REDIRECT)
         .header(http::header::LOCATION, after_logged_in_path)
         .finish()
@@ -18,13 +24,12 @@ pub async fn cas_login(cas_client: ActixCasClient) -> impl Responder {
 #[get("/")]
 pub async fn cas_logout(req: HttpRequest, cas_client: ActixCasClient) -> impl Responder {
     debug!("*** CAS LOGOUT: {:?} ***", cas_client);
-    let session = req.get_session();
-    session.purge();
-    let logout_url = cas_client.logout_url();
-    match logout_url {
-        Some(logout_url) => HttpResponse::build(http::StatusCode::TEMPORARY_REDIRECT)
-            .header(http::header::LOCATION, logout_url)
-            .finish(),
+    if let Some(event_handler) = cas_client.event_handler() {
+        let cas_user = req.get_session().get::<CasUser>(CAS_USER_SESSION_KEY).unwrap_or(None);
+        event_handler.on_logout(&req, cas_user.as_ref()).await;
+    }
+    match logout::logout(&req, &cas_client) {
+        Some(response) => response,
         _ => logout_404_error(cas_client),
     }
 }
@@ -0,0 +1,101 @@
+//! Compares ticket validation latency with and without connection reuse.
+//!
+//! A local stand-in CAS server answers `serviceValidate` over keep-alive
+//! connections, with a small delay on every new connection to mimic the cost of
+//! a TCP/TLS handshake.
+//!
+//! ```bash
+//! cargo bench -p cas-client-core --bench connection_reuse
+//! ```
+extern crate cas_client_core;
+
+use cas_client_core::CasClient;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const ITERATIONS: u32 = 200;
+const HANDSHAKE_DELAY: Duration = Duration::from_millis(2);
+const VALIDATION_RESPONSE: &str = "<cas:serviceResponse xmlns:cas=\"http://www.yale.edu/tp/cas\">\
+    <cas:authenticationSuccess><cas:user>username</cas:user></cas:authenticationSuccess>\
+    </cas:serviceResponse>";
+
+fn start_cas_server() -> (String, Arc<AtomicUsize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let connections = Arc::new(AtomicUsize::new(0));
+    let counter = connections.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            counter.fetch_add(1, Ordering::SeqCst);
+            let mut stream = stream.unwrap();
+            thread::spawn(move || {
+                thread::sleep(HANDSHAKE_DELAY);
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                loop {
+                    let mut line = String::new();
+                    match reader.read_line(&mut line) {
+                        Ok(0) | Err(_) => return,
+                        Ok(_) if line == "\r\n" => {
+                            let resp = format!(
+                                "HTTP/1.1 200 OK\r\nContent-Type: text/xml\r\nContent-Length: {}\r\n\r\n{}",
+                                VALIDATION_RESPONSE.len(),
+                                VALIDATION_RESPONSE
+                            );
+                            if stream.write_all(resp.as_bytes()).is_err() {
+                                return;
+                            }
+                        }
+                        Ok(_) => {}
+                    }
+                }
+            });
+        }
+    });
+    (format!("http://{}/cas", address), connections)
+}
+
+fn new_client(cas_url: &str) -> CasClient {
+    let mut cas_client = CasClient::new(cas_url).unwrap();
+    cas_client.set_app_url("https://service.example.org");
+    cas_client
+}
+
+fn run<F: FnMut() -> CasClient>(name: &str, mut client_for_call: F) {
+    let start = Instant::now();
+    for i in 0..ITERATIONS {
+        let user = client_for_call()
+            .validate_service_ticket(&format!("ST-{}", i))
+            .unwrap();
+        assert!(user.is_some());
+    }
+    let elapsed = start.elapsed();
+    println!(
+        "{:<12} {:>5} validations in {:>8.2?} ({:>8.2?} / validation)",
+        name,
+        ITERATIONS,
+        elapsed,
+        elapsed / ITERATIONS,
+    );
+}
+
+fn main() {
+    let (cas_url, connections) = start_cas_server();
+
+    // Shared client: every clone uses the same connection pool
+    let shared = new_client(&cas_url);
+    run("pooled", || shared.clone());
+    let pooled_connections = connections.swap(0, Ordering::SeqCst);
+
+    // New client for every validation: a new connection each time
+    run("unpooled", || new_client(&cas_url));
+    let unpooled_connections = connections.swap(0, Ordering::SeqCst);
+
+    println!(
+        "connections opened: pooled = {}, unpooled = {}",
+        pooled_connections, unpooled_connections
+    );
+}
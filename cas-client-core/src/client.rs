@@ -1,12 +1,15 @@
-extern crate curl;
 extern crate roxmltree;
 extern crate url;
 
-use crate::CasUser;
-use curl::easy::Easy;
+use crate::{
+    CasUser, CasValidationError, HttpResponse, HttpTransport, LoginOptions, NoAuthHandler, ProxyValidation,
+    UserTransform,
+};
 use std::collections::HashMap;
 use std::collections::hash_map::Entry::Vacant;
 use std::collections::hash_map::Entry::Occupied;
+use std::fmt;
+use std::sync::Arc;
 use url::Url;
 
 #[derive(Clone, Debug, PartialEq)]
@@ -14,12 +17,17 @@ pub struct CasClient {
     cas_base_url: Url,
     login_prefix: String,
     logout_prefix: String,
+    logout_return_param: LogoutReturnParam,
     no_auth_behavior: NoAuthBehavior,
     cas_protocol: CasProtocol,
     app_url: String,
     service_validate_prefix: String,
+    proxy_validate_prefix: String,
     login_service: String,
     default_after_logged_in_path: Option<String>,
+    rest_prefix: String,
+    transport: HttpTransport,
+    user_transform: UserTransform,
 }
 
 impl CasClient {
@@ -38,11 +46,16 @@ impl CasClient {
                 cas_protocol: CasProtocol::V3,
                 login_prefix: String::from("login"),
                 logout_prefix: String::from("logout"),
+                logout_return_param: LogoutReturnParam::default(),
                 no_auth_behavior: NoAuthBehavior::Authenticate,
                 app_url: String::new(),
                 service_validate_prefix: String::from("serviceValidate"),
+                proxy_validate_prefix: String::from("proxyValidate"),
                 login_service: String::from("auth/cas"),
                 default_after_logged_in_path: None,
+                rest_prefix: String::from("v1/tickets"),
+                transport: HttpTransport::new(),
+                user_transform: UserTransform::new(),
             }),
             Err(e) => {
                 error!("CAS url is not valid! Error: {}", e);
@@ -128,6 +141,16 @@ impl CasClient {
         self
     }
 
+    // Logout return param
+    pub fn logout_return_param(&self) -> LogoutReturnParam {
+        self.logout_return_param
+    }
+
+    pub fn set_logout_return_param(&mut self, logout_return_param: LogoutReturnParam) -> &mut Self {
+        self.logout_return_param = logout_return_param;
+        self
+    }
+
     // No auth behavior
     pub fn no_auth_behavior(&self) -> &NoAuthBehavior {
         &self.no_auth_behavior
@@ -188,17 +211,88 @@ impl CasClient {
         self
     }
 
+    // Proxy validate prefix
+    pub fn proxy_validate_prefix(&self) -> &String {
+        &self.proxy_validate_prefix
+    }
+
+    pub fn set_proxy_validate_prefix(&mut self, proxy_validate_prefix: &str) -> &mut Self {
+        if !proxy_validate_prefix.is_empty() {
+            self.proxy_validate_prefix = proxy_validate_prefix.to_string();
+            if self.proxy_validate_prefix.starts_with('/') {
+                self.proxy_validate_prefix = self.proxy_validate_prefix[1..].to_string();
+            }
+            if self.proxy_validate_prefix.ends_with('/') {
+                self.proxy_validate_prefix.pop();
+            }
+        } else {
+            error!("Proxy validate prefix cannot be empty");
+        }
+        self
+    }
+
+    // REST prefix
+    pub fn rest_prefix(&self) -> &String {
+        &self.rest_prefix
+    }
+
+    pub fn set_rest_prefix(&mut self, rest_prefix: &str) -> &mut Self {
+        if !rest_prefix.is_empty() {
+            self.rest_prefix = rest_prefix.to_string();
+            if self.rest_prefix.starts_with('/') {
+                self.rest_prefix = self.rest_prefix[1..].to_string();
+            }
+            if self.rest_prefix.ends_with('/') {
+                self.rest_prefix.pop();
+            }
+        } else {
+            error!("REST prefix cannot be empty");
+        }
+        self
+    }
+
+    // HTTP transport
+    pub fn transport(&self) -> &HttpTransport {
+        &self.transport
+    }
+
+    pub fn set_transport(&mut self, transport: HttpTransport) -> &mut Self {
+        self.transport = transport;
+        self
+    }
+
+    // User transform
+    pub fn user_transform(&self) -> &UserTransform {
+        &self.user_transform
+    }
+
+    /// Normalize the users returned by CAS with `user_transform`
+    pub fn set_user_transform(&mut self, user_transform: UserTransform) -> &mut Self {
+        self.user_transform = user_transform;
+        self
+    }
+
     // ################################################################################
     // Public functions
     // ################################################################################
+    /// Default service url: `{app_url}/{login_service}/login`
+    pub fn service_url(&self) -> String {
+        format!("{}/{}/login", self.app_url(), self.login_service())
+    }
+
     pub fn login_url(&self) -> Option<String> {
-        let service_url = format!("{}/{}/login", self.app_url(), self.login_service());
-        self.login_url_for_service(&service_url)
+        self.login_url_for_service(&self.service_url())
     }
 
     pub fn login_url_for_service(&self, service_url: &str) -> Option<String> {
+        self.login_url_with_options(service_url, &LoginOptions::default())
+    }
+
+    /// Login url for `service_url` with the parameters of `options`
+    pub fn login_url_with_options(&self, service_url: &str, options: &LoginOptions) -> Option<String> {
         let base_url = format!("{}{}", &self.cas_base_url(), &self.login_prefix());
-        let params = [("service", &service_url)];
+        let mut params = vec![("service", service_url)];
+        params.extend(options.params());
         let the_url = Url::parse_with_params(&base_url, &params);
         match the_url {
             Ok(url) => Some(url.to_string()),
@@ -210,9 +304,20 @@ impl CasClient {
     }
 
     pub fn logout_url(&self) -> Option<String> {
+        self.logout_url_with_return(Some(self.app_url()))
+    }
+
+    /// Logout url sending the user to `return_url` afterwards, if CAS allows it,
+    /// or leaving them on the CAS logout page
+    pub fn logout_url_with_return(&self, return_url: Option<&str>) -> Option<String> {
         let base_url = format!("{}{}", &self.cas_base_url(), &self.logout_prefix());
-        let params = [("service", self.app_url().to_string())];
-        let the_url = Url::parse_with_params(&base_url, &params);
+        let the_url = Url::parse(&base_url).map(|mut url| {
+            if let Some(return_url) = return_url {
+                url.query_pairs_mut()
+                    .append_pair(self.logout_return_param.name(), return_url);
+            }
+            url
+        });
         match the_url {
             Ok(url) => Some(url.to_string()),
             Err(e) => {
@@ -225,26 +330,66 @@ impl CasClient {
     // ###########
     // BEGIN TODO: TEST
     // ###########
+    #[allow(clippy::result_unit_err)]
     pub fn validate_service_ticket(&self, service_ticket: &str) -> Result<Option<CasUser>, ()> {
-        debug!("Validating service ticket: {:#?}", service_ticket);
+        self.validate_service_ticket_for_service(service_ticket, &self.service_url())
+    }
 
-        let resp = match self.fetch_cas_validation(service_ticket) {
-            Some(r) => {
-                debug!("Got response from cas validation");
-                r
-            }
-            None => {
-                error!("Error while fetching cas validation!");
-                return Ok(None);
-            }
-        };
-        let (user, attributes) = self.parse_saml_response(resp);
+    /// Validates a service ticket issued for `service_url`. CAS only validates a
+    /// ticket for the exact service it was issued for.
+    ///
+    /// `Ok(None)` when CAS rejects the ticket, `Err(())` when CAS cannot be reached.
+    #[allow(clippy::result_unit_err)]
+    pub fn validate_service_ticket_for_service(
+        &self,
+        service_ticket: &str,
+        service_url: &str,
+    ) -> Result<Option<CasUser>, ()> {
+        match self.validate_ticket(service_ticket, service_url) {
+            Ok(cas_user) => Ok(Some(cas_user)),
+            Err(CasValidationError::Failure { .. }) => Ok(None),
+            Err(_) => Err(()),
+        }
+    }
 
-        let result = match user.len() {
-            0 => None,
-            _ => Some(CasUser::new(&user, Some(attributes))),
-        };
-        Ok(result)
+    /// Validates a service ticket issued for `service_url`, with the reason of
+    /// the failure
+    pub fn validate_ticket(
+        &self,
+        service_ticket: &str,
+        service_url: &str,
+    ) -> Result<CasUser, CasValidationError> {
+        self.validate_ticket_with_renew(service_ticket, service_url, false)
+    }
+
+    /// Validates a service ticket issued for `service_url`. With `renew`, CAS
+    /// rejects the tickets issued from a single sign-on session rather than from
+    /// the credentials of the user: the only way to enforce a login with `renew`.
+    pub fn validate_ticket_with_renew(
+        &self,
+        service_ticket: &str,
+        service_url: &str,
+        renew: bool,
+    ) -> Result<CasUser, CasValidationError> {
+        debug!("Validating service ticket: {:#?}", service_ticket);
+        let url = self
+            .service_validate_url(service_ticket, service_url, renew)
+            .ok_or_else(|| CasValidationError::InvalidUrl(service_url.to_string()))?;
+        self.fetch_validation(&url).map(ProxyValidation::into_user)
+    }
+
+    /// Validates a proxy ticket, or a service ticket, issued for `service_url`
+    /// with `proxyValidate`, with the proxies it went through
+    pub fn validate_proxy_ticket(
+        &self,
+        ticket: &str,
+        service_url: &str,
+    ) -> Result<ProxyValidation, CasValidationError> {
+        debug!("Validating proxy ticket: {:#?}", ticket);
+        let url = self
+            .validate_url(self.proxy_validate_prefix(), ticket, service_url, false)
+            .ok_or_else(|| CasValidationError::InvalidUrl(service_url.to_string()))?;
+        self.fetch_validation(&url)
     }
     // ###########
     // END TODO
@@ -256,37 +401,40 @@ impl CasClient {
     // ###########
     // BEGIN TODO: TEST
     // ###########
-    pub(self) fn fetch_cas_validation(&self, ticket: &str) -> Option<String> {
-        match self.service_validate_url(ticket) {
-            Some(url) => {
-                let mut data = Vec::new();
-                let mut handle = Easy::new();
-                handle.url(&url).unwrap();
-                {
-                    let mut transfer = handle.transfer();
-                    transfer
-                        .write_function(|new_data| {
-                            data.extend_from_slice(new_data);
-                            Ok(new_data.len())
-                        })
-                        .unwrap();
-                    transfer.perform().unwrap();
-                }
-                match String::from_utf8(data) {
-                    Ok(r) => Some(r),
-                    Err(err) => {
-                        error!(
-                            "Error while requesting ticket validation! Error: {:?}",
-                            err
-                        );
-                        None
-                    }
-                }
-            }
-            None => {
-                error!("Error: service_ticket_validation_url returned None!");
-                None
-            }
+    pub(self) fn fetch_validation(&self, url: &str) -> Result<ProxyValidation, CasValidationError> {
+        let resp = self.transport.get(url).map_err(|err| {
+            error!("Error while requesting ticket validation! Error: {}", err);
+            CasValidationError::Transport(err.to_string())
+        })?;
+        debug!("Got response from cas validation");
+        let proxies = parse_proxies(&resp.body);
+        let result = self
+            .validation_result(resp)
+            .map(|cas_user| ProxyValidation::new(cas_user, proxies));
+        if let Err(err) = &result {
+            warn!("Ticket validation failed: {}", err);
+        }
+        result
+    }
+
+    pub(self) fn validation_result(&self, resp: HttpResponse) -> Result<CasUser, CasValidationError> {
+        if !(200..300).contains(&resp.status) {
+            return Err(CasValidationError::UnexpectedResponse {
+                status: resp.status,
+                body: resp.body,
+            });
+        }
+        if let Some(failure) = parse_validation_failure(&resp.body) {
+            return Err(failure);
+        }
+        let (user, attributes) = self.parse_saml_response(resp.body.clone());
+        let cas_user = self.user_transform.apply(CasUser::new(&user, Some(attributes)))?;
+        match cas_user.username().len() {
+            0 => Err(CasValidationError::UnexpectedResponse {
+                status: resp.status,
+                body: resp.body,
+            }),
+            _ => Ok(cas_user),
         }
     }
     // ###########
@@ -310,7 +458,7 @@ impl CasClient {
                         "authenticationSuccess" => {
                             info!("Authentication success!");
                         }
-                        "serviceResponse" | "attributes" => {}
+                        "serviceResponse" | "attributes" | "proxies" | "proxy" => {}
                         "user" => {
                             if let Some(child) = node.first_child() {
                                 if let Some(text) = child.text() {
@@ -332,21 +480,32 @@ impl CasClient {
                 }
             }
         };
-
-
-println!("ATTR ===> {:?}", attributes);
+        debug!("CAS attributes: {:?}", attributes);
 
         (user, attributes)
     }
 
-    pub(self) fn service_validate_url(&self, ticket: &str) -> Option<String> {
-        let base_url = format!(
-            "{}{}",
-            &self.cas_base_url(),
-            &self.service_validate_prefix()
-        );
-        let service_url = format!("{}/{}/login", self.app_url(), self.login_service());
-        let params = [("service", &service_url), ("ticket", &ticket.to_owned())];
+    pub(self) fn service_validate_url(
+        &self,
+        ticket: &str,
+        service_url: &str,
+        renew: bool,
+    ) -> Option<String> {
+        self.validate_url(self.service_validate_prefix(), ticket, service_url, renew)
+    }
+
+    pub(self) fn validate_url(
+        &self,
+        prefix: &str,
+        ticket: &str,
+        service_url: &str,
+        renew: bool,
+    ) -> Option<String> {
+        let base_url = format!("{}{}", &self.cas_base_url(), prefix);
+        let mut params = vec![("service", service_url), ("ticket", ticket)];
+        if renew {
+            params.push(("renew", "true"));
+        }
         let the_url = Url::parse_with_params(&base_url, &params);
         match the_url {
             Ok(url) => Some(url.to_string()),
@@ -357,6 +516,53 @@ println!("ATTR ===> {:?}", attributes);
         }
     }
 }
+
+/// `authenticationFailure` of a validation response, if any
+fn parse_validation_failure(resp: &str) -> Option<CasValidationError> {
+    let document = roxmltree::Document::parse(resp).ok()?;
+    let failure = document
+        .descendants()
+        .find(|node| node.is_element() && node.tag_name().name() == "authenticationFailure")?;
+    Some(CasValidationError::Failure {
+        code: failure.attribute("code").unwrap_or_default().to_string(),
+        description: failure.text().unwrap_or_default().trim().to_string(),
+    })
+}
+
+/// Proxies listed by a successful validation, the most recent first
+fn parse_proxies(resp: &str) -> Vec<String> {
+    let document = match roxmltree::Document::parse(resp) {
+        Ok(document) => document,
+        Err(_) => return Vec::new(),
+    };
+    document
+        .descendants()
+        .filter(|node| node.is_element() && node.tag_name().name() == "proxy")
+        .filter(|node| matches!(node.parent_element(), Some(parent) if parent.tag_name().name() == "proxies"))
+        .filter_map(|node| node.text())
+        .map(|proxy| proxy.trim().to_string())
+        .collect()
+}
+
+/// Parameter of the logout url carrying the url to return to
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum LogoutReturnParam {
+    /// `service`, since CAS 3
+    #[default]
+    Service,
+    /// `url`, for CAS 2 servers
+    Url,
+}
+
+impl LogoutReturnParam {
+    pub fn name(&self) -> &'static str {
+        match self {
+            LogoutReturnParam::Service => "service",
+            LogoutReturnParam::Url => "url",
+        }
+    }
+}
+
 /// CAS protocal to use
 ///
 /// NOT USED CURRENTLY
@@ -373,12 +579,44 @@ pub enum CasProtocol {
 /// - AuthenticatedOr404: returns HTTP 404 status code if user is not logged in
 /// - Authenticate: authenticates user if is not logged in
 /// - ForceAuthentication: authenticates user for each request
-#[derive(Clone, Debug, PartialEq)]
+/// - Custom: lets a `NoAuthHandler` decide
+#[derive(Clone)]
 pub enum NoAuthBehavior {
     AuthenticatedOr403,
     AuthenticatedOr404,
     Authenticate,
     ForceAuthentication,
+    Custom(Arc<dyn NoAuthHandler>),
+}
+
+impl NoAuthBehavior {
+    pub fn custom<H: NoAuthHandler + 'static>(handler: H) -> Self {
+        NoAuthBehavior::Custom(Arc::new(handler))
+    }
+}
+
+impl fmt::Debug for NoAuthBehavior {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NoAuthBehavior::AuthenticatedOr403 => f.write_str("AuthenticatedOr403"),
+            NoAuthBehavior::AuthenticatedOr404 => f.write_str("AuthenticatedOr404"),
+            NoAuthBehavior::Authenticate => f.write_str("Authenticate"),
+            NoAuthBehavior::ForceAuthentication => f.write_str("ForceAuthentication"),
+            NoAuthBehavior::Custom(_) => f.write_str("Custom(..)"),
+        }
+    }
+}
+
+// Custom behaviors are equal when they share their handler
+impl PartialEq for NoAuthBehavior {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (NoAuthBehavior::Custom(handler), NoAuthBehavior::Custom(other_handler)) => {
+                Arc::ptr_eq(handler, other_handler)
+            }
+            _ => std::mem::discriminant(self) == std::mem::discriminant(other),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -404,6 +642,8 @@ mod tests {
         assert_eq!(cas_client.cas_protocol, CasProtocol::V3);
         assert_eq!(cas_client.app_url, String::new());
         assert_eq!(cas_client.service_validate_prefix, "serviceValidate");
+        assert_eq!(cas_client.proxy_validate_prefix, "proxyValidate");
+        assert_eq!(cas_client.rest_prefix, "v1/tickets");
 
         let cas_base_url = "https://cas.example.org/";
         let cas_client = CasClient::new(cas_base_url).unwrap();
@@ -431,14 +671,14 @@ mod tests {
     fn new_should_return_an_error_if_cas_url_is_empty() {
         let cas_url = "";
         let cas_client = CasClient::new(cas_url);
-        assert_eq!(cas_client.is_err(), true);
+        assert!(cas_client.is_err());
     }
 
     #[test]
     fn new_should_return_an_error_if_cas_url_is_invalid() {
         let cas_url = "cas.example.org";
         let cas_client = CasClient::new(cas_url);
-        assert_eq!(cas_client.is_err(), true);
+        assert!(cas_client.is_err());
     }
 
     // ################################################################################
@@ -594,6 +834,26 @@ mod tests {
         assert_eq!(return_value, cas_client);
     }
 
+    #[test]
+    fn custom_no_auth_behaviors_should_be_equal_when_sharing_handler() {
+        struct Continue;
+        #[async_trait::async_trait(?Send)]
+        impl NoAuthHandler for Continue {
+            async fn handle(
+                &self,
+                _request: &crate::NoAuthRequest,
+                _session: &dyn crate::SessionState,
+            ) -> crate::NoAuthOutcome {
+                crate::NoAuthOutcome::Continue
+            }
+        }
+        let custom = NoAuthBehavior::custom(Continue);
+        assert_eq!(custom, custom.clone());
+        assert_ne!(custom, NoAuthBehavior::custom(Continue));
+        assert_ne!(custom, NoAuthBehavior::Authenticate);
+        assert_eq!(format!("{:?}", custom), "Custom(..)");
+    }
+
     // CAS protocol
     #[test]
     fn cas_protocol_getter_and_setter() {
@@ -703,6 +963,61 @@ mod tests {
         assert_eq!(return_value, cas_client);
     }
 
+    // Proxy validate prefix
+    #[test]
+    fn proxy_validate_prefix_getter_and_setter() {
+        let cas_url = "https://cas.example.org";
+        let mut cas_client = CasClient::new(cas_url).unwrap();
+        assert_eq!(cas_client.proxy_validate_prefix(), "proxyValidate");
+
+        // Invalid proxy_validate_prefix
+        cas_client.set_proxy_validate_prefix("");
+        assert_eq!(cas_client.proxy_validate_prefix(), "proxyValidate");
+
+        // Valid proxy_validate_prefix
+        cas_client.set_proxy_validate_prefix("/p3/proxyValidate/");
+        assert_eq!(cas_client.proxy_validate_prefix(), "p3/proxyValidate");
+    }
+
+    // REST prefix
+    #[test]
+    fn rest_prefix_getter_and_setter() {
+        let cas_url = "https://cas.example.org";
+        let mut cas_client = CasClient::new(cas_url).unwrap();
+        assert_eq!(cas_client.rest_prefix(), "v1/tickets");
+
+        // Invalid rest_prefix
+        cas_client.set_rest_prefix("");
+        assert_eq!(cas_client.rest_prefix(), "v1/tickets");
+
+        // Valid rest_prefix
+        cas_client.set_rest_prefix("/cas/v1/tickets/");
+        assert_eq!(cas_client.rest_prefix(), "cas/v1/tickets");
+    }
+
+    // HTTP transport
+    #[test]
+    fn transport_getter_and_setter() {
+        let cas_url = "https://cas.example.org";
+        let mut cas_client = CasClient::new(cas_url).unwrap();
+        assert_eq!(cas_client.transport(), &HttpTransport::new());
+
+        let mut transport = HttpTransport::new();
+        transport.set_timeout(std::time::Duration::from_secs(5));
+        cas_client.set_transport(transport.clone());
+        assert_eq!(cas_client.transport(), &transport);
+    }
+
+    #[test]
+    fn clones_should_share_the_transport_pool() {
+        let cas_url = "https://cas.example.org";
+        let cas_client = CasClient::new(cas_url).unwrap();
+        let clone = cas_client.clone();
+        assert_eq!(clone.transport().idle_handles(), 0);
+        let _ = cas_client.transport().get("http://127.0.0.1:1/");
+        assert_eq!(clone.transport().idle_handles(), 1);
+    }
+
     // ################################################################################
     // Public functions
     // ################################################################################
@@ -747,6 +1062,19 @@ mod tests {
         );
     }
 
+    // Service Url
+    #[test]
+    fn should_return_service_url() {
+        let cas_url = "https://cas.example.org";
+        let mut cas_client = CasClient::new(cas_url).unwrap();
+        assert_eq!(cas_client.service_url(), "/auth/cas/login");
+        cas_client.set_app_url("https://service.example.org/");
+        assert_eq!(
+            cas_client.service_url(),
+            "https://service.example.org/auth/cas/login"
+        );
+    }
+
     // Logout Url
     #[test]
     fn should_return_logout_url() {
@@ -777,6 +1105,17 @@ mod tests {
                 "https://cas.example.org/logout?service=https%3A%2F%2Fservice.example.org%2Fpath"
             ))
         );
+        cas_client.set_logout_return_param(LogoutReturnParam::Url);
+        assert_eq!(
+            cas_client.logout_url_with_return(Some("https://service.example.org/bye")),
+            Some(String::from(
+                "https://cas.example.org/logout?url=https%3A%2F%2Fservice.example.org%2Fbye"
+            ))
+        );
+        assert_eq!(
+            cas_client.logout_url_with_return(None),
+            Some(String::from("https://cas.example.org/logout"))
+        );
     }
 
     // ################################################################################
@@ -835,13 +1174,110 @@ mod tests {
         assert_eq!(attr, attributes);
     }
 
+    #[test]
+    fn should_parse_proxies() {
+        let resp = "
+        <cas:serviceResponse xmlns:cas=\"http://www.yale.edu/tp/cas\">
+        <cas:authenticationSuccess>
+            <cas:user>username</cas:user>
+            <cas:proxies>
+                <cas:proxy>https://proxy2.example.org/pgt</cas:proxy>
+                <cas:proxy>https://proxy1.example.org/pgt</cas:proxy>
+            </cas:proxies>
+        </cas:authenticationSuccess>
+        </cas:serviceResponse>";
+        assert_eq!(
+            parse_proxies(resp),
+            vec!["https://proxy2.example.org/pgt", "https://proxy1.example.org/pgt"]
+        );
+        assert!(parse_proxies("<cas:serviceResponse xmlns:cas=\"http://www.yale.edu/tp/cas\"/>").is_empty());
+    }
+
+    // validation_result
+    #[test]
+    fn should_return_validation_failure_code() {
+        let cas_client = CasClient::new("https://cas.example.org").unwrap();
+        let response = |status: u32, body: &str| HttpResponse {
+            status,
+            headers: Vec::new(),
+            body: body.to_string(),
+        };
+
+        let resp = "
+        <cas:serviceResponse xmlns:cas=\"http://www.yale.edu/tp/cas\">
+        <cas:authenticationFailure code=\"INVALID_SERVICE\">
+            Ticket ST-1 does not match supplied service
+            </cas:authenticationFailure>
+        </cas:serviceResponse>";
+        let err = cas_client.validation_result(response(200, resp)).unwrap_err();
+        assert_eq!(err.code(), Some("INVALID_SERVICE"));
+        assert_eq!(
+            err,
+            CasValidationError::Failure {
+                code: "INVALID_SERVICE".to_string(),
+                description: "Ticket ST-1 does not match supplied service".to_string(),
+            }
+        );
+
+        let err = cas_client.validation_result(response(503, "Unavailable")).unwrap_err();
+        assert_eq!(err.code(), None);
+        assert_eq!(
+            cas_client.validation_result(response(200, "<html/>")),
+            Err(CasValidationError::UnexpectedResponse {
+                status: 200,
+                body: "<html/>".to_string()
+            })
+        );
+
+        let resp = "
+        <cas:serviceResponse xmlns:cas=\"http://www.yale.edu/tp/cas\">
+        <cas:authenticationSuccess>
+            <cas:user>username</cas:user>
+            <cas:proxies>
+                <cas:proxy>https://proxy.example.org/pgtCallback</cas:proxy>
+            </cas:proxies>
+        </cas:authenticationSuccess>
+        </cas:serviceResponse>";
+        let cas_user = cas_client.validation_result(response(200, resp)).unwrap();
+        assert_eq!(cas_user.username(), "username");
+        assert!(cas_user.attributes().is_empty());
+    }
+
+    #[test]
+    fn validated_users_should_be_transformed() {
+        let mut cas_client = CasClient::new("https://cas.example.org").unwrap();
+        let mut transform = UserTransform::new();
+        transform
+            .add_stripped_realm("EXAMPLE.ORG")
+            .set_lowercase_username(true)
+            .rename_attribute("mail", "email");
+        cas_client.set_user_transform(transform);
+        let resp = "
+        <cas:serviceResponse xmlns:cas=\"http://www.yale.edu/tp/cas\">
+        <cas:authenticationSuccess>
+            <cas:user>JDoe@EXAMPLE.ORG</cas:user>
+            <cas:attributes>
+                <cas:mail>jdoe@example.org</cas:mail>
+            </cas:attributes>
+        </cas:authenticationSuccess>
+        </cas:serviceResponse>";
+        let response = HttpResponse {
+            status: 200,
+            headers: Vec::new(),
+            body: resp.to_string(),
+        };
+        let cas_user = cas_client.validation_result(response).unwrap();
+        assert_eq!(cas_user.username(), "jdoe");
+        assert_eq!(cas_user.attributes()["email"], "jdoe@example.org");
+    }
+
     // Service validate Url
     #[test]
     fn should_return_service_validate_url() {
         let cas_url = "https://cas.example.org";
         let mut cas_client = CasClient::new(cas_url).unwrap();
         assert_eq!(
-            cas_client.service_validate_url(""),
+            cas_client.service_validate_url("", &cas_client.service_url(), false),
             Some(String::from(
                 "https://cas.example.org/serviceValidate?service=%2Fauth%2Fcas%2Flogin&ticket="
             ))
@@ -849,17 +1285,31 @@ mod tests {
 
         cas_client.set_app_url("https://service.example.org/");
         assert_eq!(
-            cas_client.service_validate_url(""),
+            cas_client.service_validate_url("", &cas_client.service_url(), false),
             Some(String::from(
                 "https://cas.example.org/serviceValidate?service=https%3A%2F%2Fservice.example.org%2Fauth%2Fcas%2Flogin&ticket="
             ))
         );
 
         assert_eq!(
-            cas_client.service_validate_url("fake_ticket"),
+            cas_client.service_validate_url("fake_ticket", &cas_client.service_url(), false),
             Some(String::from(
                 "https://cas.example.org/serviceValidate?service=https%3A%2F%2Fservice.example.org%2Fauth%2Fcas%2Flogin&ticket=fake_ticket"
             ))
         );
+
+        assert_eq!(
+            cas_client.service_validate_url("fake_ticket", "https://service.example.org/user?a=b", false),
+            Some(String::from(
+                "https://cas.example.org/serviceValidate?service=https%3A%2F%2Fservice.example.org%2Fuser%3Fa%3Db&ticket=fake_ticket"
+            ))
+        );
+
+        assert_eq!(
+            cas_client.service_validate_url("fake_ticket", "https://service.example.org/user", true),
+            Some(String::from(
+                "https://cas.example.org/serviceValidate?service=https%3A%2F%2Fservice.example.org%2Fuser&ticket=fake_ticket&renew=true"
+            ))
+        );
     }
 }
@@ -6,7 +6,19 @@ extern crate log;
 extern crate serde;
 
 mod client;
+mod login_options;
+mod no_auth;
+mod rest;
+mod transport;
 mod user;
+mod user_transform;
+mod validation;
 
-pub use crate::client::{CasClient, CasProtocol, NoAuthBehavior};
+pub use crate::client::{CasClient, CasProtocol, LogoutReturnParam, NoAuthBehavior};
+pub use crate::login_options::LoginOptions;
+pub use crate::no_auth::{NoAuthHandler, NoAuthOutcome, NoAuthRequest, SessionState};
+pub use crate::rest::CasRestError;
+pub use crate::transport::{HttpResponse, HttpTransport};
 pub use crate::user::CasUser;
+pub use crate::user_transform::UserTransform;
+pub use crate::validation::{CasValidationError, ProxyValidation};
@@ -0,0 +1,166 @@
+/// Parameters set by the client itself, which extra parameters cannot override
+const RESERVED_PARAMS: [&str; 6] = ["service", "renew", "gateway", "method", "locale", "authn_method"];
+
+/// Parameters of the CAS login url besides `service`
+///
+/// ```
+/// use cas_client_core::{CasClient, LoginOptions};
+///
+/// let cas_client = CasClient::new("https://cas.example.org/cas").unwrap();
+/// let mut options = LoginOptions::new();
+/// options.set_renew(true).set_authn_method(Some("mfa-duo".to_string()));
+/// let login_url = cas_client
+///     .login_url_with_options("https://app.example.org/auth/cas/login", &options)
+///     .unwrap();
+/// assert!(login_url.ends_with("&renew=true&authn_method=mfa-duo"));
+/// ```
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LoginOptions {
+    renew: bool,
+    gateway: bool,
+    post_response: bool,
+    locale: Option<String>,
+    authn_method: Option<String>,
+    extra_params: Vec<(String, String)>,
+}
+
+impl LoginOptions {
+    // ################################################################################
+    // Constructor
+    // ################################################################################
+    pub fn new() -> Self {
+        LoginOptions::default()
+    }
+
+    // ################################################################################
+    // Getters / Setters
+    // ################################################################################
+    pub fn renew(&self) -> bool {
+        self.renew
+    }
+
+    /// Make users enter their credentials again, even with a single sign-on
+    /// session (`renew=true`). CAS ignores `gateway` along with it.
+    ///
+    /// The login url can be altered by the browser: the ticket must be validated
+    /// with `renew` as well (`CasClient::validate_ticket_with_renew`), as the
+    /// middlewares do for the logins they start.
+    pub fn set_renew(&mut self, renew: bool) -> &mut Self {
+        self.renew = renew;
+        self
+    }
+
+    pub fn gateway(&self) -> bool {
+        self.gateway
+    }
+
+    /// Only log in users who already have a single sign-on session
+    /// (`gateway=true`): the others come back without ticket instead of seeing
+    /// the login form.
+    pub fn set_gateway(&mut self, gateway: bool) -> &mut Self {
+        self.gateway = gateway;
+        self
+    }
+
+    pub fn post_response(&self) -> bool {
+        self.post_response
+    }
+
+    /// Ask CAS to send the ticket back in a POST form rather than in the url
+    /// (`method=POST`, CAS 3)
+    pub fn set_post_response(&mut self, post_response: bool) -> &mut Self {
+        self.post_response = post_response;
+        self
+    }
+
+    pub fn locale(&self) -> Option<&str> {
+        self.locale.as_deref()
+    }
+
+    /// Language of the login form, e.g. `fr` or `pt_BR`
+    pub fn set_locale(&mut self, locale: Option<String>) -> &mut Self {
+        self.locale = locale;
+        self
+    }
+
+    pub fn authn_method(&self) -> Option<&str> {
+        self.authn_method.as_deref()
+    }
+
+    /// Authentication method CAS must use, triggering e.g. a multifactor
+    /// provider (`authn_method=mfa-duo`)
+    ///
+    /// Nothing enforces it at validation: check the authentication attributes
+    /// released by CAS (e.g. `successfulAuthenticationHandlers`) before granting
+    /// access, with an attribute rule or when the user logs in.
+    pub fn set_authn_method(&mut self, authn_method: Option<String>) -> &mut Self {
+        self.authn_method = authn_method;
+        self
+    }
+
+    pub fn extra_params(&self) -> &[(String, String)] {
+        &self.extra_params
+    }
+
+    /// Add a parameter for a site-specific CAS customisation. The parameters
+    /// above cannot be set this way.
+    pub fn add_param(&mut self, name: &str, value: &str) -> &mut Self {
+        match RESERVED_PARAMS.contains(&name) {
+            true => error!("Login parameter {} cannot be set as an extra parameter!", name),
+            false => self.extra_params.push((name.to_string(), value.to_string())),
+        }
+        self
+    }
+
+    // ################################################################################
+    // Public functions
+    // ################################################################################
+    /// Query parameters of the login url, after `service`
+    pub fn params(&self) -> Vec<(&str, &str)> {
+        let mut params = Vec::new();
+        if self.renew {
+            params.push(("renew", "true"));
+        }
+        if self.gateway {
+            params.push(("gateway", "true"));
+        }
+        if self.post_response {
+            params.push(("method", "POST"));
+        }
+        if let Some(locale) = &self.locale {
+            params.push(("locale", locale.as_str()));
+        }
+        if let Some(authn_method) = &self.authn_method {
+            params.push(("authn_method", authn_method.as_str()));
+        }
+        for (name, value) in &self.extra_params {
+            params.push((name.as_str(), value.as_str()));
+        }
+        params
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn params_should_be_empty_by_default() {
+        assert!(LoginOptions::new().params().is_empty());
+    }
+
+    #[test]
+    fn params_should_list_options() {
+        let mut options = LoginOptions::new();
+        options
+            .set_gateway(true)
+            .set_post_response(true)
+            .set_locale(Some("fr".to_string()))
+            .add_param("tenant", "acme")
+            .add_param("service", "https://evil.example.org");
+        assert_eq!(
+            options.params(),
+            vec![("gateway", "true"), ("method", "POST"), ("locale", "fr"), ("tenant", "acme")]
+        );
+    }
+}
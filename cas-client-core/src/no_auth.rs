@@ -0,0 +1,161 @@
+use async_trait::async_trait;
+
+/// Handler of the requests of users who are not logged in, for
+/// `NoAuthBehavior::Custom`
+///
+/// # Examples
+/// ```
+/// use async_trait::async_trait;
+/// use cas_client_core::{NoAuthHandler, NoAuthOutcome, NoAuthRequest, SessionState};
+///
+/// /// Lets API clients in with a key, sends the browsers of a known tenant to
+/// /// their login page, the others to CAS
+/// struct ApiKeyOrLogin;
+///
+/// #[async_trait(?Send)]
+/// impl NoAuthHandler for ApiKeyOrLogin {
+///     async fn handle(&self, request: &NoAuthRequest, session: &dyn SessionState) -> NoAuthOutcome {
+///         match (request.header("X-Api-Key"), session.get("tenant")) {
+///             (Some("secret"), _) => NoAuthOutcome::Continue,
+///             (Some(_), _) => NoAuthOutcome::Respond {
+///                 status: 403,
+///                 headers: vec![],
+///                 body: String::from("Invalid API key"),
+///             },
+///             (None, Some(tenant)) => NoAuthOutcome::Redirect(format!("/{}/login", tenant.as_str().unwrap_or_default())),
+///             (None, None) => NoAuthOutcome::Authenticate,
+///         }
+///     }
+/// }
+/// ```
+#[async_trait(?Send)]
+pub trait NoAuthHandler: Send + Sync {
+    async fn handle(&self, request: &NoAuthRequest, session: &dyn SessionState) -> NoAuthOutcome;
+}
+
+/// Session of the request a `NoAuthHandler` handles, with JSON values
+pub trait SessionState {
+    fn get(&self, key: &str) -> Option<serde_json::Value>;
+
+    fn set(&self, key: &str, value: serde_json::Value);
+
+    fn remove(&self, key: &str);
+}
+
+/// What to do with a request of a user who is not logged in
+#[derive(Clone, Debug, PartialEq)]
+pub enum NoAuthOutcome {
+    /// Let the request through
+    Continue,
+    /// Authenticate the user with CAS, as `NoAuthBehavior::Authenticate` does
+    Authenticate,
+    /// Redirect the user to the url
+    Redirect(String),
+    /// Answer the request
+    Respond {
+        status: u16,
+        headers: Vec<(String, String)>,
+        body: String,
+    },
+}
+
+/// Request of a user who is not logged in, with the CAS state
+#[derive(Clone, Debug, PartialEq)]
+pub struct NoAuthRequest {
+    method: String,
+    url: String,
+    headers: Vec<(String, String)>,
+    ticket: Option<String>,
+    login_url: Option<String>,
+    session_expired: bool,
+}
+
+impl NoAuthRequest {
+    // ################################################################################
+    // Constructor
+    // ################################################################################
+    pub fn new(method: &str, url: &str, headers: Vec<(String, String)>) -> Self {
+        NoAuthRequest {
+            method: method.to_string(),
+            url: url.to_string(),
+            headers,
+            ticket: None,
+            login_url: None,
+            session_expired: false,
+        }
+    }
+
+    // ################################################################################
+    // Getters / Setters
+    // ################################################################################
+    pub fn method(&self) -> &str {
+        &self.method
+    }
+
+    /// Full url of the request
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn headers(&self) -> &[(String, String)] {
+        &self.headers
+    }
+
+    /// First value of the header `name`, case insensitive
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// CAS ticket of the request, if any
+    pub fn ticket(&self) -> Option<&str> {
+        self.ticket.as_deref()
+    }
+
+    pub fn set_ticket(&mut self, ticket: Option<String>) -> &mut Self {
+        self.ticket = ticket;
+        self
+    }
+
+    /// Url of the CAS login page
+    pub fn login_url(&self) -> Option<&str> {
+        self.login_url.as_deref()
+    }
+
+    pub fn set_login_url(&mut self, login_url: Option<String>) -> &mut Self {
+        self.login_url = login_url;
+        self
+    }
+
+    /// Whether the user was logged out by this request, as their session expired
+    pub fn session_expired(&self) -> bool {
+        self.session_expired
+    }
+
+    pub fn set_session_expired(&mut self, session_expired: bool) -> &mut Self {
+        self.session_expired = session_expired;
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_lookup_should_ignore_case() {
+        let mut request = NoAuthRequest::new(
+            "GET",
+            "https://app.example.org/user",
+            vec![("x-api-key".to_string(), "secret".to_string())],
+        );
+        request.set_ticket(Some("ST-1".to_string()));
+        assert_eq!(request.header("X-Api-Key"), Some("secret"));
+        assert_eq!(request.header("Accept"), None);
+        assert_eq!(request.ticket(), Some("ST-1"));
+        assert_eq!(request.login_url(), None);
+        assert!(!request.session_expired());
+    }
+}
@@ -0,0 +1,276 @@
+use crate::{CasClient, HttpResponse};
+use std::error::Error;
+use std::fmt;
+use url::Url;
+
+/// Errors returned by the CAS REST protocol client
+#[derive(Clone, Debug, PartialEq)]
+pub enum CasRestError {
+    /// Username or password rejected by CAS
+    BadCredentials,
+    /// Account is locked
+    AccountLocked,
+    /// Account is disabled
+    AccountDisabled,
+    /// Password is expired or must be changed before login
+    PasswordExpired,
+    /// Ticket granting ticket is unknown or expired
+    InvalidTicketGrantingTicket,
+    /// Service is not allowed to use the REST protocol
+    ServiceNotAuthorized,
+    /// CAS answered with an unexpected HTTP status
+    UnexpectedResponse { status: u32, body: String },
+    /// CAS could not be reached
+    Transport(String),
+    /// CAS REST url could not be built
+    InvalidUrl(String),
+}
+
+impl fmt::Display for CasRestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CasRestError::BadCredentials => write!(f, "Bad credentials"),
+            CasRestError::AccountLocked => write!(f, "Account is locked"),
+            CasRestError::AccountDisabled => write!(f, "Account is disabled"),
+            CasRestError::PasswordExpired => write!(f, "Password is expired"),
+            CasRestError::InvalidTicketGrantingTicket => {
+                write!(f, "Ticket granting ticket is invalid or expired")
+            }
+            CasRestError::ServiceNotAuthorized => {
+                write!(f, "Service is not authorized to use the CAS REST protocol")
+            }
+            CasRestError::UnexpectedResponse { status, body } => {
+                write!(f, "Unexpected CAS response (HTTP {}): {}", status, body)
+            }
+            CasRestError::Transport(err) => write!(f, "CAS is unreachable: {}", err),
+            CasRestError::InvalidUrl(err) => write!(f, "Invalid CAS REST url: {}", err),
+        }
+    }
+}
+
+impl Error for CasRestError {}
+
+/// CAS REST protocol (`/v1/tickets`)
+///
+/// Lets CLI tools, batch jobs and tests log in without a browser. Requests go
+/// through the client's `HttpTransport`, so they share its connection pool and TLS
+/// configuration with ticket validation.
+impl CasClient {
+    // ################################################################################
+    // Public functions
+    // ################################################################################
+    /// Request a ticket granting ticket (TGT) for `username` / `password`
+    pub fn create_ticket_granting_ticket(
+        &self,
+        username: &str,
+        password: &str,
+    ) -> Result<String, CasRestError> {
+        let url = self.rest_tickets_url(None)?;
+        let params = [("username", username), ("password", password)];
+        let resp = self
+            .transport()
+            .post_form(url.as_str(), &params)
+            .map_err(|err| CasRestError::Transport(err.to_string()))?;
+        match resp.status {
+            201 => ticket_from_location(&resp),
+            _ => Err(rest_error_for_response(resp)),
+        }
+    }
+
+    /// Request a service ticket (ST) for `service` using a ticket granting ticket
+    pub fn create_service_ticket(
+        &self,
+        ticket_granting_ticket: &str,
+        service: &str,
+    ) -> Result<String, CasRestError> {
+        let url = self.rest_tickets_url(Some(ticket_granting_ticket))?;
+        let resp = self
+            .transport()
+            .post_form(url.as_str(), &[("service", service)])
+            .map_err(|err| CasRestError::Transport(err.to_string()))?;
+        match resp.status {
+            200 => match resp.body.trim() {
+                "" => Err(CasRestError::UnexpectedResponse {
+                    status: resp.status,
+                    body: resp.body,
+                }),
+                ticket => Ok(ticket.to_string()),
+            },
+            _ => Err(rest_error_for_response(resp)),
+        }
+    }
+
+    /// Destroy a ticket granting ticket (logout)
+    pub fn destroy_ticket_granting_ticket(
+        &self,
+        ticket_granting_ticket: &str,
+    ) -> Result<(), CasRestError> {
+        let url = self.rest_tickets_url(Some(ticket_granting_ticket))?;
+        let resp = self
+            .transport()
+            .delete(url.as_str())
+            .map_err(|err| CasRestError::Transport(err.to_string()))?;
+        match resp.status {
+            200 | 204 => Ok(()),
+            _ => Err(rest_error_for_response(resp)),
+        }
+    }
+
+    // ################################################################################
+    // Private functions
+    // ################################################################################
+    pub(crate) fn rest_tickets_url(
+        &self,
+        ticket_granting_ticket: Option<&str>,
+    ) -> Result<Url, CasRestError> {
+        let base_url = format!("{}{}", &self.cas_base_url(), &self.rest_prefix());
+        let mut url =
+            Url::parse(&base_url).map_err(|err| CasRestError::InvalidUrl(err.to_string()))?;
+        if let Some(ticket_granting_ticket) = ticket_granting_ticket {
+            if ticket_granting_ticket.is_empty() {
+                return Err(CasRestError::InvalidTicketGrantingTicket);
+            }
+            url.path_segments_mut()
+                .map_err(|_| CasRestError::InvalidUrl(base_url.clone()))?
+                .push(ticket_granting_ticket);
+        }
+        Ok(url)
+    }
+}
+
+/// TGT is the last segment of the `Location` header of the `201 Created` response
+fn ticket_from_location(resp: &HttpResponse) -> Result<String, CasRestError> {
+    let ticket = resp
+        .header("Location")
+        .and_then(|location| location.trim_end_matches('/').rsplit('/').next())
+        .filter(|ticket| !ticket.is_empty());
+    match ticket {
+        Some(ticket) => Ok(ticket.to_string()),
+        None => Err(CasRestError::UnexpectedResponse {
+            status: resp.status,
+            body: resp.body.clone(),
+        }),
+    }
+}
+
+/// Map CAS REST error responses. CAS lists the authentication exceptions in the
+/// body of `401` responses (e.g. `{"authentication_exceptions":["AccountLockedException"]}`).
+fn rest_error_for_response(resp: HttpResponse) -> CasRestError {
+    match resp.status {
+        400 | 401 => {
+            if resp.body.contains("AccountLocked") {
+                CasRestError::AccountLocked
+            } else if resp.body.contains("AccountDisabled") {
+                CasRestError::AccountDisabled
+            } else if resp.body.contains("AccountPasswordMustChange")
+                || resp.body.contains("CredentialExpired")
+                || resp.body.contains("PasswordExpired")
+            {
+                CasRestError::PasswordExpired
+            } else {
+                CasRestError::BadCredentials
+            }
+        }
+        403 => CasRestError::ServiceNotAuthorized,
+        404 => CasRestError::InvalidTicketGrantingTicket,
+        423 => CasRestError::AccountLocked,
+        status => CasRestError::UnexpectedResponse {
+            status,
+            body: resp.body,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(status: u32, body: &str) -> HttpResponse {
+        HttpResponse {
+            status,
+            headers: Vec::new(),
+            body: body.to_string(),
+        }
+    }
+
+    // ################################################################################
+    // Private functions
+    // ################################################################################
+    // REST tickets url
+    #[test]
+    fn should_return_rest_tickets_url() {
+        let cas_url = "https://cas.example.org/cas";
+        let mut cas_client = CasClient::new(cas_url).unwrap();
+        assert_eq!(
+            cas_client.rest_tickets_url(None).unwrap().as_str(),
+            "https://cas.example.org/cas/v1/tickets"
+        );
+        assert_eq!(
+            cas_client.rest_tickets_url(Some("TGT-1-abc")).unwrap().as_str(),
+            "https://cas.example.org/cas/v1/tickets/TGT-1-abc"
+        );
+        assert_eq!(
+            cas_client.rest_tickets_url(Some("../TGT")).unwrap().as_str(),
+            "https://cas.example.org/cas/v1/tickets/..%2FTGT"
+        );
+        assert_eq!(
+            cas_client.rest_tickets_url(Some("")),
+            Err(CasRestError::InvalidTicketGrantingTicket)
+        );
+
+        cas_client.set_rest_prefix("api/tickets");
+        assert_eq!(
+            cas_client.rest_tickets_url(None).unwrap().as_str(),
+            "https://cas.example.org/cas/api/tickets"
+        );
+    }
+
+    // Location header
+    #[test]
+    fn should_read_ticket_from_location() {
+        let mut resp = response(201, "");
+        assert!(ticket_from_location(&resp).is_err());
+
+        resp.headers.push((
+            "location".to_string(),
+            "https://cas.example.org/cas/v1/tickets/TGT-1-abc".to_string(),
+        ));
+        assert_eq!(ticket_from_location(&resp), Ok("TGT-1-abc".to_string()));
+    }
+
+    // Error mapping
+    #[test]
+    fn should_map_rest_errors() {
+        assert_eq!(
+            rest_error_for_response(response(401, "")),
+            CasRestError::BadCredentials
+        );
+        assert_eq!(
+            rest_error_for_response(response(
+                401,
+                "{\"authentication_exceptions\":[\"AccountLockedException\"]}"
+            )),
+            CasRestError::AccountLocked
+        );
+        assert_eq!(
+            rest_error_for_response(response(401, "AccountDisabledException")),
+            CasRestError::AccountDisabled
+        );
+        assert_eq!(
+            rest_error_for_response(response(401, "AccountPasswordMustChangeException")),
+            CasRestError::PasswordExpired
+        );
+        assert_eq!(rest_error_for_response(response(423, "")), CasRestError::AccountLocked);
+        assert_eq!(
+            rest_error_for_response(response(404, "")),
+            CasRestError::InvalidTicketGrantingTicket
+        );
+        assert_eq!(
+            rest_error_for_response(response(500, "boom")),
+            CasRestError::UnexpectedResponse {
+                status: 500,
+                body: "boom".to_string()
+            }
+        );
+    }
+}
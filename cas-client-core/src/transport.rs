@@ -0,0 +1,425 @@
+extern crate curl;
+
+use curl::easy::{Easy, List};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// HTTP transport used to talk to the CAS server
+///
+/// Keeps a shared pool of curl handles. A curl handle caches the connections it
+/// opened, so handing the same handles out again lets consecutive requests reuse
+/// an open (keep-alive) TCP/TLS connection instead of paying a new handshake.
+///
+/// Clones share the same pool, so a `CasClient` cloned across actix workers
+/// still reuses connections.
+///
+/// The TLS settings apply to every request sent to CAS (ticket validation and
+/// REST protocol alike).
+#[derive(Clone)]
+pub struct HttpTransport {
+    connect_timeout: Duration,
+    timeout: Duration,
+    max_idle_handles: usize,
+    verify_tls: bool,
+    ca_bundle: Option<PathBuf>,
+    handles: Arc<Mutex<Vec<Easy>>>,
+}
+
+/// Response returned by the CAS server
+#[derive(Clone, Debug, PartialEq)]
+pub struct HttpResponse {
+    pub status: u32,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl HttpResponse {
+    /// Get the first header named `name` (case insensitive)
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+enum Method<'a> {
+    Get,
+    PostForm(&'a [(&'a str, &'a str)]),
+    Delete,
+}
+
+impl HttpTransport {
+    // ################################################################################
+    // Constructor
+    // ################################################################################
+    pub fn new() -> Self {
+        HttpTransport {
+            connect_timeout: Duration::from_secs(10),
+            timeout: Duration::from_secs(30),
+            max_idle_handles: 16,
+            verify_tls: true,
+            ca_bundle: None,
+            handles: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    // ################################################################################
+    // Getters / Setters
+    // ################################################################################
+    // Connect timeout
+    pub fn connect_timeout(&self) -> Duration {
+        self.connect_timeout
+    }
+
+    pub fn set_connect_timeout(&mut self, connect_timeout: Duration) -> &mut Self {
+        self.connect_timeout = connect_timeout;
+        self
+    }
+
+    // Timeout
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.timeout = timeout;
+        self
+    }
+
+    // Max idle handles
+    pub fn max_idle_handles(&self) -> usize {
+        self.max_idle_handles
+    }
+
+    pub fn set_max_idle_handles(&mut self, max_idle_handles: usize) -> &mut Self {
+        self.max_idle_handles = max_idle_handles;
+        self
+    }
+
+    // TLS verification
+    pub fn verify_tls(&self) -> bool {
+        self.verify_tls
+    }
+
+    /// Disable TLS certificate and host verification. Never do that in production!
+    pub fn set_verify_tls(&mut self, verify_tls: bool) -> &mut Self {
+        if !verify_tls {
+            warn!("TLS verification of the CAS server is disabled!");
+        }
+        self.verify_tls = verify_tls;
+        self
+    }
+
+    // CA bundle
+    pub fn ca_bundle(&self) -> Option<&Path> {
+        self.ca_bundle.as_deref()
+    }
+
+    pub fn set_ca_bundle(&mut self, ca_bundle: Option<PathBuf>) -> &mut Self {
+        self.ca_bundle = ca_bundle;
+        self
+    }
+
+    // ################################################################################
+    // Public functions
+    // ################################################################################
+    pub fn get(&self, url: &str) -> Result<HttpResponse, curl::Error> {
+        self.send(url, Method::Get)
+    }
+
+    /// Send an `application/x-www-form-urlencoded` POST request
+    pub fn post_form(
+        &self,
+        url: &str,
+        params: &[(&str, &str)],
+    ) -> Result<HttpResponse, curl::Error> {
+        self.send(url, Method::PostForm(params))
+    }
+
+    pub fn delete(&self, url: &str) -> Result<HttpResponse, curl::Error> {
+        self.send(url, Method::Delete)
+    }
+
+    /// Number of idle handles (and thus cached connections) in the pool
+    pub fn idle_handles(&self) -> usize {
+        match self.handles.lock() {
+            Ok(handles) => handles.len(),
+            Err(_) => 0,
+        }
+    }
+
+    // ################################################################################
+    // Private functions
+    // ################################################################################
+    pub(self) fn send(&self, url: &str, method: Method) -> Result<HttpResponse, curl::Error> {
+        let mut handle = self.checkout();
+        let result = self.perform(&mut handle, url, method);
+        self.release(handle);
+        result
+    }
+
+    pub(self) fn checkout(&self) -> Easy {
+        let handle = match self.handles.lock() {
+            Ok(mut handles) => handles.pop(),
+            Err(err) => {
+                error!("CAS connection pool is poisoned! Error: {}", err);
+                None
+            }
+        };
+        match handle {
+            Some(mut handle) => {
+                // `reset` keeps the connection cache of the handle.
+                handle.reset();
+                handle
+            }
+            None => Easy::new(),
+        }
+    }
+
+    pub(self) fn release(&self, handle: Easy) {
+        if let Ok(mut handles) = self.handles.lock() {
+            if handles.len() < self.max_idle_handles {
+                handles.push(handle);
+            }
+        }
+    }
+
+    pub(self) fn perform(
+        &self,
+        handle: &mut Easy,
+        url: &str,
+        method: Method,
+    ) -> Result<HttpResponse, curl::Error> {
+        handle.url(url)?;
+        handle.tcp_keepalive(true)?;
+        handle.connect_timeout(self.connect_timeout)?;
+        handle.timeout(self.timeout)?;
+        handle.ssl_verify_peer(self.verify_tls)?;
+        handle.ssl_verify_host(self.verify_tls)?;
+        if let Some(ca_bundle) = &self.ca_bundle {
+            handle.cainfo(ca_bundle)?;
+        }
+        match method {
+            Method::Get => handle.get(true)?,
+            Method::PostForm(params) => {
+                let body = url::form_urlencoded::Serializer::new(String::new())
+                    .extend_pairs(params)
+                    .finish();
+                let mut headers = List::new();
+                headers.append("Content-Type: application/x-www-form-urlencoded")?;
+                handle.http_headers(headers)?;
+                handle.post(true)?;
+                handle.post_fields_copy(body.as_bytes())?;
+            }
+            Method::Delete => handle.custom_request("DELETE")?,
+        }
+        let mut data = Vec::new();
+        let mut headers = Vec::new();
+        {
+            let mut transfer = handle.transfer();
+            transfer.header_function(|header| {
+                let header = String::from_utf8_lossy(header);
+                if let Some(index) = header.find(':') {
+                    headers.push((
+                        header[..index].trim().to_string(),
+                        header[index + 1..].trim().to_string(),
+                    ));
+                }
+                true
+            })?;
+            transfer.write_function(|new_data| {
+                data.extend_from_slice(new_data);
+                Ok(new_data.len())
+            })?;
+            transfer.perform()?;
+        }
+        Ok(HttpResponse {
+            status: handle.response_code()?,
+            headers,
+            body: String::from_utf8_lossy(&data).into_owned(),
+        })
+    }
+}
+
+impl Default for HttpTransport {
+    fn default() -> Self {
+        HttpTransport::new()
+    }
+}
+
+impl fmt::Debug for HttpTransport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HttpTransport")
+            .field("connect_timeout", &self.connect_timeout)
+            .field("timeout", &self.timeout)
+            .field("max_idle_handles", &self.max_idle_handles)
+            .field("verify_tls", &self.verify_tls)
+            .field("ca_bundle", &self.ca_bundle)
+            .field("idle_handles", &self.idle_handles())
+            .finish()
+    }
+}
+
+/// Two transports are equal when they are configured the same way, whatever the
+/// state of their pools.
+impl PartialEq for HttpTransport {
+    fn eq(&self, other: &Self) -> bool {
+        self.connect_timeout == other.connect_timeout
+            && self.timeout == other.timeout
+            && self.max_idle_handles == other.max_idle_handles
+            && self.verify_tls == other.verify_tls
+            && self.ca_bundle == other.ca_bundle
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    // Minimal keep-alive HTTP server counting accepted connections. It answers with
+    // the request method in a `X-Method` header and echoes the request body.
+    fn start_server() -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let counter = connections.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                counter.fetch_add(1, Ordering::SeqCst);
+                let mut stream = stream.unwrap();
+                thread::spawn(move || {
+                    let mut reader = BufReader::new(stream.try_clone().unwrap());
+                    let mut method = String::new();
+                    let mut content_length = 0;
+                    loop {
+                        let mut line = String::new();
+                        match reader.read_line(&mut line) {
+                            Ok(0) | Err(_) => return,
+                            Ok(_) if line == "\r\n" => {
+                                let mut body = vec![0; content_length];
+                                if reader.read_exact(&mut body).is_err() {
+                                    return;
+                                }
+                                let body = match body.is_empty() {
+                                    true => String::from("ok"),
+                                    false => String::from_utf8(body).unwrap(),
+                                };
+                                let resp = format!(
+                                    "HTTP/1.1 200 OK\r\nX-Method: {}\r\nContent-Length: {}\r\n\r\n{}",
+                                    method,
+                                    body.len(),
+                                    body
+                                );
+                                if stream.write_all(resp.as_bytes()).is_err() {
+                                    return;
+                                }
+                                method.clear();
+                                content_length = 0;
+                            }
+                            Ok(_) if method.is_empty() => {
+                                method = line.split(' ').next().unwrap().to_string();
+                            }
+                            Ok(_) => {
+                                let lower = line.to_lowercase();
+                                if let Some(value) = lower.strip_prefix("content-length:") {
+                                    content_length = value.trim().parse().unwrap();
+                                }
+                            }
+                        }
+                    }
+                });
+            }
+        });
+        (format!("http://{}/", address), connections)
+    }
+
+    // ################################################################################
+    // Getters / Setters
+    // ################################################################################
+    #[test]
+    fn timeouts_getters_and_setters() {
+        let mut transport = HttpTransport::new();
+        assert_eq!(transport.connect_timeout(), Duration::from_secs(10));
+        assert_eq!(transport.timeout(), Duration::from_secs(30));
+
+        transport.set_connect_timeout(Duration::from_secs(1));
+        transport.set_timeout(Duration::from_secs(2));
+        assert_eq!(transport.connect_timeout(), Duration::from_secs(1));
+        assert_eq!(transport.timeout(), Duration::from_secs(2));
+    }
+
+    #[test]
+    fn tls_getters_and_setters() {
+        let mut transport = HttpTransport::new();
+        assert!(transport.verify_tls());
+        assert_eq!(transport.ca_bundle(), None);
+
+        transport.set_verify_tls(false);
+        transport.set_ca_bundle(Some(PathBuf::from("/etc/ssl/cas.pem")));
+        assert!(!transport.verify_tls());
+        assert_eq!(transport.ca_bundle(), Some(Path::new("/etc/ssl/cas.pem")));
+    }
+
+    #[test]
+    fn clones_should_share_the_pool() {
+        let transport = HttpTransport::new();
+        let clone = transport.clone();
+        clone.release(Easy::new());
+        assert_eq!(transport.idle_handles(), 1);
+        assert_eq!(transport, clone);
+    }
+
+    #[test]
+    fn release_should_not_exceed_max_idle_handles() {
+        let mut transport = HttpTransport::new();
+        transport.set_max_idle_handles(1);
+        transport.release(Easy::new());
+        transport.release(Easy::new());
+        assert_eq!(transport.idle_handles(), 1);
+    }
+
+    // ################################################################################
+    // Public functions
+    // ################################################################################
+    #[test]
+    fn get_should_reuse_connections() {
+        let (url, connections) = start_server();
+        let transport = HttpTransport::new();
+        for _ in 0..5 {
+            let resp = transport.clone().get(&url).unwrap();
+            assert_eq!(resp.status, 200);
+            assert_eq!(resp.body, "ok");
+        }
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn post_form_should_send_urlencoded_body() {
+        let (url, _) = start_server();
+        let transport = HttpTransport::new();
+        let resp = transport
+            .post_form(&url, &[("username", "jdoe"), ("password", "p@ss word")])
+            .unwrap();
+        assert_eq!(resp.header("x-method"), Some("POST"));
+        assert_eq!(resp.body, "username=jdoe&password=p%40ss+word");
+    }
+
+    #[test]
+    fn delete_should_send_delete_request() {
+        let (url, connections) = start_server();
+        let transport = HttpTransport::new();
+        let resp = transport.delete(&url).unwrap();
+        assert_eq!(resp.header("X-Method"), Some("DELETE"));
+        let resp = transport.get(&url).unwrap();
+        assert_eq!(resp.header("X-Method"), Some("GET"));
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+    }
+}
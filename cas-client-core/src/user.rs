@@ -2,7 +2,7 @@ use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
 /// Struct for CAS user
-#[derive(Debug, Deserialize, PartialEq, Serialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub struct CasUser {
     username: String,
     attributes: HashMap<String, String>,
@@ -46,10 +46,7 @@ impl CasUser {
         );
         CasUser {
             username: username.to_string(),
-            attributes: match attributes {
-                Some(v) => v,
-                None => HashMap::new(),
-            },
+            attributes: attributes.unwrap_or_default(),
         }
     }
 
@@ -87,6 +84,36 @@ impl CasUser {
         self.attributes.clone()
     }
 
+    /// Get the values of CAS user's attribute `name`. CAS joins the values of
+    /// multi-valued attributes with `,`.
+    ///
+    /// Values containing a `,` themselves, such as the LDAP DNs of `memberOf`,
+    /// are split apart too: have CAS release such attributes in a form without
+    /// commas, e.g. the group names rather than their DNs.
+    ///
+    /// # Examples
+    /// ```
+    /// use cas_client_core::CasUser;
+    /// use std::collections::HashMap;
+    ///
+    /// let mut attributes: HashMap<String, String> = HashMap::new();
+    /// attributes.insert("memberOf".to_string(), "staff,admin".to_string());
+    /// let cas_user = CasUser::new("user", Some(attributes));
+    /// assert_eq!(cas_user.attribute_values("memberOf"), vec!["staff", "admin"]);
+    /// assert!(cas_user.attribute_values("mail").is_empty());
+    ///
+    /// let mut attributes: HashMap<String, String> = HashMap::new();
+    /// attributes.insert("memberOf".to_string(), "cn=staff,dc=example,dc=org".to_string());
+    /// let cas_user = CasUser::new("user", Some(attributes));
+    /// assert_eq!(cas_user.attribute_values("memberOf"), vec!["cn=staff", "dc=example", "dc=org"]);
+    /// ```
+    pub fn attribute_values(&self, name: &str) -> Vec<&str> {
+        match self.attributes.get(name) {
+            Some(value) => value.split(',').collect(),
+            None => Vec::new(),
+        }
+    }
+
     /// Converts CAS user to String
     ///
     /// # Examples
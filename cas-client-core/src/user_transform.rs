@@ -0,0 +1,271 @@
+use crate::{CasUser, CasValidationError};
+
+/// Failure code of the users whose realm is rejected
+const REALM_NOT_ALLOWED: &str = "REALM_NOT_ALLOWED";
+
+/// Normalizes the users returned by a CAS server, whose usernames and attribute
+/// names differ from one server to another
+///
+/// The steps run in this order:
+/// 1. the principal attribute, if any, replaces the username
+/// 2. the domain or realm is stripped from the username, if it is one of the
+///    stripped realms. Users of other realms are kept as is, or rejected.
+/// 3. the username is lowercased
+/// 4. attributes are renamed, then dropped, then defaulted
+///
+/// ```
+/// use cas_client_core::{CasUser, UserTransform};
+/// use std::collections::HashMap;
+///
+/// let mut transform = UserTransform::new();
+/// transform
+///     .add_stripped_realm("EXAMPLE.ORG")
+///     .set_lowercase_username(true)
+///     .rename_attribute("mail", "email")
+///     .default_attribute("locale", "en");
+/// let mut attributes = HashMap::new();
+/// attributes.insert("mail".to_string(), "jdoe@example.org".to_string());
+/// let cas_user = transform
+///     .apply(CasUser::new("JDoe@EXAMPLE.ORG", Some(attributes)))
+///     .unwrap();
+/// assert_eq!(cas_user.username(), "jdoe");
+/// assert_eq!(cas_user.attributes()["email"], "jdoe@example.org");
+/// assert_eq!(cas_user.attributes()["locale"], "en");
+/// ```
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct UserTransform {
+    principal_attribute: Option<String>,
+    stripped_realms: Vec<String>,
+    reject_other_realms: bool,
+    lowercase_username: bool,
+    renamed_attributes: Vec<(String, String)>,
+    dropped_attributes: Vec<String>,
+    default_attributes: Vec<(String, String)>,
+}
+
+impl UserTransform {
+    // ################################################################################
+    // Constructor
+    // ################################################################################
+    /// Transform leaving the users as returned by CAS
+    pub fn new() -> Self {
+        UserTransform::default()
+    }
+
+    // ################################################################################
+    // Getters / Setters
+    // ################################################################################
+    pub fn principal_attribute(&self) -> Option<&str> {
+        self.principal_attribute.as_deref()
+    }
+
+    /// Attribute to use as username, e.g. when CAS returns an opaque id. Users
+    /// without it keep their username.
+    pub fn set_principal_attribute(&mut self, principal_attribute: Option<String>) -> &mut Self {
+        self.principal_attribute = principal_attribute;
+        self
+    }
+
+    pub fn stripped_realms(&self) -> &[String] {
+        &self.stripped_realms
+    }
+
+    /// Strip `realm`, a Kerberos realm, email domain or Windows domain compared
+    /// case-insensitively, from the usernames: with `EXAMPLE.ORG`,
+    /// `jdoe@EXAMPLE.ORG` becomes `jdoe`, with `EXAMPLE`, `EXAMPLE\jdoe` does.
+    /// Only the realms added are stripped, so that users of different realms keep
+    /// different usernames.
+    pub fn add_stripped_realm(&mut self, realm: &str) -> &mut Self {
+        self.stripped_realms.push(realm.to_string());
+        self
+    }
+
+    pub fn reject_other_realms(&self) -> bool {
+        self.reject_other_realms
+    }
+
+    /// Reject the users whose username has a realm other than the stripped ones,
+    /// with a `REALM_NOT_ALLOWED` validation failure, rather than keeping their
+    /// username as is. Users without realm are kept.
+    pub fn set_reject_other_realms(&mut self, reject_other_realms: bool) -> &mut Self {
+        self.reject_other_realms = reject_other_realms;
+        self
+    }
+
+    pub fn lowercase_username(&self) -> bool {
+        self.lowercase_username
+    }
+
+    pub fn set_lowercase_username(&mut self, lowercase_username: bool) -> &mut Self {
+        self.lowercase_username = lowercase_username;
+        self
+    }
+
+    pub fn renamed_attributes(&self) -> &[(String, String)] {
+        &self.renamed_attributes
+    }
+
+    /// Rename attribute `from` to `to`, replacing any `to` attribute
+    pub fn rename_attribute(&mut self, from: &str, to: &str) -> &mut Self {
+        self.renamed_attributes.push((from.to_string(), to.to_string()));
+        self
+    }
+
+    pub fn dropped_attributes(&self) -> &[String] {
+        &self.dropped_attributes
+    }
+
+    /// Drop attribute `name`, by its name after renaming
+    pub fn drop_attribute(&mut self, name: &str) -> &mut Self {
+        self.dropped_attributes.push(name.to_string());
+        self
+    }
+
+    pub fn default_attributes(&self) -> &[(String, String)] {
+        &self.default_attributes
+    }
+
+    /// Give attribute `name` the value `value` for the users without it
+    pub fn default_attribute(&mut self, name: &str, value: &str) -> &mut Self {
+        self.default_attributes.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    // ################################################################################
+    // Public functions
+    // ################################################################################
+    /// `cas_user` normalized by this transform, or the failure rejecting them
+    pub fn apply(&self, cas_user: CasUser) -> Result<CasUser, CasValidationError> {
+        if *self == UserTransform::default() {
+            return Ok(cas_user);
+        }
+        let mut attributes = cas_user.attributes();
+        let mut username = cas_user.username().to_string();
+        if let Some(principal_attribute) = &self.principal_attribute {
+            match attributes.get(principal_attribute) {
+                Some(principal) => username = principal.clone(),
+                None => warn!(
+                    "CAS user {} has no attribute {}, keeping their username",
+                    username, principal_attribute
+                ),
+            }
+        }
+        if let Some((name, realm)) = split_realm(&username) {
+            if self.stripped_realms.iter().any(|stripped| stripped.eq_ignore_ascii_case(realm)) {
+                username = name.to_string();
+            } else if self.reject_other_realms {
+                return Err(CasValidationError::Failure {
+                    code: REALM_NOT_ALLOWED.to_string(),
+                    description: format!("Realm {} of CAS user {} is not allowed", realm, username),
+                });
+            }
+        }
+        if self.lowercase_username {
+            username = username.to_lowercase();
+        }
+        for (from, to) in &self.renamed_attributes {
+            if let Some(value) = attributes.remove(from) {
+                attributes.insert(to.clone(), value);
+            }
+        }
+        for name in &self.dropped_attributes {
+            attributes.remove(name);
+        }
+        for (name, value) in &self.default_attributes {
+            attributes.entry(name.clone()).or_insert_with(|| value.clone());
+        }
+        Ok(CasUser::new(&username, Some(attributes)))
+    }
+}
+
+/// `username` split into its name and its Windows domain (`EXAMPLE\jdoe`), or its
+/// Kerberos realm or email domain (`jdoe@EXAMPLE.ORG`), if it has one
+fn split_realm(username: &str) -> Option<(&str, &str)> {
+    match username.split_once('\\') {
+        Some((realm, name)) => Some((name, realm)),
+        None => username.rsplit_once('@'),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn cas_user(username: &str, attributes: &[(&str, &str)]) -> CasUser {
+        let attributes: HashMap<String, String> = attributes
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        CasUser::new(username, Some(attributes))
+    }
+
+    #[test]
+    fn default_transform_should_keep_users() {
+        let user = cas_user("JDoe@EXAMPLE.ORG", &[("mail", "jdoe@example.org")]);
+        assert_eq!(UserTransform::new().apply(user.clone()).unwrap(), user);
+    }
+
+    #[test]
+    fn usernames_should_be_normalized() {
+        let mut transform = UserTransform::new();
+        transform
+            .add_stripped_realm("EXAMPLE.ORG")
+            .add_stripped_realm("example")
+            .set_lowercase_username(true);
+        for username in &["JDoe", "jdoe@EXAMPLE.ORG", "EXAMPLE\\JDOE", "jdoe@example.org", "jdoe"] {
+            assert_eq!(transform.apply(cas_user(username, &[])).unwrap().username(), "jdoe");
+        }
+        // Users of other realms keep theirs
+        let user = transform.apply(cas_user("JDoe@PARTNER.ORG", &[])).unwrap();
+        assert_eq!(user.username(), "jdoe@partner.org");
+    }
+
+    #[test]
+    fn other_realms_should_be_rejected_when_asked() {
+        let mut transform = UserTransform::new();
+        transform.add_stripped_realm("EXAMPLE.ORG").set_reject_other_realms(true);
+        assert_eq!(transform.apply(cas_user("jdoe@EXAMPLE.ORG", &[])).unwrap().username(), "jdoe");
+        assert_eq!(transform.apply(cas_user("jdoe", &[])).unwrap().username(), "jdoe");
+        for username in &["jdoe@PARTNER.ORG", "PARTNER\\jdoe"] {
+            let error = transform.apply(cas_user(username, &[])).unwrap_err();
+            assert_eq!(error.code(), Some(REALM_NOT_ALLOWED));
+        }
+    }
+
+    #[test]
+    fn principal_attribute_should_replace_username() {
+        let mut transform = UserTransform::new();
+        transform
+            .set_principal_attribute(Some("uid".to_string()))
+            .set_lowercase_username(true);
+        let user = transform.apply(cas_user("8f14e45f", &[("uid", "JDoe")])).unwrap();
+        assert_eq!(user.username(), "jdoe");
+        assert_eq!(user.attributes()["uid"], "JDoe");
+        assert_eq!(transform.apply(cas_user("8F14E45F", &[])).unwrap().username(), "8f14e45f");
+    }
+
+    #[test]
+    fn attributes_should_be_renamed_dropped_and_defaulted() {
+        let mut transform = UserTransform::new();
+        transform
+            .rename_attribute("mail", "email")
+            .drop_attribute("password_expires")
+            .default_attribute("locale", "en")
+            .default_attribute("email", "unknown@example.org");
+        let user = transform.apply(cas_user(
+            "jdoe",
+            &[("mail", "jdoe@example.org"), ("password_expires", "2030-01-01"), ("locale", "fr")],
+        ))
+        .unwrap();
+        assert_eq!(
+            user,
+            cas_user("jdoe", &[("email", "jdoe@example.org"), ("locale", "fr")])
+        );
+        let user = transform.apply(cas_user("jdoe", &[])).unwrap();
+        assert_eq!(
+            user,
+            cas_user("jdoe", &[("email", "unknown@example.org"), ("locale", "en")])
+        );
+    }
+}
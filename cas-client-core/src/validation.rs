@@ -0,0 +1,79 @@
+use crate::CasUser;
+use std::error::Error;
+use std::fmt;
+
+/// Successful `proxyValidate` validation
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProxyValidation {
+    user: CasUser,
+    proxies: Vec<String>,
+}
+
+impl ProxyValidation {
+    // ################################################################################
+    // Constructor
+    // ################################################################################
+    pub fn new(user: CasUser, proxies: Vec<String>) -> Self {
+        ProxyValidation { user, proxies }
+    }
+
+    // ################################################################################
+    // Getters
+    // ################################################################################
+    pub fn user(&self) -> &CasUser {
+        &self.user
+    }
+
+    /// Proxies the ticket went through, the most recent first. Empty for a
+    /// service ticket.
+    pub fn proxies(&self) -> &[String] {
+        &self.proxies
+    }
+
+    pub fn into_user(self) -> CasUser {
+        self.user
+    }
+}
+
+/// Errors returned by service ticket validation
+#[derive(Clone, Debug, PartialEq)]
+pub enum CasValidationError {
+    /// CAS rejected the ticket with the failure `code` of the protocol (e.g.
+    /// `INVALID_TICKET`, `INVALID_SERVICE`)
+    Failure { code: String, description: String },
+    /// CAS answered with an unexpected HTTP status or body
+    UnexpectedResponse { status: u32, body: String },
+    /// CAS could not be reached
+    Transport(String),
+    /// CAS validation url could not be built
+    InvalidUrl(String),
+}
+
+impl CasValidationError {
+    /// Failure code of CAS, if CAS rejected the ticket
+    pub fn code(&self) -> Option<&str> {
+        match self {
+            CasValidationError::Failure { code, .. } => Some(code),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for CasValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CasValidationError::Failure { code, description } => {
+                write!(f, "CAS rejected the ticket ({}): {}", code, description)
+            }
+            CasValidationError::UnexpectedResponse { status, body } => {
+                write!(f, "Unexpected CAS response (HTTP {}): {}", status, body)
+            }
+            CasValidationError::Transport(err) => write!(f, "CAS is unreachable: {}", err),
+            CasValidationError::InvalidUrl(err) => {
+                write!(f, "Invalid CAS validation url: {}", err)
+            }
+        }
+    }
+}
+
+impl Error for CasValidationError {}
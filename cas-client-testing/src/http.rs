@@ -0,0 +1,237 @@
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+use url::Url;
+
+/// HTTP request received by the mock server
+#[derive(Clone, Debug)]
+pub(crate) struct Request {
+    pub method: String,
+    pub path: String,
+    pub query: Vec<(String, String)>,
+    pub headers: Vec<(String, String)>,
+    pub form: Vec<(String, String)>,
+}
+
+impl Request {
+    /// Read one request from a keep-alive connection. Returns `None` once the
+    /// client closed the connection.
+    pub fn read_from<R: BufRead>(reader: &mut R) -> io::Result<Option<Request>> {
+        let mut request_line = String::new();
+        if reader.read_line(&mut request_line)? == 0 {
+            return Ok(None);
+        }
+        let mut parts = request_line.split_whitespace();
+        let method = parts.next().unwrap_or_default().to_string();
+        let target = parts.next().unwrap_or("/").to_string();
+
+        let mut headers = Vec::new();
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line)? == 0 || line == "\r\n" || line == "\n" {
+                break;
+            }
+            if let Some(index) = line.find(':') {
+                headers.push((
+                    line[..index].trim().to_lowercase(),
+                    line[index + 1..].trim().to_string(),
+                ));
+            }
+        }
+
+        let content_length = headers
+            .iter()
+            .find(|(name, _)| name == "content-length")
+            .and_then(|(_, value)| value.parse::<usize>().ok())
+            .unwrap_or(0);
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body)?;
+
+        let (path, query) = match target.find('?') {
+            Some(index) => (target[..index].to_string(), parse_form(&target[index + 1..])),
+            None => (target, Vec::new()),
+        };
+        Ok(Some(Request {
+            method,
+            path,
+            query,
+            headers,
+            form: parse_form(&String::from_utf8_lossy(&body)),
+        }))
+    }
+
+    /// First query string or form parameter named `name`
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.query
+            .iter()
+            .chain(self.form.iter())
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        let name = name.to_lowercase();
+        self.headers
+            .iter()
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// HTTP response sent by the mock server
+#[derive(Clone, Debug)]
+pub(crate) struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl Response {
+    pub fn new(status: u16, content_type: &str, body: String) -> Self {
+        Response {
+            status,
+            headers: vec![("Content-Type".to_string(), content_type.to_string())],
+            body,
+        }
+    }
+
+    pub fn redirect(location: &str) -> Self {
+        Response {
+            status: 302,
+            headers: vec![("Location".to_string(), location.to_string())],
+            body: String::new(),
+        }
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, reason(self.status));
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str(&format!("Content-Length: {}\r\n\r\n", self.body.len()));
+        writer.write_all(head.as_bytes())?;
+        writer.write_all(self.body.as_bytes())?;
+        writer.flush()
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        302 => "Found",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        415 => "Unsupported Media Type",
+        423 => "Locked",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "Unknown",
+    }
+}
+
+pub(crate) fn parse_form(input: &str) -> Vec<(String, String)> {
+    url::form_urlencoded::parse(input.as_bytes())
+        .into_owned()
+        .collect()
+}
+
+/// Add `name=value` to the query string of `url`
+pub(crate) fn with_param(url: &str, name: &str, value: &str) -> String {
+    match Url::parse(url) {
+        Ok(mut parsed) => {
+            parsed.query_pairs_mut().append_pair(name, value);
+            parsed.to_string()
+        }
+        Err(_) => {
+            let separator = if url.contains('?') { '&' } else { '?' };
+            let param: String = url::form_urlencoded::Serializer::new(String::new())
+                .append_pair(name, value)
+                .finish();
+            format!("{}{}{}", url, separator, param)
+        }
+    }
+}
+
+/// Plain HTTP client used for callbacks to the application (single logout and
+/// proxy granting ticket callbacks). Returns the response status.
+pub(crate) fn send(method: &str, url: &str, form: &[(&str, &str)]) -> io::Result<u16> {
+    let url = Url::parse(url).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    if url.scheme() != "http" {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Only http callbacks are supported by the mock CAS server",
+        ));
+    }
+    let host = url.host_str().unwrap_or("localhost");
+    let port = url.port_or_known_default().unwrap_or(80);
+    let mut stream = TcpStream::connect((host, port))?;
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+
+    let body = url::form_urlencoded::Serializer::new(String::new())
+        .extend_pairs(form)
+        .finish();
+    let target = match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_string(),
+    };
+    let request = format!(
+        "{} {} HTTP/1.1\r\nHost: {}:{}\r\nContent-Type: application/x-www-form-urlencoded\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+        method,
+        target,
+        host,
+        port,
+        body.len(),
+        body
+    );
+    stream.write_all(request.as_bytes())?;
+
+    let mut status_line = String::new();
+    BufReader::new(stream).read_line(&mut status_line)?;
+    status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, status_line.clone()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_read_request() {
+        let raw = "POST /cas/v1/tickets?renew=true HTTP/1.1\r\nHost: localhost\r\n\
+                   Content-Length: 27\r\n\r\nusername=jdoe&password=s+1&";
+        let mut reader = BufReader::new(raw.as_bytes());
+        let request = Request::read_from(&mut reader).unwrap().unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/cas/v1/tickets");
+        assert_eq!(request.param("renew"), Some("true"));
+        assert_eq!(request.param("username"), Some("jdoe"));
+        assert_eq!(request.param("password"), Some("s 1"));
+        assert_eq!(request.header("HOST"), Some("localhost"));
+        assert!(Request::read_from(&mut reader).unwrap().is_none());
+    }
+
+    #[test]
+    fn should_add_param() {
+        assert_eq!(
+            with_param("http://app.example.org/user", "ticket", "ST-1"),
+            "http://app.example.org/user?ticket=ST-1"
+        );
+        assert_eq!(
+            with_param("http://app.example.org/user?a=b", "ticket", "ST-1"),
+            "http://app.example.org/user?a=b&ticket=ST-1"
+        );
+        assert_eq!(with_param("/auth/cas/login", "ticket", "ST-1"), "/auth/cas/login?ticket=ST-1");
+    }
+}
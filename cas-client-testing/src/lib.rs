@@ -0,0 +1,14 @@
+//! In-process CAS server for tests
+//!
+//! Start a [`MockCasServer`](struct.MockCasServer.html) on localhost, register
+//! users and point a `CasClient` at [`MockCasServer::url`](struct.MockCasServer.html#method.url).
+#[macro_use]
+extern crate log;
+extern crate url;
+
+mod http;
+mod server;
+mod user;
+
+pub use crate::server::{MockCasServer, CAS_BASE_PATH};
+pub use crate::user::MockUser;
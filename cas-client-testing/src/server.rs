@@ -0,0 +1,1033 @@
+use crate::http::{self, Request, Response};
+use crate::MockUser;
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, BufReader, ErrorKind};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Path of the mock CAS server, as in a typical `https://host/cas` deployment
+pub const CAS_BASE_PATH: &str = "/cas";
+
+const XML_CONTENT_TYPE: &str = "application/xml; charset=utf-8";
+const HTML_CONTENT_TYPE: &str = "text/html; charset=utf-8";
+
+/// In-process CAS server listening on localhost
+///
+/// Implements enough of the CAS protocol to run full login flows in `cargo test`
+/// without network access:
+/// - `login` (GET with an SSO session or POST with credentials, honoring `renew`,
+///   `gateway` and `method=POST`), `logout`
+/// - `serviceValidate`, `p3/serviceValidate`, `proxyValidate`, `p3/proxyValidate`,
+///   honoring `renew`
+/// - `proxy`
+/// - REST protocol (`v1/tickets`)
+///
+/// Tickets are single use and bound to their service, like on a real CAS server.
+/// The server stops when dropped.
+///
+/// # Examples
+/// ```
+/// use cas_client_testing::{MockCasServer, MockUser};
+///
+/// let cas = MockCasServer::start();
+/// let mut user = MockUser::new("jdoe", "secret");
+/// user.add_attribute("mail", "jdoe@example.org");
+/// cas.add_user(user);
+///
+/// let ticket = cas.issue_service_ticket("jdoe", "https://app.example.org/");
+/// assert!(ticket.starts_with("ST-"));
+/// assert!(cas.url().starts_with("http://127.0.0.1:"));
+/// ```
+pub struct MockCasServer {
+    address: SocketAddr,
+    state: Arc<Mutex<State>>,
+    running: Arc<AtomicBool>,
+}
+
+#[derive(Default)]
+struct State {
+    users: HashMap<String, MockUser>,
+    sso_user: Option<String>,
+    tickets: HashMap<String, Ticket>,
+    ticket_granting_tickets: HashMap<String, String>,
+    proxy_granting_tickets: HashMap<String, ProxyGrantingTicket>,
+    counter: u64,
+    validation_failures: VecDeque<(String, String)>,
+    unavailable: bool,
+    single_logout_on_logout: bool,
+    received_requests: Vec<String>,
+}
+
+struct Ticket {
+    username: String,
+    service: String,
+    proxies: Vec<String>,
+    consumed: bool,
+    from_credentials: bool,
+}
+
+struct ProxyGrantingTicket {
+    username: String,
+    proxies: Vec<String>,
+}
+
+enum Validation {
+    Service,
+    Proxy,
+}
+
+impl State {
+    fn next_id(&mut self, prefix: &str) -> String {
+        self.counter += 1;
+        format!("{}-{}-mock", prefix, self.counter)
+    }
+
+    fn issue_ticket(&mut self, prefix: &str, username: &str, service: &str, proxies: Vec<String>) -> String {
+        let ticket = self.next_id(prefix);
+        self.tickets.insert(
+            ticket.clone(),
+            Ticket {
+                username: username.to_string(),
+                service: service.to_string(),
+                proxies,
+                consumed: false,
+                from_credentials: false,
+            },
+        );
+        ticket
+    }
+
+    /// Issue a service ticket right after the user entered their credentials, the
+    /// only tickets accepted by a validation with `renew`
+    fn issue_ticket_from_credentials(&mut self, username: &str, service: &str) -> String {
+        let ticket = self.issue_ticket("ST", username, service, Vec::new());
+        if let Some(issued) = self.tickets.get_mut(&ticket) {
+            issued.from_credentials = true;
+        }
+        ticket
+    }
+
+    fn authenticate(&self, username: &str, password: &str) -> Result<(), &'static str> {
+        match self.users.get(username) {
+            Some(user) if user.password() != password => Err("FailedLoginException"),
+            Some(user) if user.locked() => Err("AccountLockedException"),
+            Some(user) if user.disabled() => Err("AccountDisabledException"),
+            Some(_) => Ok(()),
+            None => Err("AccountNotFoundException"),
+        }
+    }
+}
+
+impl MockCasServer {
+    // ################################################################################
+    // Constructor
+    // ################################################################################
+    /// Start the server on a random port of `127.0.0.1`
+    pub fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Cannot bind mock CAS server");
+        let address = listener.local_addr().expect("Cannot read mock CAS server address");
+        let state = Arc::new(Mutex::new(State::default()));
+        let running = Arc::new(AtomicBool::new(true));
+        debug!("Mock CAS server listening on {}", address);
+
+        let server = MockCasServer {
+            address,
+            state: state.clone(),
+            running: running.clone(),
+        };
+        let base_url = server.url();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if !running.load(Ordering::SeqCst) {
+                    break;
+                }
+                if let Ok(stream) = stream {
+                    let state = state.clone();
+                    let running = running.clone();
+                    let base_url = base_url.clone();
+                    thread::spawn(move || serve(stream, state, running, base_url));
+                }
+            }
+        });
+        server
+    }
+
+    // ################################################################################
+    // Getters / Setters
+    // ################################################################################
+    /// CAS base url, to give to `CasClient::new`
+    pub fn url(&self) -> String {
+        format!("http://{}{}", self.address, CAS_BASE_PATH)
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Add (or replace) a user
+    pub fn add_user(&self, user: MockUser) -> &Self {
+        self.state()
+            .users
+            .insert(user.username().to_string(), user);
+        self
+    }
+
+    /// Open an SSO session: `GET /login` then issues tickets for `username`
+    /// without asking for credentials
+    pub fn login_as(&self, username: &str) -> &Self {
+        self.state().sso_user = Some(username.to_string());
+        self
+    }
+
+    /// Close the SSO session
+    pub fn logout(&self) -> &Self {
+        self.state().sso_user = None;
+        self
+    }
+
+    /// Issue a service ticket for `username` and `service`, as `GET /login` would
+    pub fn issue_service_ticket(&self, username: &str, service: &str) -> String {
+        self.state().issue_ticket("ST", username, service, Vec::new())
+    }
+
+    /// Issue a proxy granting ticket for `username`, owned by the proxy service
+    /// `proxy_callback_url`
+    pub fn issue_proxy_granting_ticket(&self, username: &str, proxy_callback_url: &str) -> String {
+        let mut state = self.state();
+        let ticket = state.next_id("PGT");
+        state.proxy_granting_tickets.insert(
+            ticket.clone(),
+            ProxyGrantingTicket {
+                username: username.to_string(),
+                proxies: vec![proxy_callback_url.to_string()],
+            },
+        );
+        ticket
+    }
+
+    /// Make the next ticket validation fail with the CAS error `code` (e.g.
+    /// `INVALID_TICKET`, `INVALID_SERVICE`, `INTERNAL_ERROR`)
+    pub fn fail_next_validation(&self, code: &str, description: &str) -> &Self {
+        self.state()
+            .validation_failures
+            .push_back((code.to_string(), description.to_string()));
+        self
+    }
+
+    /// Answer every request with `503 Service Unavailable`
+    pub fn set_unavailable(&self, unavailable: bool) -> &Self {
+        self.state().unavailable = unavailable;
+        self
+    }
+
+    /// Send single logout requests to the services of the SSO session on
+    /// `GET /logout`
+    pub fn set_single_logout_on_logout(&self, single_logout_on_logout: bool) -> &Self {
+        self.state().single_logout_on_logout = single_logout_on_logout;
+        self
+    }
+
+    /// Requests received so far, as `"METHOD /path"`
+    pub fn received_requests(&self) -> Vec<String> {
+        self.state().received_requests.clone()
+    }
+
+    // ################################################################################
+    // Public functions
+    // ################################################################################
+    /// Send a SAML single logout request for `service_ticket` to the service the
+    /// ticket was issued for. Returns the HTTP status answered by the service.
+    pub fn send_single_logout(&self, service_ticket: &str) -> io::Result<u16> {
+        let (username, service) = match self.state().tickets.get(service_ticket) {
+            Some(ticket) => (ticket.username.clone(), ticket.service.clone()),
+            None => {
+                return Err(io::Error::new(
+                    ErrorKind::NotFound,
+                    format!("Unknown ticket {}", service_ticket),
+                ))
+            }
+        };
+        single_logout(&self.state, &username, service_ticket, &service)
+    }
+
+    // ################################################################################
+    // Private functions
+    // ################################################################################
+    pub(self) fn state(&self) -> MutexGuard<'_, State> {
+        lock(&self.state)
+    }
+}
+
+impl Drop for MockCasServer {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+        // Wake up the accept loop
+        let _ = TcpStream::connect(self.address);
+    }
+}
+
+fn lock(state: &Mutex<State>) -> MutexGuard<'_, State> {
+    match state.lock() {
+        Ok(state) => state,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+fn serve(stream: TcpStream, state: Arc<Mutex<State>>, running: Arc<AtomicBool>, base_url: String) {
+    let _ = stream.set_read_timeout(Some(Duration::from_millis(500)));
+    let mut writer = match stream.try_clone() {
+        Ok(writer) => writer,
+        Err(_) => return,
+    };
+    let mut reader = BufReader::new(stream);
+    while running.load(Ordering::SeqCst) {
+        let request = match Request::read_from(&mut reader) {
+            Ok(Some(request)) => request,
+            Ok(None) => return,
+            Err(err) if err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::TimedOut => {
+                continue
+            }
+            Err(_) => return,
+        };
+        debug!("Mock CAS server: {} {}", request.method, request.path);
+        let response = handle(&state, &base_url, &request);
+        if response.write_to(&mut writer).is_err() {
+            return;
+        }
+        if request.header("connection") == Some("close") {
+            return;
+        }
+    }
+}
+
+fn handle(state: &Mutex<State>, base_url: &str, req: &Request) -> Response {
+    {
+        let mut state = lock(state);
+        state
+            .received_requests
+            .push(format!("{} {}", req.method, req.path));
+        if state.unavailable {
+            return Response::new(503, HTML_CONTENT_TYPE, "CAS is unavailable".to_string());
+        }
+    }
+    let path = match req.path.strip_prefix(CAS_BASE_PATH) {
+        Some(path) => path.trim_start_matches('/'),
+        None => return Response::new(404, HTML_CONTENT_TYPE, String::new()),
+    };
+    match (req.method.as_str(), path) {
+        ("GET", "login") => login_page(state, req),
+        ("POST", "login") => login_form(state, req),
+        ("GET", "logout") => logout(state, req),
+        ("GET", "serviceValidate") | ("GET", "p3/serviceValidate") => {
+            validate(state, req, Validation::Service)
+        }
+        ("GET", "proxyValidate") | ("GET", "p3/proxyValidate") => {
+            validate(state, req, Validation::Proxy)
+        }
+        ("GET", "proxy") => proxy(state, req),
+        ("POST", "v1/tickets") => rest_ticket_granting_ticket(state, base_url, req),
+        (method, path) if path.starts_with("v1/tickets/") => {
+            let ticket = &path["v1/tickets/".len()..];
+            match method {
+                "POST" => rest_service_ticket(state, ticket, req),
+                "DELETE" => rest_logout(state, ticket),
+                _ => Response::new(405, HTML_CONTENT_TYPE, String::new()),
+            }
+        }
+        _ => Response::new(404, HTML_CONTENT_TYPE, String::new()),
+    }
+}
+
+// ################################################################################
+// Login / logout
+// ################################################################################
+fn login_page(state: &Mutex<State>, req: &Request) -> Response {
+    let service = req.param("service").unwrap_or_default();
+    let renew = req.param("renew").is_some();
+    let gateway = req.param("gateway").is_some();
+    let method = req.param("method").unwrap_or_default();
+    let mut state = lock(state);
+    match state.sso_user.clone() {
+        Some(username) if !renew && !service.is_empty() => {
+            let ticket = state.issue_ticket("ST", &username, service, Vec::new());
+            service_response(service, &ticket, method)
+        }
+        Some(_) if !renew => Response::new(200, HTML_CONTENT_TYPE, "Logged in".to_string()),
+        _ if gateway && !service.is_empty() => Response::redirect(service),
+        _ => login_form_page(service, method, None),
+    }
+}
+
+fn login_form(state: &Mutex<State>, req: &Request) -> Response {
+    let service = req.param("service").unwrap_or_default();
+    let username = req.param("username").unwrap_or_default();
+    let password = req.param("password").unwrap_or_default();
+    let method = req.param("method").unwrap_or_default();
+    let mut state = lock(state);
+    if let Err(err) = state.authenticate(username, password) {
+        let mut resp = login_form_page(service, method, Some(err));
+        resp.status = 401;
+        return resp;
+    }
+    state.sso_user = Some(username.to_string());
+    match service {
+        "" => Response::new(200, HTML_CONTENT_TYPE, "Logged in".to_string()),
+        service => {
+            let ticket = state.issue_ticket_from_credentials(username, service);
+            service_response(service, &ticket, method)
+        }
+    }
+}
+
+/// Sends the browser back to `service` with `ticket`: redirected, or posting a
+/// form when the login asked for `method=POST`
+fn service_response(service: &str, ticket: &str, method: &str) -> Response {
+    if !method.eq_ignore_ascii_case("POST") {
+        return Response::redirect(&http::with_param(service, "ticket", ticket));
+    }
+    let body = format!(
+        "<html><body onload=\"document.forms[0].submit()\">\
+         <form method=\"post\" action=\"{}\">\
+         <input type=\"hidden\" name=\"ticket\" value=\"{}\">\
+         </form></body></html>",
+        escape(service),
+        escape(ticket),
+    );
+    Response::new(200, HTML_CONTENT_TYPE, body)
+}
+
+fn login_form_page(service: &str, method: &str, error: Option<&str>) -> Response {
+    let body = format!(
+        "<html><body>{}<form method=\"post\">\
+         <input type=\"hidden\" name=\"service\" value=\"{}\">\
+         <input type=\"hidden\" name=\"method\" value=\"{}\">\
+         <input name=\"username\"><input name=\"password\" type=\"password\">\
+         <button type=\"submit\">Login</button></form></body></html>",
+        error
+            .map(|err| format!("<p class=\"error\">{}</p>", escape(err)))
+            .unwrap_or_default(),
+        escape(service),
+        escape(method),
+    );
+    Response::new(200, HTML_CONTENT_TYPE, body)
+}
+
+fn logout(state: &Mutex<State>, req: &Request) -> Response {
+    let (username, single_logout_on_logout) = {
+        let mut state = lock(state);
+        (state.sso_user.take(), state.single_logout_on_logout)
+    };
+    if let (Some(username), true) = (username, single_logout_on_logout) {
+        let sessions: Vec<(String, String)> = lock(state)
+            .tickets
+            .iter()
+            .filter(|(id, ticket)| {
+                ticket.username == username && ticket.consumed && id.starts_with("ST-")
+            })
+            .map(|(id, ticket)| (id.clone(), ticket.service.clone()))
+            .collect();
+        for (ticket, service) in sessions {
+            if let Err(err) = single_logout(state, &username, &ticket, &service) {
+                warn!("Mock CAS server: single logout to {} failed: {}", service, err);
+            }
+        }
+    }
+    match req.param("service").or_else(|| req.param("url")) {
+        Some(service) if !service.is_empty() => Response::redirect(service),
+        _ => Response::new(200, HTML_CONTENT_TYPE, "Logged out".to_string()),
+    }
+}
+
+fn single_logout(
+    state: &Mutex<State>,
+    username: &str,
+    service_ticket: &str,
+    service: &str,
+) -> io::Result<u16> {
+    let id = lock(state).next_id("LR");
+    let logout_request = format!(
+        "<samlp:LogoutRequest xmlns:samlp=\"urn:oasis:names:tc:SAML:2.0:protocol\" \
+         xmlns:saml=\"urn:oasis:names:tc:SAML:2.0:assertion\" ID=\"{}\" Version=\"2.0\" \
+         IssueInstant=\"{}\"><saml:NameID>{}</saml:NameID>\
+         <samlp:SessionIndex>{}</samlp:SessionIndex></samlp:LogoutRequest>",
+        id,
+        iso8601_now(),
+        escape(username),
+        escape(service_ticket),
+    );
+    http::send("POST", service, &[("logoutRequest", &logout_request)])
+}
+
+// ################################################################################
+// Validation
+// ################################################################################
+fn validate(state: &Mutex<State>, req: &Request, validation: Validation) -> Response {
+    let (service, ticket) = match (req.param("service"), req.param("ticket")) {
+        (Some(service), Some(ticket)) if !service.is_empty() && !ticket.is_empty() => {
+            (service, ticket)
+        }
+        _ => {
+            return validation_failure(
+                "INVALID_REQUEST",
+                "'service' and 'ticket' parameters are both required",
+            )
+        }
+    };
+
+    let (username, proxies) = {
+        let mut state = lock(state);
+        if let Some((code, description)) = state.validation_failures.pop_front() {
+            return validation_failure(&code, &description);
+        }
+        if let Validation::Service = validation {
+            if ticket.starts_with("PT-") {
+                return validation_failure(
+                    "INVALID_TICKET_SPEC",
+                    "Proxy tickets cannot be validated by serviceValidate",
+                );
+            }
+        }
+        let found = match state.tickets.get_mut(ticket) {
+            Some(found) if !found.consumed => found,
+            _ => {
+                return validation_failure(
+                    "INVALID_TICKET",
+                    &format!("Ticket {} not recognized", ticket),
+                )
+            }
+        };
+        // Tickets are single use, even when validation fails
+        found.consumed = true;
+        if found.service != service {
+            return validation_failure(
+                "INVALID_SERVICE",
+                &format!("Ticket {} does not match supplied service", ticket),
+            );
+        }
+        if req.param("renew").is_some() && !found.from_credentials {
+            return validation_failure(
+                "INVALID_TICKET",
+                &format!("Ticket {} was not issued from the credentials of the user", ticket),
+            );
+        }
+        (found.username.clone(), found.proxies.clone())
+    };
+
+    let proxy_granting_ticket_iou = match req.param("pgtUrl") {
+        Some(pgt_url) => proxy_callback(state, &username, pgt_url, &proxies),
+        None => None,
+    };
+
+    let attributes = lock(state)
+        .users
+        .get(&username)
+        .map(|user| user.attributes().to_vec())
+        .unwrap_or_default();
+    let mut body = format!(
+        "<cas:serviceResponse xmlns:cas=\"http://www.yale.edu/tp/cas\">\
+         <cas:authenticationSuccess><cas:user>{}</cas:user>",
+        escape(&username)
+    );
+    if !attributes.is_empty() {
+        body.push_str("<cas:attributes>");
+        for (name, value) in attributes {
+            body.push_str(&format!("<cas:{0}>{1}</cas:{0}>", name, escape(&value)));
+        }
+        body.push_str("</cas:attributes>");
+    }
+    if let Some(iou) = proxy_granting_ticket_iou {
+        body.push_str(&format!(
+            "<cas:proxyGrantingTicket>{}</cas:proxyGrantingTicket>",
+            iou
+        ));
+    }
+    if !proxies.is_empty() {
+        body.push_str("<cas:proxies>");
+        for proxy in proxies.iter().rev() {
+            body.push_str(&format!("<cas:proxy>{}</cas:proxy>", escape(proxy)));
+        }
+        body.push_str("</cas:proxies>");
+    }
+    body.push_str("</cas:authenticationSuccess></cas:serviceResponse>");
+    Response::new(200, XML_CONTENT_TYPE, body)
+}
+
+fn validation_failure(code: &str, description: &str) -> Response {
+    let body = format!(
+        "<cas:serviceResponse xmlns:cas=\"http://www.yale.edu/tp/cas\">\
+         <cas:authenticationFailure code=\"{}\">{}</cas:authenticationFailure>\
+         </cas:serviceResponse>",
+        escape(code),
+        escape(description)
+    );
+    Response::new(200, XML_CONTENT_TYPE, body)
+}
+
+/// Send the proxy granting ticket to `pgt_url` and return its IOU, as CAS does.
+/// No IOU is returned when the callback fails.
+fn proxy_callback(
+    state: &Mutex<State>,
+    username: &str,
+    pgt_url: &str,
+    proxies: &[String],
+) -> Option<String> {
+    let (ticket, iou) = {
+        let mut state = lock(state);
+        (state.next_id("PGT"), state.next_id("PGTIOU"))
+    };
+    let callback_url = http::with_param(&http::with_param(pgt_url, "pgtId", &ticket), "pgtIou", &iou);
+    match http::send("GET", &callback_url, &[]) {
+        Ok(200) => {
+            let mut chain = proxies.to_vec();
+            chain.push(pgt_url.to_string());
+            lock(state).proxy_granting_tickets.insert(
+                ticket,
+                ProxyGrantingTicket {
+                    username: username.to_string(),
+                    proxies: chain,
+                },
+            );
+            Some(iou)
+        }
+        other => {
+            warn!("Mock CAS server: proxy callback to {} failed: {:?}", pgt_url, other);
+            None
+        }
+    }
+}
+
+fn proxy(state: &Mutex<State>, req: &Request) -> Response {
+    let (pgt, target_service) = match (req.param("pgt"), req.param("targetService")) {
+        (Some(pgt), Some(target)) if !pgt.is_empty() && !target.is_empty() => (pgt, target),
+        _ => {
+            return proxy_failure(
+                "INVALID_REQUEST",
+                "'pgt' and 'targetService' parameters are both required",
+            )
+        }
+    };
+    let mut state = lock(state);
+    let (username, proxies) = match state.proxy_granting_tickets.get(pgt) {
+        Some(found) => (found.username.clone(), found.proxies.clone()),
+        None => return proxy_failure("INVALID_TICKET", &format!("Ticket {} not recognized", pgt)),
+    };
+    let ticket = state.issue_ticket("PT", &username, target_service, proxies);
+    Response::new(
+        200,
+        XML_CONTENT_TYPE,
+        format!(
+            "<cas:serviceResponse xmlns:cas=\"http://www.yale.edu/tp/cas\">\
+             <cas:proxySuccess><cas:proxyTicket>{}</cas:proxyTicket></cas:proxySuccess>\
+             </cas:serviceResponse>",
+            ticket
+        ),
+    )
+}
+
+fn proxy_failure(code: &str, description: &str) -> Response {
+    let body = format!(
+        "<cas:serviceResponse xmlns:cas=\"http://www.yale.edu/tp/cas\">\
+         <cas:proxyFailure code=\"{}\">{}</cas:proxyFailure></cas:serviceResponse>",
+        escape(code),
+        escape(description)
+    );
+    Response::new(200, XML_CONTENT_TYPE, body)
+}
+
+// ################################################################################
+// REST protocol
+// ################################################################################
+fn rest_ticket_granting_ticket(state: &Mutex<State>, base_url: &str, req: &Request) -> Response {
+    let username = req.param("username").unwrap_or_default();
+    let password = req.param("password").unwrap_or_default();
+    let mut state = lock(state);
+    if let Err(err) = state.authenticate(username, password) {
+        return Response::new(
+            401,
+            "application/json",
+            format!("{{\"authentication_exceptions\":[\"{}\"]}}", err),
+        );
+    }
+    let ticket = state.next_id("TGT");
+    state
+        .ticket_granting_tickets
+        .insert(ticket.clone(), username.to_string());
+    let location = format!("{}/v1/tickets/{}", base_url, ticket);
+    Response::new(201, HTML_CONTENT_TYPE, String::new()).header("Location", &location)
+}
+
+fn rest_service_ticket(state: &Mutex<State>, ticket_granting_ticket: &str, req: &Request) -> Response {
+    let service = match req.param("service") {
+        Some(service) if !service.is_empty() => service,
+        _ => return Response::new(400, "text/plain", "'service' is required".to_string()),
+    };
+    let mut state = lock(state);
+    match state.ticket_granting_tickets.get(ticket_granting_ticket).cloned() {
+        Some(username) => {
+            let ticket = state.issue_ticket("ST", &username, service, Vec::new());
+            Response::new(200, "text/plain", ticket)
+        }
+        None => Response::new(404, "text/plain", String::new()),
+    }
+}
+
+fn rest_logout(state: &Mutex<State>, ticket_granting_ticket: &str) -> Response {
+    lock(state)
+        .ticket_granting_tickets
+        .remove(ticket_granting_ticket);
+    Response::new(200, "text/plain", ticket_granting_ticket.to_string())
+}
+
+// ################################################################################
+// Helpers
+// ################################################################################
+fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+/// Current UTC time as `YYYY-MM-DDTHH:MM:SSZ`
+fn iso8601_now() -> String {
+    let seconds = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0);
+    let (days, time) = (seconds / 86_400, seconds % 86_400);
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        time / 3_600,
+        time % 3_600 / 60,
+        time % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use cas_client_core::{CasClient, CasRestError, CasValidationError, HttpResponse, HttpTransport};
+    use std::collections::HashMap;
+    use std::io::Write;
+    use std::sync::mpsc;
+
+    const APP_URL: &str = "https://app.example.org";
+    const SERVICE_URL: &str = "https://app.example.org/auth/cas/login";
+
+    fn server_with_user() -> MockCasServer {
+        let server = MockCasServer::start();
+        let mut user = MockUser::new("jdoe", "secret");
+        user.add_attribute("mail", "jdoe@example.org");
+        user.add_attribute("memberOf", "staff");
+        user.add_attribute("memberOf", "admins");
+        server.add_user(user);
+        let mut locked = MockUser::new("locked", "secret");
+        locked.set_locked(true);
+        server.add_user(locked);
+        server
+    }
+
+    fn cas_client(server: &MockCasServer) -> CasClient {
+        let mut cas_client = CasClient::new(&server.url()).unwrap();
+        cas_client.set_app_url(APP_URL);
+        cas_client
+    }
+
+    // Accept one request and send its body through the channel
+    fn start_service() -> (String, mpsc::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/slo", listener.local_addr().unwrap());
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let request = Request::read_from(&mut reader).unwrap().unwrap();
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+            let body = request.param("logoutRequest").unwrap_or_default().to_string();
+            sender.send(body).unwrap();
+        });
+        (url, receiver)
+    }
+
+    // ################################################################################
+    // Validation
+    // ################################################################################
+    #[test]
+    fn should_validate_service_ticket() {
+        let server = server_with_user();
+        let cas_client = cas_client(&server);
+        let ticket = server.issue_service_ticket("jdoe", SERVICE_URL);
+
+        let user = cas_client.validate_service_ticket(&ticket).unwrap().unwrap();
+        let mut attributes = HashMap::new();
+        attributes.insert("mail".to_string(), "jdoe@example.org".to_string());
+        attributes.insert("memberOf".to_string(), "staff,admins".to_string());
+        assert_eq!(user.username(), "jdoe");
+        assert_eq!(user.attributes(), attributes);
+        assert_eq!(
+            server.received_requests(),
+            vec!["GET /cas/serviceValidate".to_string()]
+        );
+    }
+
+    #[test]
+    fn tickets_should_be_single_use() {
+        let server = server_with_user();
+        let cas_client = cas_client(&server);
+        let ticket = server.issue_service_ticket("jdoe", SERVICE_URL);
+
+        assert!(cas_client.validate_service_ticket(&ticket).unwrap().is_some());
+        assert!(cas_client.validate_service_ticket(&ticket).unwrap().is_none());
+    }
+
+    #[test]
+    fn tickets_should_be_bound_to_their_service() {
+        let server = server_with_user();
+        let cas_client = cas_client(&server);
+        let ticket = server.issue_service_ticket("jdoe", "https://other.example.org/");
+
+        assert!(cas_client.validate_service_ticket(&ticket).unwrap().is_none());
+    }
+
+    #[test]
+    fn should_inject_validation_failures() {
+        let server = server_with_user();
+        let cas_client = cas_client(&server);
+        server.fail_next_validation("INTERNAL_ERROR", "Boom");
+
+        let ticket = server.issue_service_ticket("jdoe", SERVICE_URL);
+        assert!(cas_client.validate_service_ticket(&ticket).unwrap().is_none());
+        assert!(cas_client.validate_service_ticket(&ticket).unwrap().is_some());
+    }
+
+    #[test]
+    fn should_be_unavailable() {
+        let server = server_with_user();
+        server.set_unavailable(true);
+        let resp = HttpTransport::new()
+            .get(&format!("{}/login", server.url()))
+            .unwrap();
+        assert_eq!(resp.status, 503);
+    }
+
+    // ################################################################################
+    // Login / logout
+    // ################################################################################
+    #[test]
+    fn login_should_redirect_with_ticket_when_logged_in() {
+        let server = server_with_user();
+        let cas_client = cas_client(&server);
+        let transport = HttpTransport::new();
+
+        let resp = transport.get(&cas_client.login_url().unwrap()).unwrap();
+        assert_eq!(resp.status, 200);
+        assert!(resp.body.contains("<form"));
+
+        server.login_as("jdoe");
+        let resp = transport.get(&cas_client.login_url().unwrap()).unwrap();
+        assert_eq!(resp.status, 302);
+        let location = resp.header("Location").unwrap();
+        assert!(location.starts_with(&format!("{}?ticket=ST-", SERVICE_URL)));
+
+        let ticket = location.split("ticket=").nth(1).unwrap();
+        let user = cas_client.validate_service_ticket(ticket).unwrap().unwrap();
+        assert_eq!(user.username(), "jdoe");
+
+        let resp = transport.get(&cas_client.logout_url().unwrap()).unwrap();
+        assert_eq!(resp.status, 302);
+        assert_eq!(resp.header("Location"), Some(APP_URL));
+        let resp = transport.get(&cas_client.login_url().unwrap()).unwrap();
+        assert_eq!(resp.status, 200);
+    }
+
+    #[test]
+    fn login_should_post_ticket_when_asked() {
+        let server = server_with_user();
+        let cas_client = cas_client(&server);
+        server.login_as("jdoe");
+
+        let mut options = cas_client_core::LoginOptions::new();
+        options.set_post_response(true);
+        let login_url = cas_client.login_url_with_options(SERVICE_URL, &options).unwrap();
+        let resp = HttpTransport::new().get(&login_url).unwrap();
+        assert_eq!(resp.status, 200);
+        assert!(resp.body.contains(&format!("<form method=\"post\" action=\"{}\">", SERVICE_URL)));
+        let start = resp.body.find("ST-").unwrap();
+        let ticket = &resp.body[start..start + resp.body[start..].find('"').unwrap()];
+        let user = cas_client.validate_service_ticket(ticket).unwrap().unwrap();
+        assert_eq!(user.username(), "jdoe");
+    }
+
+    #[test]
+    fn renewed_validation_should_require_credentials() {
+        let server = server_with_user();
+        let cas_client = cas_client(&server);
+        server.login_as("jdoe");
+        let transport = HttpTransport::new();
+        let ticket = |resp: HttpResponse| {
+            let location = resp.header("Location").unwrap().to_string();
+            location[location.find("ST-").unwrap()..].to_string()
+        };
+
+        let login_url = cas_client.login_url_for_service(SERVICE_URL).unwrap();
+        let sso_ticket = ticket(transport.get(&login_url).unwrap());
+        let result = cas_client.validate_ticket_with_renew(&sso_ticket, SERVICE_URL, true);
+        assert!(matches!(
+            result,
+            Err(CasValidationError::Failure { code, .. }) if code == "INVALID_TICKET"
+        ));
+
+        let url = format!("{}/login", server.url());
+        let resp = transport
+            .post_form(&url, &[("username", "jdoe"), ("password", "secret"), ("service", SERVICE_URL)])
+            .unwrap();
+        let user = cas_client.validate_ticket_with_renew(&ticket(resp), SERVICE_URL, true).unwrap();
+        assert_eq!(user.username(), "jdoe");
+    }
+
+    #[test]
+    fn login_form_should_check_credentials() {
+        let server = server_with_user();
+        let transport = HttpTransport::new();
+        let url = format!("{}/login", server.url());
+
+        let resp = transport
+            .post_form(&url, &[("username", "jdoe"), ("password", "wrong"), ("service", SERVICE_URL)])
+            .unwrap();
+        assert_eq!(resp.status, 401);
+
+        let resp = transport
+            .post_form(&url, &[("username", "jdoe"), ("password", "secret"), ("service", SERVICE_URL)])
+            .unwrap();
+        assert_eq!(resp.status, 302);
+    }
+
+    #[test]
+    fn should_send_single_logout_request() {
+        let server = server_with_user();
+        let (service, receiver) = start_service();
+        let ticket = server.issue_service_ticket("jdoe", &service);
+
+        assert_eq!(server.send_single_logout(&ticket).unwrap(), 200);
+        let logout_request = receiver.recv().unwrap();
+        assert!(logout_request.contains("<saml:NameID>jdoe</saml:NameID>"));
+        assert!(logout_request.contains(&format!(
+            "<samlp:SessionIndex>{}</samlp:SessionIndex>",
+            ticket
+        )));
+        assert!(server.send_single_logout("ST-unknown").is_err());
+    }
+
+    // ################################################################################
+    // Proxy
+    // ################################################################################
+    #[test]
+    fn should_issue_and_validate_proxy_tickets() {
+        let server = server_with_user();
+        let transport = HttpTransport::new();
+        let pgt = server.issue_proxy_granting_ticket("jdoe", "https://proxy.example.org/pgt");
+
+        let resp = transport
+            .get(&format!(
+                "{}/proxy?pgt={}&targetService=https%3A%2F%2Fapi.example.org",
+                server.url(),
+                pgt
+            ))
+            .unwrap();
+        assert!(resp.body.contains("<cas:proxySuccess>"));
+        let ticket = resp
+            .body
+            .split("<cas:proxyTicket>")
+            .nth(1)
+            .and_then(|rest| rest.split('<').next())
+            .unwrap()
+            .to_string();
+        assert!(ticket.starts_with("PT-"));
+
+        let validate = |endpoint: &str| {
+            transport
+                .get(&format!(
+                    "{}/{}?service=https%3A%2F%2Fapi.example.org&ticket={}",
+                    server.url(),
+                    endpoint,
+                    ticket
+                ))
+                .unwrap()
+                .body
+        };
+        assert!(validate("serviceValidate").contains("INVALID_TICKET_SPEC"));
+        let body = validate("proxyValidate");
+        assert!(body.contains("<cas:user>jdoe</cas:user>"));
+        assert!(body.contains("<cas:proxy>https://proxy.example.org/pgt</cas:proxy>"));
+    }
+
+    // ################################################################################
+    // REST protocol
+    // ################################################################################
+    #[test]
+    fn should_login_with_rest_protocol() {
+        let server = server_with_user();
+        let cas_client = cas_client(&server);
+
+        let tgt = cas_client
+            .create_ticket_granting_ticket("jdoe", "secret")
+            .unwrap();
+        assert!(tgt.starts_with("TGT-"));
+        let ticket = cas_client.create_service_ticket(&tgt, SERVICE_URL).unwrap();
+        let user = cas_client.validate_service_ticket(&ticket).unwrap().unwrap();
+        assert_eq!(user.username(), "jdoe");
+
+        cas_client.destroy_ticket_granting_ticket(&tgt).unwrap();
+        assert_eq!(
+            cas_client.create_service_ticket(&tgt, SERVICE_URL),
+            Err(CasRestError::InvalidTicketGrantingTicket)
+        );
+    }
+
+    #[test]
+    fn rest_protocol_should_return_typed_errors() {
+        let server = server_with_user();
+        let cas_client = cas_client(&server);
+        assert_eq!(
+            cas_client.create_ticket_granting_ticket("jdoe", "wrong"),
+            Err(CasRestError::BadCredentials)
+        );
+        assert_eq!(
+            cas_client.create_ticket_granting_ticket("locked", "secret"),
+            Err(CasRestError::AccountLocked)
+        );
+    }
+
+    // ################################################################################
+    // Helpers
+    // ################################################################################
+    #[test]
+    fn should_format_current_time() {
+        let now = iso8601_now();
+        assert_eq!(now.len(), "2020-01-01T00:00:00Z".len());
+        assert_eq!(&now[4..5], "-");
+        assert_eq!(&now[10..11], "T");
+        assert!(now.ends_with('Z'));
+    }
+}
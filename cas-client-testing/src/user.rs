@@ -0,0 +1,66 @@
+/// User known by the mock CAS server
+#[derive(Clone, Debug, PartialEq)]
+pub struct MockUser {
+    username: String,
+    password: String,
+    attributes: Vec<(String, String)>,
+    locked: bool,
+    disabled: bool,
+}
+
+impl MockUser {
+    // ################################################################################
+    // Constructor
+    // ################################################################################
+    pub fn new(username: &str, password: &str) -> Self {
+        MockUser {
+            username: username.to_string(),
+            password: password.to_string(),
+            attributes: Vec::new(),
+            locked: false,
+            disabled: false,
+        }
+    }
+
+    // ################################################################################
+    // Getters / Setters
+    // ################################################################################
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+
+    pub fn password(&self) -> &str {
+        &self.password
+    }
+
+    /// Attributes released to the services. An attribute may be added several times
+    /// to get a multi-valued attribute.
+    pub fn attributes(&self) -> &[(String, String)] {
+        &self.attributes
+    }
+
+    pub fn add_attribute(&mut self, name: &str, value: &str) -> &mut Self {
+        self.attributes.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn locked(&self) -> bool {
+        self.locked
+    }
+
+    /// Locked users cannot log in (`AccountLockedException` on the REST API)
+    pub fn set_locked(&mut self, locked: bool) -> &mut Self {
+        self.locked = locked;
+        self
+    }
+
+    pub fn disabled(&self) -> bool {
+        self.disabled
+    }
+
+    /// Disabled users cannot log in (`AccountDisabledException` on the REST API)
+    pub fn set_disabled(&mut self, disabled: bool) -> &mut Self {
+        self.disabled = disabled;
+        self
+    }
+}
@@ -2,12 +2,12 @@ extern crate cas_client;
 extern crate dotenv;
 
 use actix_web::Responder;
-use actix_session::{CookieSession, UserSession};
+use actix_session::CookieSession;
 use actix_web::http::StatusCode;
 use actix_web::middleware::Logger;
 use actix_web::{get, middleware, web, App, HttpRequest, HttpResponse, HttpServer};
-use cas_client::actix::ActixCasClient;
-use cas_client::{CasClient, CasUser, NoAuthBehavior};
+use cas_client::actix::{ActixCasClient, MaybeCasUser, RouteAccess, RouteRule};
+use cas_client::{CasClient, NoAuthBehavior};
 use dotenv::dotenv;
 use env_logger::Env;
 use std::collections::HashMap;
@@ -48,10 +48,8 @@ async fn unauthorized(_req: HttpRequest) -> impl Responder {
 }
 
 #[get("/")]
-async fn user(req: HttpRequest) -> impl Responder {
-    let session = req.get_session();
-    let user_session = session.get::<CasUser>("cas_user");
-    let user = user_session.unwrap_or(None);
+async fn user(req: HttpRequest, user: MaybeCasUser) -> impl Responder {
+    let user = user.into_inner();
     let username = match user {
         Some(ref user) => user.username().to_owned(),
         None => "guest".to_owned(),
@@ -85,64 +83,65 @@ async fn main() -> std::io::Result<()> {
 
     let server_bind_address = env_or_default("SERVER_BIND_ADDRESS", "127.0.0.1:8080");
 
-    HttpServer::new(|| {
-        let auth_service = "/auth/cas";
-        let mut cas_client_auth = init_cas_client(&auth_service, NoAuthBehavior::Authenticate);
-        cas_client_auth.set_default_after_logged_in_path(Some("/user".to_string()));
-
-        let cas_client_403 = init_cas_client(&auth_service, NoAuthBehavior::AuthenticatedOr403);
-        let cas_client_404 = init_cas_client(&auth_service, NoAuthBehavior::AuthenticatedOr404);
+    let cas_url = env_or_default("CAS_URL", "https://cas.example.com");
+    let app_url = env_or_default("APP_URL", "http://localhost:8080");
+    HttpServer::new(move || {
+        let (cas_client, login_cas_client) = init_cas_clients(&cas_url, &app_url);
         App::new()
+            .wrap(cas_client.clone())
             .wrap(Logger::default())
             .wrap(middleware::NormalizePath::default())
             .wrap(CookieSession::signed(&[0; 32]).secure(false).name("actix-web-example"))
-            .app_data(cas_client_auth.clone())
-            .service(guest)
-            .service(
-                web::scope("/user/welcome")
-                    .wrap(cas_client_auth.clone())
-                    .service(user)
-            )
-            .service(
-                web::scope("/user")
-                    .wrap(cas_client_auth.clone())
-                    .service(user)
-            )
-            .service(
-                web::scope("/user_or_403/welcome")
-                    .wrap(cas_client_403.clone())
-                    .service(user)
-            )
-            .service(
-                web::scope("/user_or_403")
-                    .wrap(cas_client_403.clone())
-                    .service(user)
-            )
-            .service(
-                web::scope("/user_or_404/welcome")
-                    .wrap(cas_client_404.clone())
-                    .service(user)
-            )
-            .service(
-                web::scope("/user_or_404")
-                    .wrap(cas_client_404.clone())
-                    .service(user)
-            )
-            .configure(|cfg| { cas_client::actix::urls::register(cfg, auth_service, &cas_client_auth) })
+            .app_data(cas_client)
+            .configure(|cfg| configure(cfg, &login_cas_client))
         })
         .bind(server_bind_address)?
         .run()
     .await
 }
 
+const AUTH_SERVICE: &str = "/auth/cas";
+
+/// Middleware of the app, and the one `register` wraps the login route with
+fn init_cas_clients(cas_url: &str, app_url: &str) -> (ActixCasClient, ActixCasClient) {
+    let mut cas_client = init_cas_client(cas_url, app_url, AUTH_SERVICE, NoAuthBehavior::Authenticate);
+    cas_client.set_default_after_logged_in_path(Some("/user".to_string()));
+    // Without the rules: the login route is public for the app middleware, yet
+    // its own middleware must validate the tickets CAS sends back to it
+    let login_cas_client = cas_client.clone();
+    // First matching rule wins, other paths require authentication
+    cas_client
+        .add_route_rule(RouteRule::public("/"))
+        // Login is protected by the middleware `register` wraps it with
+        .add_route_rule(RouteRule::public("/auth/cas/**"))
+        .add_route_rule(RouteRule::glob(
+            "/user_or_403/**",
+            RouteAccess::Authenticated(NoAuthBehavior::AuthenticatedOr403),
+        ))
+        .add_route_rule(RouteRule::glob(
+            "/user_or_404/**",
+            RouteAccess::Authenticated(NoAuthBehavior::AuthenticatedOr404),
+        ));
+    (cas_client, login_cas_client)
+}
+
+fn configure(cfg: &mut web::ServiceConfig, login_cas_client: &ActixCasClient) {
+    cfg.service(guest)
+        .service(web::scope("/user/welcome").service(user))
+        .service(web::scope("/user").service(user))
+        .service(web::scope("/user_or_403/welcome").service(user))
+        .service(web::scope("/user_or_403").service(user))
+        .service(web::scope("/user_or_404/welcome").service(user))
+        .service(web::scope("/user_or_404").service(user));
+    cas_client::actix::urls::register(cfg, AUTH_SERVICE, login_cas_client);
+}
+
 fn env_or_default(key: &str, default: &str) -> String {
     env::var(key).unwrap_or(default.to_string())
 }
 
-fn init_cas_client(auth_service: &str, behavior: NoAuthBehavior) -> ActixCasClient {
-    let cas_url = env_or_default("CAS_URL", "https://cas.example.com");
-    let app_url = env_or_default("APP_URL", "http://localhost:8080");
-    let mut cas_client = CasClient::new(&cas_url).unwrap();
+fn init_cas_client(cas_url: &str, app_url: &str, auth_service: &str, behavior: NoAuthBehavior) -> ActixCasClient {
+    let mut cas_client = CasClient::new(cas_url).unwrap();
 
     if let Ok(login_prefix) = env::var("CAS_LOGIN_PREFIX") {
         cas_client.set_login_prefix(login_prefix.as_ref());
@@ -151,9 +150,85 @@ fn init_cas_client(auth_service: &str, behavior: NoAuthBehavior) -> ActixCasClie
         cas_client.set_service_validate_prefix(service_validate_prefix.as_ref());
     }
 
-    cas_client.set_app_url(&app_url);
+    cas_client.set_app_url(app_url);
     cas_client.set_no_auth_behavior(behavior);
     cas_client.set_login_service(auth_service);
 
     ActixCasClient::new(cas_client, Some("/protected_or_error/403".to_string()), Some("/protected_or_error/404".to_string()))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::cookie::Cookie;
+    use actix_web::dev::ServiceResponse;
+    use actix_web::http::header;
+    use actix_web::test::{self, TestRequest};
+    use cas_client_testing::{MockCasServer, MockUser};
+    use url::{Position, Url};
+
+    const APP_URL: &str = "http://localhost:8080";
+
+    /// Cookies set by `resp`, replacing those of `jar` with the same name
+    fn keep_cookies(jar: &mut Vec<Cookie<'static>>, resp: &ServiceResponse) {
+        for cookie in resp.response().cookies() {
+            jar.retain(|kept| kept.name() != cookie.name());
+            jar.push(cookie.into_owned());
+        }
+    }
+
+    fn get(uri: &str, jar: &[Cookie<'static>]) -> TestRequest {
+        jar.iter()
+            .fold(TestRequest::get().uri(uri), |req, cookie| req.cookie(cookie.clone()))
+    }
+
+    fn location(resp: &ServiceResponse) -> String {
+        resp.headers().get(header::LOCATION).unwrap().to_str().unwrap().to_string()
+    }
+
+    #[actix_rt::test]
+    async fn should_log_in_through_cas() {
+        let cas = MockCasServer::start();
+        cas.add_user(MockUser::new("jdoe", "secret"));
+        let (cas_client, login_cas_client) = init_cas_clients(&cas.url(), APP_URL);
+        let mut app = test::init_service(
+            App::new()
+                .wrap(cas_client.clone())
+                .wrap(middleware::NormalizePath::default())
+                .wrap(CookieSession::signed(&[0; 32]).secure(false).name("actix-web-example"))
+                .app_data(cas_client)
+                .configure(|cfg| configure(cfg, &login_cas_client)),
+        )
+        .await;
+
+        for start in &["/user", "/auth/cas/login"] {
+            let mut jar = Vec::new();
+            let resp = test::call_service(&mut app, get(start, &jar).to_request()).await;
+            assert!(resp.status().is_redirection(), "{}", start);
+            keep_cookies(&mut jar, &resp);
+            let login = Url::parse(&location(&resp)).unwrap();
+            assert!(login.as_str().starts_with(&cas.url()), "{}", login);
+            let service = login
+                .query_pairs()
+                .find(|(name, _)| name == "service")
+                .map(|(_, service)| service.into_owned())
+                .unwrap();
+
+            // CAS sends the user back to the service with a ticket
+            let ticket = cas.issue_service_ticket("jdoe", &service);
+            let mut callback = Url::parse(&service).unwrap();
+            callback.query_pairs_mut().append_pair("ticket", &ticket);
+            let callback = &callback[Position::BeforePath..];
+            let resp = test::call_service(&mut app, get(callback, &jar).to_request()).await;
+            assert!(resp.status().is_redirection(), "{}", callback);
+            keep_cookies(&mut jar, &resp);
+            let after_login = location(&resp);
+            assert!(!after_login.starts_with(&cas.url()), "login loops back to CAS from {}", start);
+
+            let resp = test::call_service(&mut app, get("/user", &jar).to_request()).await;
+            assert!(resp.status().is_success(), "{}", start);
+            let body = test::read_body(resp).await;
+            assert!(String::from_utf8_lossy(&body).contains("Welcome <b>jdoe</b>"), "{}", start);
+        }
+    }
+}
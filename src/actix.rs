@@ -1,3 +1,16 @@
 extern crate cas_client_actix;
 
 pub use cas_client_actix::{urls, ActixCasClient, ActixCasClientMiddleware};
+#[cfg(feature = "testing")]
+pub use cas_client_actix::testing;
+pub use cas_client_actix::{
+    CasAuthentication, CAS_AUTHENTICATION_SESSION_KEY, CAS_PRINCIPAL_SESSION_KEY, CAS_USER_SESSION_KEY,
+};
+pub use cas_client_actix::{
+    ApiDetection, AuthenticatedCasUser, CasGuard, DefaultErrorPages, ErrorPage, ErrorPageRenderer,
+    MaybeCasUser, RedirectPolicy, ReturnUrlMode, RouteAccess, RouteRule,
+};
+pub use cas_client_actix::{CasEventError, CasEventHandler, LoginDecision};
+pub use cas_client_actix::{Principal, PrincipalResolver};
+pub use cas_client_actix::{LogoutBehavior, LOGOUT_RETURN_PARAM};
+pub use cas_client_actix::{StatelessCasClient, StatelessCasClientMiddleware};
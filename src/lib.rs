@@ -1,7 +1,9 @@
 extern crate cas_client_core;
 
-pub use cas_client_core::CasUser;
-pub use cas_client_core::{CasClient, CasProtocol, NoAuthBehavior};
+pub use cas_client_core::{CasUser, UserTransform};
+pub use cas_client_core::{CasClient, CasProtocol, LoginOptions, LogoutReturnParam, NoAuthBehavior};
+pub use cas_client_core::{NoAuthHandler, NoAuthOutcome, NoAuthRequest, SessionState};
+pub use cas_client_core::{CasRestError, CasValidationError, HttpTransport, ProxyValidation};
 
 #[cfg(feature = "actix-framework")]
 pub mod actix;